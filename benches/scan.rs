@@ -0,0 +1,95 @@
+//! Scans of 1M documents with the bytecode VM of `Parser::parse` (`vm`) and with the tree of
+//! nodes of `Parser::compile` (`tree`). The README records the results.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use flql::exp_parser::{BoxedExpression, Parser, Value};
+use flql::optimizer::optimize;
+
+/// Documents scanned per iteration, cycling through `DISTINCT` generated ones.
+const SCAN: usize = 1_000_000;
+const DISTINCT: usize = 1_000;
+
+const EXPRESSIONS: [(&str, &str); 7] = [
+    ("comparison", ".age > 30"),
+    (
+        "conjunction",
+        ".age > 30 && .address.city == 'Berlin' && .active && .score >= 0.5",
+    ),
+    (
+        "short_circuit",
+        ".active && .name.last STARTS_WITH 'M' && len(.tags) > 1",
+    ),
+    (
+        "disjunction",
+        ".name.first == 'User1' || .name.last == 'Miller' || .address.city == 'Lima'",
+    ),
+    (
+        "range",
+        ".age >= 20 && .age < 40 && .score > 0.2 && .score < 0.8",
+    ),
+    ("arithmetic", "(.score * 100 + .age + .id) DIV 3 % 7 == 2"),
+    (
+        "in_list",
+        ".address.city IN ['Berlin', 'Paris', 'Tokyo', 'Lima', 'Oslo']",
+    ),
+];
+
+fn documents() -> Vec<Vec<u8>> {
+    let cities = ["Berlin", "Paris", "Tokyo", "Lima", "Oslo", "Cairo"];
+    (0..DISTINCT)
+        .map(|i| {
+            let tags = [r#""a""#, r#""b""#, r#""c""#][..i % 4].join(",");
+            format!(
+                r#"{{"id":{i},"name":{{"first":"User{i}","last":"{}"}},"age":{},"active":{},"score":{},"address":{{"street":"Main St {i}","city":"{}"}},"tags":[{tags}],"bio":"Lorem ipsum dolor sit amet, consectetur adipiscing elit."}}"#,
+                if i % 3 == 0 { "Miller" } else { "Smith" },
+                18 + i % 50,
+                i % 2 == 0,
+                (i % 100) as f64 / 100.0,
+                cities[i % cities.len()],
+            )
+            .into_bytes()
+        })
+        .collect()
+}
+
+fn scan(expression: &BoxedExpression, documents: &[Vec<u8>]) -> usize {
+    documents
+        .iter()
+        .cycle()
+        .take(SCAN)
+        .filter(|doc| {
+            expression
+                .calculate(doc)
+                .is_ok_and(|v| v == Value::Bool(true))
+        })
+        .count()
+}
+
+fn bench(c: &mut Criterion) {
+    let documents = documents();
+    let mut group = c.benchmark_group("scan_1m");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(SCAN as u64));
+
+    for (name, expression) in EXPRESSIONS {
+        let expr = optimize(Parser::parse_expr(expression).unwrap()).expr;
+        let tree = Parser::compile(expr).unwrap();
+        let vm = Parser::parse(expression).unwrap();
+        assert_eq!(
+            scan(&tree, &documents),
+            scan(&vm, &documents),
+            "{expression}"
+        );
+
+        group.bench_with_input(BenchmarkId::new("tree", name), &tree, |b, expression| {
+            b.iter(|| scan(black_box(expression), &documents));
+        });
+        group.bench_with_input(BenchmarkId::new("vm", name), &vm, |b, expression| {
+            b.iter(|| scan(black_box(expression), &documents));
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench);
+criterion_main!(benches);
@@ -0,0 +1,551 @@
+use std::collections::BTreeSet;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use chrono::SecondsFormat;
+use crate::duration::format_duration;
+use crate::exp_parser::{Parser, Value};
+use crate::error::ExprError;
+
+/// A parsed expression, see `Parser::parse_expr`.
+///
+/// Parentheses are not kept, the tree itself gives the precedence. `Parser::compile` turns an
+/// `Expr` into an `Expression` that can be calculated, and `Display` writes it back as an
+/// expression that parses to the same tree.
+///
+/// ```
+/// use flql::ast::{BinaryOp, Expr};
+///
+/// let expr: Expr = ".age >= 18 && .name STARTS_WITH 'A'".parse().unwrap();
+/// assert_eq!(expr.paths(), ["age", "name"].into());
+/// assert!(matches!(expr, Expr::Binary { op: BinaryOp::And, .. }));
+/// assert_eq!(expr.to_string(), "((.age >= 18) && (.name STARTS_WITH \"A\"))");
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    /// A string, number, boolean, `NULL` or duration literal.
+    Literal(Value),
+    /// A gjson selector path without its leading `.`, eg. `name.first`.
+    Path(String),
+    /// A `$name` variable of the `EvalContext`, without its leading `$`.
+    Variable(String),
+    Array(Vec<Expr>),
+    /// `{"<key>": <value>, ...}`, the members whose value is missing are left out.
+    Object(Vec<(String, Expr)>),
+    Unary { op: UnaryOp, expr: Box<Expr> },
+    Binary { op: BinaryOp, left: Box<Expr>, right: Box<Expr> },
+    /// `<expr> BETWEEN <low> <high>`, both bounds are exclusive.
+    Between { expr: Box<Expr>, low: Box<Expr>, high: Box<Expr> },
+    /// `<expr> MATCHES '<pattern>'` with a regular expression.
+    Matches { expr: Box<Expr>, pattern: String },
+    /// `EXISTS .path` or `.path IS NOT MISSING`.
+    Exists(String),
+    /// `.path IS MISSING`.
+    Missing(String),
+    /// `COERCE <expr> _<type>_`, a chain of types nests one `Coerce` per type.
+    Coerce { expr: Box<Expr>, to: Coercion },
+    /// A built-in function such as `len` or `now`, or a function of the host.
+    Call { name: String, args: Vec<Expr> },
+    /// `ANY(<array>, <body>)`, `ALL(<array>, <body>)`, `<array> FILTER (<body>)` or
+    /// `<array> MAP (<body>)`. The selector paths of `body` read each element of the array, `.`
+    /// being the element itself.
+    Iterate { op: IterateOp, array: Box<Expr>, body: Box<Expr> },
+    /// `CASE WHEN <condition> THEN <value> ... ELSE <otherwise> END`, the value of the first
+    /// condition that is `true`, else `otherwise` or `NULL` without it. `<condition> ? <value> :
+    /// <otherwise>` is the same with a single condition.
+    Case { branches: Vec<(Expr, Expr)>, otherwise: Option<Box<Expr>> },
+}
+
+/// What an `Expr::Iterate` does with the `body` of each element.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum IterateOp {
+    /// Whether `body` is `true` for any element.
+    Any,
+    /// Whether `body` is `true` for every element, so `true` for an empty array.
+    All,
+    /// The elements `body` is `true` for.
+    Filter,
+    /// The `body` of each element.
+    Map,
+}
+
+impl IterateOp {
+    /// How the operator is written in an expression.
+    #[must_use]
+    pub fn keyword(self) -> &'static str {
+        match self {
+            IterateOp::Any => "ANY",
+            IterateOp::All => "ALL",
+            IterateOp::Filter => "FILTER",
+            IterateOp::Map => "MAP",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UnaryOp {
+    /// `!`
+    Not,
+    /// Prefix `-`.
+    Neg,
+    /// Prefix `+`.
+    Pos,
+    /// Postfix `IS NULL`.
+    IsNull,
+    /// Postfix `IS NOT NULL`.
+    IsNotNull,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    IntDiv,
+    Rem,
+    Pow,
+    Eq,
+    NotEq,
+    Gt,
+    Gte,
+    Lt,
+    Lte,
+    And,
+    Or,
+    Contains,
+    NotContains,
+    ContainsAny,
+    ContainsAll,
+    In,
+    NotIn,
+    StartsWith,
+    EndsWith,
+    Like,
+    ILike,
+}
+
+impl BinaryOp {
+    /// How the operator is written in an expression.
+    #[must_use]
+    pub fn symbol(self) -> &'static str {
+        match self {
+            BinaryOp::Add => "+",
+            BinaryOp::Sub => "-",
+            BinaryOp::Mul => "*",
+            BinaryOp::Div => "/",
+            BinaryOp::IntDiv => "DIV",
+            BinaryOp::Rem => "%",
+            BinaryOp::Pow => "**",
+            BinaryOp::Eq => "==",
+            BinaryOp::NotEq => "!=",
+            BinaryOp::Gt => ">",
+            BinaryOp::Gte => ">=",
+            BinaryOp::Lt => "<",
+            BinaryOp::Lte => "<=",
+            BinaryOp::And => "&&",
+            BinaryOp::Or => "||",
+            BinaryOp::Contains => "CONTAINS",
+            BinaryOp::NotContains => "NOT CONTAINS",
+            BinaryOp::ContainsAny => "CONTAINS_ANY",
+            BinaryOp::ContainsAll => "CONTAINS_ALL",
+            BinaryOp::In => "IN",
+            BinaryOp::NotIn => "NOT IN",
+            BinaryOp::StartsWith => "STARTS_WITH",
+            BinaryOp::EndsWith => "ENDS_WITH",
+            BinaryOp::Like => "LIKE",
+            BinaryOp::ILike => "ILIKE",
+        }
+    }
+
+    /// Whether the operator compares its sides and results in a boolean.
+    #[must_use]
+    pub fn is_comparison(self) -> bool {
+        !matches!(
+            self,
+            BinaryOp::Add
+                | BinaryOp::Sub
+                | BinaryOp::Mul
+                | BinaryOp::Div
+                | BinaryOp::IntDiv
+                | BinaryOp::Rem
+                | BinaryOp::Pow
+                | BinaryOp::And
+                | BinaryOp::Or
+        )
+    }
+}
+
+/// The type of a `COERCE`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Coercion {
+    /// `_datetime_` with its optional format and time zone as written.
+    DateTime { format: Option<String>, zone: Option<String> },
+    String,
+    Number,
+    #[cfg(feature = "decimal")]
+    Decimal,
+    Lowercase,
+    Uppercase,
+}
+
+impl Expr {
+    /// The selector paths the expression reads, including those of `EXISTS` and `IS MISSING`.
+    /// Those of the body of an `Iterate` read its elements instead of the document, so they are
+    /// left out.
+    #[must_use]
+    pub fn paths(&self) -> BTreeSet<&str> {
+        struct Paths<'ast>(BTreeSet<&'ast str>);
+
+        impl<'ast> Visitor<'ast> for Paths<'ast> {
+            fn visit_expr(&mut self, expr: &'ast Expr) {
+                match expr {
+                    Expr::Path(path) | Expr::Exists(path) | Expr::Missing(path) => {
+                        self.0.insert(path);
+                    }
+                    Expr::Iterate { array, .. } => return self.visit_expr(array),
+                    _ => {}
+                }
+                walk_expr(self, expr);
+            }
+        }
+
+        let mut paths = Paths(BTreeSet::new());
+        paths.visit_expr(self);
+        paths.0
+    }
+
+    /// The direct sub-expressions, in the order they are written.
+    #[must_use]
+    pub fn children(&self) -> Vec<&Expr> {
+        match self {
+            Expr::Literal(_) | Expr::Path(_) | Expr::Variable(_) | Expr::Exists(_) | Expr::Missing(_) => Vec::new(),
+            Expr::Array(items) | Expr::Call { args: items, .. } => items.iter().collect(),
+            Expr::Object(members) => members.iter().map(|(_, value)| value).collect(),
+            Expr::Unary { expr, .. } | Expr::Matches { expr, .. } | Expr::Coerce { expr, .. } => vec![expr],
+            Expr::Binary { left, right, .. } => vec![left, right],
+            Expr::Between { expr, low, high } => vec![expr, low, high],
+            Expr::Iterate { array, body, .. } => vec![array, body],
+            Expr::Case { branches, otherwise } => branches
+                .iter()
+                .flat_map(|(condition, value)| [condition, value])
+                .chain(otherwise.as_deref())
+                .collect(),
+        }
+    }
+}
+
+impl FromStr for Expr {
+    type Err = ExprError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Parser::parse_expr(s)
+    }
+}
+
+/// Walks an `Expr` tree by reference.
+///
+/// `visit_expr` is called for every node, an implementation that overrides it calls `walk_expr`
+/// to carry on with the children.
+pub trait Visitor<'ast> {
+    fn visit_expr(&mut self, expr: &'ast Expr) {
+        walk_expr(self, expr);
+    }
+}
+
+/// Visits the children of `expr`.
+pub fn walk_expr<'ast, V: Visitor<'ast> + ?Sized>(visitor: &mut V, expr: &'ast Expr) {
+    for child in expr.children() {
+        visitor.visit_expr(child);
+    }
+}
+
+/// Rebuilds an `Expr` tree, eg. to rewrite a query.
+///
+/// `fold_expr` is called for every node, an implementation that overrides it calls
+/// `fold_children` to rebuild the children first, or leaves them out to keep them as they are.
+pub trait Fold {
+    fn fold_expr(&mut self, expr: Expr) -> Expr {
+        fold_children(self, expr)
+    }
+}
+
+/// Folds the children of `expr` and puts them back in place.
+pub fn fold_children<F: Fold + ?Sized>(folder: &mut F, expr: Expr) -> Expr {
+    let mut fold = |expr: Box<Expr>| Box::new(folder.fold_expr(*expr));
+    match expr {
+        Expr::Literal(_) | Expr::Path(_) | Expr::Variable(_) | Expr::Exists(_) | Expr::Missing(_) => expr,
+        Expr::Array(items) => Expr::Array(items.into_iter().map(|e| folder.fold_expr(e)).collect()),
+        Expr::Object(members) => Expr::Object(
+            members
+                .into_iter()
+                .map(|(key, value)| (key, folder.fold_expr(value)))
+                .collect(),
+        ),
+        Expr::Call { name, args } => Expr::Call {
+            name,
+            args: args.into_iter().map(|e| folder.fold_expr(e)).collect(),
+        },
+        Expr::Unary { op, expr } => Expr::Unary { op, expr: fold(expr) },
+        Expr::Binary { op, left, right } => Expr::Binary {
+            op,
+            left: fold(left),
+            right: fold(right),
+        },
+        Expr::Between { expr, low, high } => Expr::Between {
+            expr: fold(expr),
+            low: fold(low),
+            high: fold(high),
+        },
+        Expr::Matches { expr, pattern } => Expr::Matches { expr: fold(expr), pattern },
+        Expr::Coerce { expr, to } => Expr::Coerce { expr: fold(expr), to },
+        Expr::Iterate { op, array, body } => Expr::Iterate {
+            op,
+            array: fold(array),
+            body: fold(body),
+        },
+        Expr::Case { branches, otherwise } => Expr::Case {
+            branches: branches
+                .into_iter()
+                .map(|(condition, value)| (folder.fold_expr(condition), folder.fold_expr(value)))
+                .collect(),
+            otherwise: otherwise.map(|otherwise| Box::new(folder.fold_expr(*otherwise))),
+        },
+    }
+}
+
+impl Display for Expr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Expr::Literal(value) => write_literal(f, value),
+            Expr::Path(path) => write!(f, ".{path}"),
+            Expr::Variable(name) => write!(f, "${name}"),
+            Expr::Array(items) => {
+                f.write_str("[")?;
+                write_list(f, items)?;
+                f.write_str("]")
+            }
+            Expr::Object(members) => {
+                f.write_str("{")?;
+                for (i, (key, value)) in members.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    write_quoted(f, key)?;
+                    write!(f, ": {value}")?;
+                }
+                f.write_str("}")
+            }
+            Expr::Unary { op, expr } => match op {
+                UnaryOp::Not => write!(f, "(!{expr})"),
+                UnaryOp::Neg => write!(f, "(-{expr})"),
+                UnaryOp::Pos => write!(f, "(+{expr})"),
+                UnaryOp::IsNull => write!(f, "({expr} IS NULL)"),
+                UnaryOp::IsNotNull => write!(f, "({expr} IS NOT NULL)"),
+            },
+            Expr::Binary { op, left, right } => write!(f, "({left} {} {right})", op.symbol()),
+            Expr::Between { expr, low, high } => write!(f, "({expr} BETWEEN {low} {high})"),
+            Expr::Matches { expr, pattern } => {
+                write!(f, "({expr} MATCHES ")?;
+                write_quoted(f, pattern)?;
+                f.write_str(")")
+            }
+            Expr::Exists(path) => write!(f, "EXISTS .{path}"),
+            Expr::Missing(path) => write!(f, "(.{path} IS MISSING)"),
+            Expr::Coerce { expr, to } => {
+                write!(f, "(COERCE {expr} ")?;
+                match to {
+                    Coercion::DateTime { format, zone } => {
+                        f.write_str("_datetime_")?;
+                        let args: Vec<&String> = format.iter().chain(zone).collect();
+                        if !args.is_empty() {
+                            f.write_str("(")?;
+                            for (i, arg) in args.into_iter().enumerate() {
+                                if i > 0 {
+                                    f.write_str(", ")?;
+                                }
+                                write_quoted(f, arg)?;
+                            }
+                            f.write_str(")")?;
+                        }
+                    }
+                    Coercion::String => f.write_str("_string_")?,
+                    Coercion::Number => f.write_str("_number_")?,
+                    #[cfg(feature = "decimal")]
+                    Coercion::Decimal => f.write_str("_decimal_")?,
+                    Coercion::Lowercase => f.write_str("_lowercase_")?,
+                    Coercion::Uppercase => f.write_str("_uppercase_")?,
+                }
+                f.write_str(")")
+            }
+            Expr::Call { name, args } => {
+                write!(f, "{name}(")?;
+                write_list(f, args)?;
+                f.write_str(")")
+            }
+            Expr::Iterate { op: op @ (IterateOp::Any | IterateOp::All), array, body } => {
+                write!(f, "{}({array}, {body})", op.keyword())
+            }
+            Expr::Iterate { op, array, body } => write!(f, "({array} {} ({body}))", op.keyword()),
+            Expr::Case { branches, otherwise } => {
+                f.write_str("CASE")?;
+                for (condition, value) in branches {
+                    write!(f, " WHEN {condition} THEN {value}")?;
+                }
+                if let Some(otherwise) = otherwise {
+                    write!(f, " ELSE {otherwise}")?;
+                }
+                f.write_str(" END")
+            }
+        }
+    }
+}
+
+fn write_list(f: &mut Formatter<'_>, items: &[Expr]) -> std::fmt::Result {
+    for (i, item) in items.iter().enumerate() {
+        if i > 0 {
+            f.write_str(", ")?;
+        }
+        write!(f, "{item}")?;
+    }
+    Ok(())
+}
+
+/// Strings are read without unescaping, so they are written in the quote they do not contain.
+fn write_quoted(f: &mut Formatter<'_>, s: &str) -> std::fmt::Result {
+    if s.contains('"') {
+        write!(f, "'{s}'")
+    } else {
+        write!(f, "\"{s}\"")
+    }
+}
+
+fn write_literal(f: &mut Formatter<'_>, value: &Value) -> std::fmt::Result {
+    match value {
+        Value::Null | Value::Missing => f.write_str("NULL"),
+        Value::String(s) => write_quoted(f, s),
+        // negative numbers are parenthesized so that `(-2) ** 2` is not read as `-(2 ** 2)`
+        Value::Int(i) if *i < 0 => write!(f, "({i})"),
+        Value::Int(i) => write!(f, "{i}"),
+        // `{:?}` keeps the fraction of whole numbers, so they are read back as a `Number`
+        Value::Number(n) if n.is_sign_negative() => write!(f, "({n:?})"),
+        Value::Number(n) => write!(f, "{n:?}"),
+        #[cfg(feature = "decimal")]
+        Value::Decimal(d) => write!(f, "(COERCE \"{d}\" _decimal_)"),
+        Value::Bool(b) => write!(f, "{b}"),
+        Value::DateTime(dt) => write!(
+            f,
+            "(COERCE \"{}\" _datetime_)",
+            dt.to_rfc3339_opts(SecondsFormat::AutoSi, true)
+        ),
+        Value::Duration(d) if *d < chrono::Duration::zero() => write!(f, "({})", format_duration(d)),
+        Value::Duration(d) => f.write_str(&format_duration(d)),
+        Value::Array(items) => {
+            f.write_str("[")?;
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    f.write_str(", ")?;
+                }
+                write_literal(f, item)?;
+            }
+            f.write_str("]")
+        }
+        Value::Object(members) => {
+            f.write_str("{")?;
+            for (i, (key, value)) in members.iter().enumerate() {
+                if i > 0 {
+                    f.write_str(", ")?;
+                }
+                write_quoted(f, key)?;
+                f.write_str(": ")?;
+                write_literal(f, value)?;
+            }
+            f.write_str("}")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fold_children, walk_expr, BinaryOp, Expr, Fold, Visitor};
+    use crate::exp_parser::{Parser, Value};
+
+    #[test]
+    fn display_parses_back() {
+        for expression in [
+            ".a + .b * 2 == 5 || !(.c IN [1, 2.5, 'x'])",
+            "-.a ** 2 + (-2) ** 2 - -3 % 2 DIV 1",
+            ".a BETWEEN -2 .b + 1 && .n NOT IN [NULL, true]",
+            ".name MATCHES '^T\"o' && .name ILIKE \"t*\" && .tags NOT CONTAINS \"c\"",
+            ".a IS MISSING || .b IS NOT MISSING || EXISTS .c || .d IS NOT NULL",
+            "COERCE .date _datetime_('%Y', 'Asia/Tokyo'),_string_ == COERCE \"2024\" _datetime_",
+            "len(trim(.name)) > $min && now() - 7d < COERCE .at _datetime_ && lookup(.id) == -1.5",
+            "ANY(.items, .qty > 5) && ALL(.tags, . STARTS_WITH 'x') || size(.items FILTER (.p > 1) MAP (.p)) > 1",
+            "CASE WHEN .a > 1 THEN 'big' WHEN .a IS NULL THEN .b ELSE CASE WHEN .c THEN 1 END END",
+            ".a ? .b ? 1 : 2 : .c || .d ? 3 : 4",
+            "{'total': .price * .qty, \"say \\\"hi\\\"\": [.a + 1 .b -1, {}], 'items': .items MAP ({\"sku\": .sku})}",
+        ] {
+            let expr = Parser::parse_expr(expression).unwrap();
+            assert_eq!(Parser::parse_expr(&expr.to_string()).unwrap(), expr, "{expression} as {expr}");
+        }
+    }
+
+    #[test]
+    fn visit_and_fold() {
+        struct Operators(Vec<BinaryOp>);
+
+        impl Visitor<'_> for Operators {
+            fn visit_expr(&mut self, expr: &Expr) {
+                if let Expr::Binary { op, .. } = expr {
+                    self.0.push(*op);
+                }
+                walk_expr(self, expr);
+            }
+        }
+
+        /// Moves every path under `user.`.
+        struct Prefix;
+
+        impl Fold for Prefix {
+            fn fold_expr(&mut self, expr: Expr) -> Expr {
+                match expr {
+                    Expr::Path(path) => Expr::Path(format!("user.{path}")),
+                    expr => fold_children(self, expr),
+                }
+            }
+        }
+
+        let expr = Parser::parse_expr(".age >= 18 && (.name == 'Tom' || EXISTS .admin)").unwrap();
+        assert_eq!(expr.paths(), ["admin", "age", "name"].into());
+        let iterate = Parser::parse_expr("ANY(.items, .qty > 5) || .total > 1").unwrap();
+        assert_eq!(iterate.paths(), ["items", "total"].into());
+
+        let mut operators = Operators(Vec::new());
+        operators.visit_expr(&expr);
+        assert_eq!(operators.0, [BinaryOp::And, BinaryOp::Gte, BinaryOp::Or, BinaryOp::Eq]);
+        assert!(operators.0.iter().filter(|op| op.is_comparison()).count() == 2);
+
+        let expr = Prefix.fold_expr(expr);
+        assert_eq!(expr.paths(), ["admin", "user.age", "user.name"].into());
+        let expression = Parser::compile(expr).unwrap();
+        assert_eq!(
+            expression.calculate(br#"{"user":{"age":20,"name":"Tom"}}"#),
+            Ok(Value::Bool(true))
+        );
+    }
+
+    #[test]
+    fn invalid() {
+        let expr = Expr::Call {
+            name: "len".to_string(),
+            args: Vec::new(),
+        };
+        assert!(Parser::compile(expr).is_err());
+        assert!(Parser::compile(Parser::parse_expr(".a MATCHES 'b'").unwrap()).is_ok());
+        assert!(Parser::compile(Expr::Matches {
+            expr: Box::new(Expr::Path("a".to_string())),
+            pattern: "(".to_string(),
+        })
+        .is_err());
+        assert!("".parse::<Expr>().is_err());
+    }
+}
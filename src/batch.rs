@@ -0,0 +1,245 @@
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+use crate::exp_parser::{Error, EvalContext, Expression, Value};
+
+/// What a batch helper gathered from its documents, along with the documents it could not
+/// calculate.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Batch<T> {
+    pub output: T,
+    pub errors: Vec<DocumentError>,
+}
+
+/// The error of one document of a batch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DocumentError {
+    /// The position of the document in the input, counting from 0. Blank documents count too,
+    /// so for `json_lines` it is the line of the document.
+    pub index: usize,
+    pub error: Error,
+}
+
+/// The documents of JSON Lines `input`, one per line and trimmed. Blank lines are kept as empty
+/// documents, which the batch helpers skip, so the `index` of a `DocumentError` is its line.
+pub fn json_lines(input: &[u8]) -> impl Iterator<Item = &[u8]> {
+    input.split(|b| *b == b'\n').map(|line| line.trim_ascii())
+}
+
+/// The `documents` that `expression` calculates to `true` for, in their order.
+///
+/// ```
+/// use flql::batch::{filter, json_lines};
+/// use flql::exp_parser::{EvalContext, Parser};
+///
+/// let expression = Parser::parse(".age > 30").unwrap();
+/// let input = b"{\"age\":25}\n{\"age\":40}\n{\"age\":\"old\"}\n";
+/// let batch = filter(expression.as_ref(), &EvalContext::default(), json_lines(input));
+/// assert_eq!(batch.output, vec![&b"{\"age\":40}"[..]]);
+/// assert_eq!(batch.errors.len(), 1);
+/// assert_eq!(batch.errors[0].index, 2);
+/// ```
+pub fn filter<I, D>(
+    expression: &dyn Expression,
+    ctx: &EvalContext,
+    documents: I,
+) -> Batch<Vec<D>>
+    where
+        I: IntoIterator<Item=D>,
+        D: AsRef<[u8]>,
+{
+    filter_map(expression, ctx, documents, |document, value| {
+        is_match(&value).then_some(document)
+    })
+}
+
+/// Calculates `expression` for each of the `documents` and keeps what `f` returns for them.
+/// Blank documents are skipped.
+pub fn filter_map<I, D, T, F>(
+    expression: &dyn Expression,
+    ctx: &EvalContext,
+    documents: I,
+    f: F,
+) -> Batch<Vec<T>>
+    where
+        I: IntoIterator<Item=D>,
+        D: AsRef<[u8]>,
+        F: Fn(D, Value) -> Option<T>,
+{
+    documents
+        .into_iter()
+        .enumerate()
+        .fold(Batch::default(), |batch, (index, document)| {
+            batch.push(index, expression, ctx, document, &f)
+        })
+}
+
+/// The number of `documents` that `expression` calculates to `true` for.
+pub fn count_matches<I, D>(
+    expression: &dyn Expression,
+    ctx: &EvalContext,
+    documents: I,
+) -> Batch<usize>
+    where
+        I: IntoIterator<Item=D>,
+        D: AsRef<[u8]>,
+{
+    let batch = filter_map(expression, ctx, documents, |_, value| {
+        is_match(&value).then_some(())
+    });
+    Batch {
+        output: batch.output.len(),
+        errors: batch.errors,
+    }
+}
+
+/// Same as `filter`, calculating the documents on the threads of the rayon pool.
+#[cfg(feature = "parallel")]
+pub fn par_filter<I, D>(
+    expression: &dyn Expression,
+    ctx: &EvalContext,
+    documents: I,
+) -> Batch<Vec<D>>
+    where
+        I: IntoIterator<Item=D>,
+        D: AsRef<[u8]> + Send,
+{
+    par_filter_map(expression, ctx, documents, |document, value| {
+        is_match(&value).then_some(document)
+    })
+}
+
+/// Same as `filter_map`, calculating the documents on the threads of the rayon pool. The output
+/// keeps the order of the documents.
+#[cfg(feature = "parallel")]
+pub fn par_filter_map<I, D, T, F>(
+    expression: &dyn Expression,
+    ctx: &EvalContext,
+    documents: I,
+    f: F,
+) -> Batch<Vec<T>>
+    where
+        I: IntoIterator<Item=D>,
+        D: AsRef<[u8]> + Send,
+        T: Send,
+        F: Fn(D, Value) -> Option<T> + Sync,
+{
+    documents
+        .into_iter()
+        .collect::<Vec<_>>()
+        .into_par_iter()
+        .enumerate()
+        .fold(Batch::default, |batch, (index, document)| {
+            batch.push(index, expression, ctx, document, &f)
+        })
+        .reduce(Batch::default, |mut batch, rest| {
+            batch.output.extend(rest.output);
+            batch.errors.extend(rest.errors);
+            batch
+        })
+}
+
+/// Same as `count_matches`, calculating the documents on the threads of the rayon pool.
+#[cfg(feature = "parallel")]
+pub fn par_count_matches<I, D>(
+    expression: &dyn Expression,
+    ctx: &EvalContext,
+    documents: I,
+) -> Batch<usize>
+    where
+        I: IntoIterator<Item=D>,
+        D: AsRef<[u8]> + Send,
+{
+    let batch = par_filter_map(expression, ctx, documents, |_, value| {
+        is_match(&value).then_some(())
+    });
+    Batch {
+        output: batch.output.len(),
+        errors: batch.errors,
+    }
+}
+
+impl<T> Batch<Vec<T>> {
+    fn push<D: AsRef<[u8]>>(
+        mut self,
+        index: usize,
+        expression: &dyn Expression,
+        ctx: &EvalContext,
+        document: D,
+        f: impl Fn(D, Value) -> Option<T>,
+    ) -> Self {
+        if document.as_ref().trim_ascii().is_empty() {
+            return self;
+        }
+        match expression.calculate_with(document.as_ref(), ctx) {
+            Ok(value) => self.output.extend(f(document, value)),
+            Err(error) => self.errors.push(DocumentError { index, error }),
+        }
+        self
+    }
+}
+
+fn is_match(value: &Value) -> bool {
+    *value == Value::Bool(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{count_matches, filter, filter_map, json_lines, Batch, DocumentError};
+    use crate::exp_parser::{Error, EvalContext, Parser, Value};
+
+    const INPUT: &[u8] = b"{\"n\":1,\"d\":2}\n\n{\"n\":2,\"d\":0}\r\n{\"n\":3,\"d\":1}\n{\"n\":4}";
+
+    #[test]
+    fn helpers() {
+        let expression = Parser::parse(".n / .d > 1").unwrap();
+        let ctx = EvalContext::default();
+        let errors = vec![DocumentError {
+            index: 2,
+            error: Error::DivisionByZero("2 / 0".to_string()),
+        }];
+
+        assert_eq!(json_lines(INPUT).count(), 5);
+        assert_eq!(
+            filter(expression.as_ref(), &ctx, json_lines(INPUT)),
+            Batch {
+                output: vec![&b"{\"n\":3,\"d\":1}"[..]],
+                errors: errors.clone(),
+            }
+        );
+        assert_eq!(
+            count_matches(expression.as_ref(), &ctx, json_lines(INPUT)),
+            Batch { output: 1, errors: errors.clone() }
+        );
+
+        let expression = Parser::parse(".n * 10").unwrap();
+        let above_10 = |_, value| match value {
+            Value::Int(n) if n > 10 => Some(n),
+            _ => None,
+        };
+        let batch = filter_map(expression.as_ref(), &ctx, json_lines(INPUT), above_10);
+        assert_eq!(batch, Batch { output: vec![20, 30, 40], errors: vec![] });
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn parallel() {
+        use super::{par_count_matches, par_filter, par_filter_map};
+
+        let expression = Parser::parse(".n % 3 == 0 && 10 / (.n % 7) > 1").unwrap();
+        let ctx = EvalContext::default();
+        let documents: Vec<String> = (0..10_000).map(|n| format!("{{\"n\":{n}}}")).collect();
+
+        let sequential = filter(expression.as_ref(), &ctx, &documents);
+        assert_eq!(par_filter(expression.as_ref(), &ctx, &documents), sequential);
+        assert_eq!(sequential.errors.len(), 477);
+        assert_eq!(
+            par_count_matches(expression.as_ref(), &ctx, &documents),
+            count_matches(expression.as_ref(), &ctx, &documents)
+        );
+        let keep = |_: &String, value| Some(value);
+        assert_eq!(
+            par_filter_map(expression.as_ref(), &ctx, &documents, keep),
+            filter_map(expression.as_ref(), &ctx, &documents, keep)
+        );
+    }
+}
@@ -0,0 +1,105 @@
+use crate::exp_parser::Value;
+use crate::gjson::gjson::{get_bytes, parse};
+use crate::gjson::util::tostr;
+
+/// A document that expressions read their selector paths from, see
+/// `Expression::calculate_document`.
+///
+/// The paths are gjson paths. Raw JSON resolves all of them with gjson, the other documents
+/// walk the keys and array indexes of plain paths such as `name.first` or `tags.0` themselves and
+/// serialize to JSON for gjson to resolve the others, such as `friends.#(age>40)`. The empty path,
+/// written `.` in an expression, is the whole document.
+///
+/// ```
+/// use flql::exp_parser::{EvalContext, Expression, Parser, Value};
+///
+/// let expression = Parser::parse(".age > 30 && .tags.0 == 'admin'").unwrap();
+/// let document = serde_json::json!({"age": 40, "tags": ["admin"]});
+/// assert_eq!(
+///     expression.calculate_document(&document, &EvalContext::default()),
+///     Ok(Value::Bool(true))
+/// );
+/// ```
+pub trait Document {
+    /// The value of `path`, `None` if the document does not have it.
+    fn get(&self, path: &str) -> Option<Value>;
+
+    /// Whether the document has `path`.
+    fn exists(&self, path: &str) -> bool {
+        self.get(path).is_some()
+    }
+
+    /// The raw JSON of the document, which a `vm::Program` reads in a single pass.
+    fn json(&self) -> Option<&[u8]> {
+        None
+    }
+}
+
+impl Document for &[u8] {
+    fn get(&self, path: &str) -> Option<Value> {
+        if path.is_empty() {
+            let value = parse(tostr(self));
+            return value.exists().then(|| value.into());
+        }
+        let value = unsafe { get_bytes(self, path) };
+        value.exists().then(|| value.into())
+    }
+
+    fn exists(&self, path: &str) -> bool {
+        if path.is_empty() {
+            return parse(tostr(self)).exists();
+        }
+        unsafe { get_bytes(self, path).exists() }
+    }
+
+    fn json(&self) -> Option<&[u8]> {
+        Some(self)
+    }
+}
+
+impl Document for serde_json::Value {
+    fn get(&self, path: &str) -> Option<Value> {
+        if path.is_empty() {
+            return Some(self.into());
+        }
+        if !is_keys(path) {
+            return Document::get(&serde_json::to_vec(self).ok()?.as_slice(), path);
+        }
+        path.split('.')
+            .try_fold(self, |value, key| match value {
+                serde_json::Value::Object(members) => members.get(key),
+                serde_json::Value::Array(items) => items.get(key.parse::<usize>().ok()?),
+                _ => None,
+            })
+            .map(Value::from)
+    }
+}
+
+impl Document for Value {
+    fn get(&self, path: &str) -> Option<Value> {
+        if path.is_empty() {
+            return (*self != Value::Missing).then(|| self.clone());
+        }
+        if !is_keys(path) {
+            return Document::get(&serde_json::to_vec(self).ok()?.as_slice(), path);
+        }
+        path.split('.')
+            .try_fold(self, |value, key| match value {
+                Value::Object(members) => members.get(key),
+                Value::Array(items) => items.get(key.parse::<usize>().ok()?),
+                _ => None,
+            })
+            .filter(|value| **value != Value::Missing)
+            .cloned()
+    }
+}
+
+/// Whether `path` is a path of keys and array indexes only, which gjson reads literally.
+pub(crate) fn is_keys(path: &str) -> bool {
+    path.split('.').all(|key| {
+        !key.is_empty()
+            && key.bytes().all(|b| {
+                !b.is_ascii_whitespace() && !b"*?|#@\\!=<>%~[](){},:\"'".contains(&b)
+            })
+    })
+}
@@ -0,0 +1,72 @@
+use std::time::Duration;
+
+/// Parses a human-friendly duration such as `90s`, `15m`, `7d` or `1h30m`.
+///
+/// Supported units are `ms`, `s`, `m`, `h`, `d` and `w`. A bare number is taken as seconds.
+///
+/// # Errors
+///
+/// Will return `Err` if the duration is empty, has an unknown unit or overflows.
+pub fn parse_duration(duration: &str) -> Result<Duration, String> {
+    let duration = duration.trim();
+    if duration.is_empty() {
+        return Err("empty duration".to_string());
+    }
+    if duration.bytes().all(|b| b.is_ascii_digit()) {
+        return duration
+            .parse()
+            .map(Duration::from_secs)
+            .map_err(|e| format!("invalid duration `{duration}`: {e}"));
+    }
+
+    let mut total = Duration::ZERO;
+    let mut rest = duration;
+    while !rest.is_empty() {
+        let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+        if digits == 0 {
+            return Err(format!("invalid duration `{duration}`: expected a number"));
+        }
+        let amount: u64 = rest[..digits]
+            .parse()
+            .map_err(|e| format!("invalid duration `{duration}`: {e}"))?;
+        rest = &rest[digits..];
+
+        let unit_len = rest.bytes().take_while(u8::is_ascii_alphabetic).count();
+        let part = match &rest[..unit_len] {
+            "ms" => Some(Duration::from_millis(amount)),
+            "s" => Some(Duration::from_secs(amount)),
+            "m" => amount.checked_mul(60).map(Duration::from_secs),
+            "h" => amount.checked_mul(60 * 60).map(Duration::from_secs),
+            "d" => amount.checked_mul(24 * 60 * 60).map(Duration::from_secs),
+            "w" => amount.checked_mul(7 * 24 * 60 * 60).map(Duration::from_secs),
+            unit => return Err(format!("invalid duration `{duration}`: unknown unit `{unit}`")),
+        };
+        rest = &rest[unit_len..];
+
+        total = part
+            .and_then(|part| total.checked_add(part))
+            .ok_or_else(|| format!("invalid duration `{duration}`: overflow"))?;
+    }
+    Ok(total)
+}
+
+/// Writes a duration the way `parse_duration` reads it, eg. `1h30m`, prefixed with `-` when negative.
+pub(crate) fn format_duration(duration: &chrono::Duration) -> String {
+    use std::fmt::Write;
+
+    let mut s = String::new();
+    if *duration < chrono::Duration::zero() {
+        s.push('-');
+    }
+    let mut millis = duration.num_milliseconds().unsigned_abs();
+    for (unit, len) in [("w", 604_800_000), ("d", 86_400_000), ("h", 3_600_000), ("m", 60_000), ("s", 1000), ("ms", 1)] {
+        if millis >= len {
+            let _ = write!(s, "{}{unit}", millis / len);
+            millis %= len;
+        }
+    }
+    if s.is_empty() || s == "-" {
+        s = "0s".to_string();
+    }
+    s
+}
@@ -0,0 +1,134 @@
+use crate::lexer;
+use std::fmt::{Display, Formatter};
+use std::ops::Range;
+use thiserror::Error;
+
+/// Error of `Parser::parse_expr`, with the byte range of the expression it is about.
+///
+/// `render` underlines the range in the expression, eg. for `.age >= 18 &&`:
+///
+/// ```text
+/// unexpected end of expression at 13..13, expected a value
+///   |
+/// 1 | .age >= 18 &&
+///   |              ^
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExprError {
+    pub kind: ExprErrorKind,
+    /// The bytes of the expression the error is about, empty at its end when it ends unexpectedly.
+    pub span: Range<usize>,
+    /// What the parser would have accepted at `span`, eg. a closing parenthesis or a value.
+    pub expected: Vec<&'static str>,
+}
+
+/// The kind of `ExprError`.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum ExprErrorKind {
+    #[error(transparent)]
+    Lex(#[from] lexer::Error),
+
+    #[error("unexpected `{0}`")]
+    UnexpectedToken(String),
+
+    #[error("unexpected end of expression")]
+    UnexpectedEnd,
+
+    #[error("{0}")]
+    Invalid(String),
+}
+
+impl ExprError {
+    #[must_use]
+    pub fn new(kind: impl Into<ExprErrorKind>, span: Range<usize>) -> Self {
+        ExprError {
+            kind: kind.into(),
+            span,
+            expected: Vec::new(),
+        }
+    }
+
+    #[must_use]
+    pub fn with_expected(mut self, expected: &[&'static str]) -> Self {
+        self.expected = expected.to_vec();
+        self
+    }
+
+    /// The error followed by the line of `source` it is about, with its span underlined.
+    /// `source` is the expression that was parsed.
+    #[must_use]
+    pub fn render(&self, source: &str) -> String {
+        let start = floor_char_boundary(source, self.span.start);
+        let end = floor_char_boundary(source, self.span.end.max(start));
+        let line_start = source[..start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = source[start..].find('\n').map_or(source.len(), |i| start + i);
+        let line_number = source[..start].matches('\n').count() + 1;
+        let column = source[line_start..start].chars().count();
+        let width = source[start..end.min(line_end)].chars().count().max(1);
+
+        let number = line_number.to_string();
+        let gutter = " ".repeat(number.len());
+        format!(
+            "{self}\n{gutter} |\n{number} | {}\n{gutter} | {}{}",
+            &source[line_start..line_end],
+            " ".repeat(column),
+            "^".repeat(width),
+        )
+    }
+}
+
+impl Display for ExprError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} at {}..{}", self.kind, self.span.start, self.span.end)?;
+        match self.expected.as_slice() {
+            [] => Ok(()),
+            [expected] => write!(f, ", expected {expected}"),
+            expected => write!(f, ", expected one of {}", expected.join(", ")),
+        }
+    }
+}
+
+impl std::error::Error for ExprError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match &self.kind {
+            ExprErrorKind::Lex(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+fn floor_char_boundary(s: &str, index: usize) -> usize {
+    let mut index = index.min(s.len());
+    while !s.is_char_boundary(index) {
+        index -= 1;
+    }
+    index
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ExprError, ExprErrorKind};
+
+    #[test]
+    fn render() {
+        let error = ExprError::new(ExprErrorKind::UnexpectedToken(")".to_string()), 6..7)
+            .with_expected(&["a value"]);
+        assert_eq!(
+            error.render(".a == ) && .b"),
+            "unexpected `)` at 6..7, expected a value\n  |\n1 | .a == ) && .b\n  |       ^"
+        );
+
+        let error = ExprError::new(ExprErrorKind::UnexpectedEnd, 24..24)
+            .with_expected(&["`]`", "`,`"]);
+        assert_eq!(
+            error.render(".a == 1 &&\n(.é IN [1, 2"),
+            "unexpected end of expression at 24..24, expected one of `]`, `,`\n  |\n2 | (.é IN [1, 2\n  |             ^"
+        );
+
+        let error = ExprError::new(ExprErrorKind::Invalid("unknown time zone".to_string()), 10..24);
+        assert_eq!(
+            error.render(".a IN [\n  'Mars/Olympus'\n]"),
+            "unknown time zone at 10..24\n  |\n2 |   'Mars/Olympus'\n  |   ^^^^^^^^^^^^^^"
+        );
+    }
+}
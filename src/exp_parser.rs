@@ -1,31 +1,84 @@
 use crate::lexer::{Token, TokenKind, Tokenizer};
+use crate::error::{ExprError, ExprErrorKind};
 use anyhow::anyhow;
-use chrono::{DateTime, SecondsFormat, Utc};
-use serde::Serialize;
-use std::collections::BTreeMap;
+use chrono::{
+    DateTime, FixedOffset, NaiveDate, NaiveDateTime, NaiveTime, Offset, SecondsFormat, TimeZone,
+    Utc,
+};
+use chrono_tz::Tz;
+use serde::{Serialize, Serializer};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt::{Debug, Display, Formatter};
 use std::iter::Peekable;
+use std::ops::Range;
 use thiserror::Error;
 use crate::gjson::gjson;
-use crate::gjson::gjson::{get_bytes, Kind};
+use crate::gjson::gjson::Kind;
+use crate::document::Document;
+use crate::gjson::util::pmatch;
+use crate::functions::{self, Builtin, FunctionRegistry};
+use regex::Regex;
+use crate::duration::{format_duration, parse_duration};
+use crate::number::{self, Op};
+use crate::ast::{BinaryOp, Coercion, Expr, IterateOp, UnaryOp};
+use crate::optimizer;
+use crate::vm::Program;
 
 /// Represents the calculated Expression result.
-#[derive(Debug, PartialEq, Clone, Serialize)]
+///
+/// Numbers are an exact `Int` when they are whole and fit an `i64`, a `Number` otherwise, and an
+/// exact `Decimal` when coerced with `_decimal_` under the `decimal` feature. Numbers of different
+/// kinds compare by value and arithmetic promotes to the wider kind, `Int` to `Decimal` to `Number`.
+#[derive(Debug, Clone, Serialize)]
 #[serde(untagged)]
 pub enum Value {
     Null,
+    /// The value of a selector path that does not exist in the document, unlike `Null` which
+    /// is an explicit JSON `null`. It is written as `null` when serialized.
+    ///
+    /// It only equals itself, ordering and containment comparisons against it are `false`,
+    /// arithmetic on it stays `Missing` and `&&`, `||` and `!` treat it as `false`.
+    /// `IS NOT NULL` is `false` for it too. See `ParseOptions::missing_as_null` for the legacy behaviour.
+    Missing,
     String(String),
+    Int(i64),
     Number(f64),
+    /// An exact decimal number, written as a string when serialized.
+    #[cfg(feature = "decimal")]
+    Decimal(rust_decimal::Decimal),
     Bool(bool),
-    DateTime(DateTime<Utc>), // What to put here arg! do we preserve the original zone etc..?
+    /// A point in time with the offset it was written in, comparisons are by instant.
+    DateTime(DateTime<FixedOffset>),
+    /// A span of time such as `7d`, written like a duration literal when serialized.
+    #[serde(serialize_with = "serialize_duration")]
+    Duration(chrono::Duration),
     Object(BTreeMap<String, Value>),
     Array(Vec<Value>),
 }
 
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Value::Null, Value::Null) | (Value::Missing, Value::Missing) => true,
+            (Value::String(a), Value::String(b)) => a == b,
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            (Value::DateTime(a), Value::DateTime(b)) => a == b,
+            (Value::Duration(a), Value::Duration(b)) => a == b,
+            (Value::Object(a), Value::Object(b)) => a == b,
+            (Value::Array(a), Value::Array(b)) => a == b,
+            (a, b) => number::compare(a, b) == Some(Ordering::Equal),
+        }
+    }
+}
+
 impl Display for Value {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         use serde::ser::Error;
 
+        if self == &Value::Missing {
+            return f.write_str("missing");
+        }
         match serde_json::to_string(self) {
             Ok(s) => {
                 f.write_str(&s)?;
@@ -36,12 +89,16 @@ impl Display for Value {
     }
 }
 
+fn serialize_duration<S: Serializer>(duration: &chrono::Duration, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+    serializer.serialize_str(&format_duration(duration))
+}
+
 impl<'a> From<gjson::Value<'a>> for Value {
     fn from(v: gjson::Value) -> Self {
         match v.kind() {
             Kind::Null => Value::Null,
             Kind::String => Value::String(v.str().to_string()),
-            Kind::Number => Value::Number(v.f64()),
+            Kind::Number => number::from_json(v.json(), || v.f64()),
             Kind::False => Value::Bool(false),
             Kind::True => Value::Bool(true),
             Kind::Array => {
@@ -60,6 +117,24 @@ impl<'a> From<gjson::Value<'a>> for Value {
     }
 }
 
+impl From<&serde_json::Value> for Value {
+    fn from(v: &serde_json::Value) -> Self {
+        match v {
+            serde_json::Value::Null => Value::Null,
+            serde_json::Value::Bool(b) => Value::Bool(*b),
+            serde_json::Value::Number(n) => match n.as_i64() {
+                Some(i) => Value::Int(i),
+                None => Value::Number(n.as_f64().unwrap_or(f64::NAN)),
+            },
+            serde_json::Value::String(s) => Value::String(s.clone()),
+            serde_json::Value::Array(items) => Value::Array(items.iter().map(Into::into).collect()),
+            serde_json::Value::Object(members) => Value::Object(
+                members.iter().map(|(k, v)| (k.clone(), v.into())).collect(),
+            ),
+        }
+    }
+}
+
 /// Represents a stateless parsed expression that can be applied to JSON data.
 pub trait Expression: Debug + Send + Sync {
     /// Will execute the parsed expression and apply it against the supplied json data.
@@ -72,18 +147,231 @@ pub trait Expression: Debug + Send + Sync {
     ///
     /// Will return `Err` if the expression cannot be applied to the supplied data due to invalid
     /// data type comparisons.
-    fn calculate(&self, json: &[u8]) -> Result<Value>;
+    #[inline]
+    fn calculate(&self, json: &[u8]) -> Result<Value> {
+        self.calculate_with(json, &EvalContext::default())
+    }
+
+    /// Same as `calculate`, resolving `$variables` and the functions of the host from `ctx`.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the expression cannot be applied to the supplied data due to invalid
+    /// data type comparisons, or a function or variable that is not in `ctx`.
+    #[inline]
+    fn calculate_with(&self, json: &[u8], ctx: &EvalContext) -> Result<Value> {
+        self.calculate_document(&json, ctx)
+    }
+
+    /// Same as `calculate_with`, reading the selector paths from any `Document`, such as a
+    /// `serde_json::Value` a caller already holds.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the expression cannot be applied to the supplied document due to
+    /// invalid data type comparisons, or a function or variable that is not in `ctx`.
+    fn calculate_document(&self, document: &dyn Document, ctx: &EvalContext) -> Result<Value>;
+}
+
+/// Variables and host functions available while calculating an `Expression`.
+///
+/// ```
+/// use flql::exp_parser::{EvalContext, Error, Parser, Value};
+/// use flql::functions::FunctionRegistry;
+///
+/// let mut functions = FunctionRegistry::new();
+/// functions.register("is_even", |args| match args {
+///     [Value::Int(n)] => Ok(Value::Bool(n % 2 == 0)),
+///     _ => Err(Error::UnsupportedFunctionCall("is_even expects a number".to_string())),
+/// });
+/// let ctx = EvalContext::new()
+///     .with_functions(&functions)
+///     .with_variable("user_id", Value::Int(7));
+///
+/// let expression = Parser::parse(".owner == $user_id && is_even(.size)").unwrap();
+/// assert_eq!(
+///     expression.calculate_with(br#"{"owner":7,"size":4}"#, &ctx),
+///     Ok(Value::Bool(true))
+/// );
+/// ```
+#[derive(Debug, Default, Clone)]
+pub struct EvalContext<'a> {
+    variables: HashMap<String, Value>,
+    functions: Option<&'a FunctionRegistry>,
+    now: Option<DateTime<Utc>>,
+}
+
+impl<'a> EvalContext<'a> {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Makes `value` available as `$name`.
+    #[must_use]
+    pub fn with_variable(mut self, name: impl Into<String>, value: Value) -> Self {
+        self.variables.insert(name.into(), value);
+        self
+    }
+
+    /// Makes the functions of `functions` callable, built-in functions take precedence.
+    #[must_use]
+    pub fn with_functions(mut self, functions: &'a FunctionRegistry) -> Self {
+        self.functions = Some(functions);
+        self
+    }
+
+    /// Fixes the time returned by `now()`, which is the system clock otherwise.
+    #[must_use]
+    pub fn with_now(mut self, now: DateTime<Utc>) -> Self {
+        self.now = Some(now);
+        self
+    }
+
+    /// The time returned by `now()`.
+    #[must_use]
+    pub fn now(&self) -> DateTime<Utc> {
+        self.now.unwrap_or_else(Utc::now)
+    }
+
+    /// The value of `$name`.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if `$name` is not set, so that a misspelt variable does not quietly
+    /// match nothing.
+    pub fn variable(&self, name: &str) -> Result<Value> {
+        self.variables
+            .get(name)
+            .cloned()
+            .ok_or_else(|| Error::UnknownVariable(name.to_string()))
+    }
 }
 
 /// Is an alias for a Box<dyn Expression>
 pub type BoxedExpression = Box<dyn Expression>;
 
 /// Parses a supplied expression and returns a `BoxedExpression`.
+///
+/// Operators bind from loosest to tightest as follows, operators of the same level associate to the left:
+///
+/// | Precedence | Operators |
+/// |------------|-----------|
+/// | 0 | `? :`, which associates to the right |
+/// | 1 | `\|\|`, `OR` |
+/// | 2 | `&&`, `AND` |
+/// | 3 | prefix `!`, `NOT` |
+/// | 4 | `==`, `!=`, `>`, `>=`, `<`, `<=`, `CONTAINS`, `NOT CONTAINS`, `CONTAINS_ANY`, `CONTAINS_ALL`, `IN`, `NOT IN`, `BETWEEN`, `STARTS_WITH`, `ENDS_WITH`, `LIKE`, `ILIKE`, `MATCHES`, their `!` or `NOT` negations and the postfix `IS NULL`, `IS NOT NULL`, `IS MISSING`, `IS NOT MISSING` |
+/// | 5 | `+`, `-` |
+/// | 6 | `*`, `/`, `DIV`, `%` |
+/// | 7 | prefix `-` and `+` |
+/// | 8 | `**`, `^`, which associate to the right |
+/// | 9 | postfix `FILTER (<body>)` and `MAP (<body>)` |
+///
+/// So `.a + .b * 2` is `.a + (.b * 2)` and `.x == 1 || .y == 2 && .z == 3` is `.x == 1 || (.y == 2 && .z == 3)`.
+/// `-.a ** 2` is `-(.a ** 2)` and `2 ** 3 ** 2` is `2 ** (3 ** 2)`.
+///
+/// A `-` or `+` after an operand is a subtraction or an addition, so `.a -1` is `.a - 1`. Only
+/// between the bounds of `BETWEEN` is one written against its operand but apart from what
+/// precedes it a sign, so `.a BETWEEN -2 -1` has the bounds `-2` and `-1`.
+///
+/// `ANY(<array>, <body>)` and `ALL(<array>, <body>)` tell if `body` is `true` for any or every
+/// element of an array, `FILTER` keeps the elements it is `true` for and `MAP` replaces them by
+/// `body`. The selector paths of `body` read the element, `.` being the element itself, so
+/// `ANY(.items, .qty > 5)` or `.items FILTER (.price > 10) MAP (.price * .qty)`.
+///
+/// Array literals `[<value>, ...]` and object literals `{"<key>": <value>, ...}` take any
+/// expression as their values, so an expression can build a new document from the one it is
+/// calculated for, eg. `{"total": .price * .qty, "name": .name}`. The commas between the values of
+/// an array are optional, and the members of an object whose value is missing are left out.
+///
+/// `CASE WHEN <condition> THEN <value> ... ELSE <otherwise> END` and `<condition> ? <value> :
+/// <otherwise>` result in the value of the first condition that is `true`, `NULL` when none is
+/// and there is no `ELSE`. A missing condition is not `true`, and one that is not a boolean is an
+/// error. A `?` or `:` right after a selector path is read as part of the path,
+/// so `.a ? 1 : 2` is written with spaces.
+///
+/// Keywords such as `AND`, `IN` and `IS NOT NULL` as well as `true`, `false` and `NULL` are read
+/// in any case, so `.a in [1, 2] and .b is null` works too. A `#` outside of a selector path or
+/// string starts a comment that runs to the end of the line.
 pub struct Parser<'a> {
     exp: &'a [u8],
     tokenizer: Peekable<Tokenizer<'a>>,
 }
 
+/// Options for `Parser::parse_with_options` and `Parser::compile_with_options`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ParseOptions {
+    /// Evaluates a selector path that does not exist to `Value::Null` instead of `Value::Missing`,
+    /// the behaviour before `Missing` existed.
+    pub missing_as_null: bool,
+}
+
+/// Result of parsing an expression.
+type ParseResult<T> = std::result::Result<T, ExprError>;
+
+const VALUE: &str = "a value";
+const OPERATOR: &str = "an operator";
+const OPEN_PAREN: &str = "`(`";
+const CLOSE_PAREN: &str = "`)`";
+const COMMA: &str = "`,`";
+const SELECTOR_PATH: &str = "a selector path";
+const QUOTED_STRING: &str = "a quoted string";
+const COMPARISON: &str = "a comparison operator";
+const DATA_TYPE: &str = "a data type such as `_string_`";
+
+/// Left and right binding power of the `?` of `<condition> ? <value> : <otherwise>`, so its
+/// condition is a whole `||` and a chain of them associates to the right.
+const TERNARY_BINDING_POWER: u8 = 1;
+
+/// Binding power of prefix `!`, it negates a whole comparison.
+const NOT_BINDING_POWER: u8 = 5;
+
+/// Left binding power of comparison operators.
+const COMPARISON_BINDING_POWER: u8 = 7;
+
+/// Binding power of prefix `-` and `+`, between multiplication and power.
+const SIGN_BINDING_POWER: u8 = 13;
+
+/// Left and right binding power of an infix operator, `None` if the token is not one.
+fn infix_binding_power(kind: &TokenKind) -> Option<(u8, u8)> {
+    match kind {
+        TokenKind::Or => Some((1, 2)),
+        TokenKind::And => Some((3, 4)),
+        TokenKind::Equals
+        | TokenKind::NotEquals
+        | TokenKind::Gt
+        | TokenKind::Gte
+        | TokenKind::Lt
+        | TokenKind::Lte
+        | TokenKind::Contains
+        | TokenKind::NotContains
+        | TokenKind::ContainsAny
+        | TokenKind::ContainsAll
+        | TokenKind::In
+        | TokenKind::NotIn
+        | TokenKind::IsNull
+        | TokenKind::IsNotNull
+        | TokenKind::IsMissing
+        | TokenKind::IsNotMissing
+        | TokenKind::Between
+        | TokenKind::StartsWith
+        | TokenKind::EndsWith
+        | TokenKind::Like
+        | TokenKind::ILike
+        | TokenKind::Matches
+        | TokenKind::Not => Some((COMPARISON_BINDING_POWER, COMPARISON_BINDING_POWER + 1)),
+        TokenKind::Add | TokenKind::Subtract => Some((9, 10)),
+        TokenKind::Multiply | TokenKind::Divide | TokenKind::IntDivide | TokenKind::Modulo => {
+            Some((11, 12))
+        }
+        TokenKind::Power => Some((15, 14)),
+        TokenKind::Filter | TokenKind::Map => Some((17, 18)),
+        TokenKind::Question => Some((TERNARY_BINDING_POWER, TERNARY_BINDING_POWER)),
+        _ => None,
+    }
+}
+
 impl<'a> Parser<'a> {
     fn new(exp: &'a [u8], tokenizer: Peekable<Tokenizer<'a>>) -> Self {
         Parser { exp, tokenizer }
@@ -106,160 +394,375 @@ impl<'a> Parser<'a> {
     /// # Errors
     ///
     /// Will return `Err` the expression is invalid.
+    #[inline]
     pub fn parse_bytes(expression: &[u8]) -> anyhow::Result<BoxedExpression> {
+        Parser::parse_bytes_with_options(expression, ParseOptions::default())
+    }
+
+    /// parses the provided expression like `parse` with the supplied `ParseOptions`.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` the expression is invalid.
+    #[inline]
+    pub fn parse_with_options(
+        expression: &str,
+        options: ParseOptions,
+    ) -> anyhow::Result<BoxedExpression> {
+        Parser::parse_bytes_with_options(expression.as_bytes(), options)
+    }
+
+    /// parses the provided expression as bytes like `parse_bytes` with the supplied `ParseOptions`.
+    ///
+    /// The expression is simplified by `optimizer::optimize` before it is compiled into a
+    /// `vm::Program`.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` the expression is invalid.
+    pub fn parse_bytes_with_options(
+        expression: &[u8],
+        options: ParseOptions,
+    ) -> anyhow::Result<BoxedExpression> {
+        let optimized = optimizer::optimize(Parser::parse_expr_bytes(expression)?);
+        Ok(Box::new(Program::compile(optimized.expr, options)?))
+    }
+
+    /// compiles an `Expr`, eg. one returned by `parse_expr`, into a tree of computations that can
+    /// be applied to some source data, each node calculating its operands. `vm::Program::compile`
+    /// compiles it into faster bytecode instead.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the `Expr` is invalid, such as an unknown time zone or a built-in
+    /// function called with the wrong number of arguments.
+    #[inline]
+    pub fn compile(expr: Expr) -> anyhow::Result<BoxedExpression> {
+        Parser::compile_with_options(expr, ParseOptions::default())
+    }
+
+    /// compiles an `Expr` like `compile` with the supplied `ParseOptions`.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the `Expr` is invalid.
+    pub fn compile_with_options(expr: Expr, options: ParseOptions) -> anyhow::Result<BoxedExpression> {
+        compile(expr, options)
+    }
+
+    /// parses the provided expression into its `Expr` tree, to inspect or rewrite it before it is compiled.
+    ///
+    /// # Errors
+    ///
+    /// Will return an `ExprError` with the part of the expression that is invalid, which
+    /// `ExprError::render` underlines.
+    ///
+    /// ```
+    /// use flql::exp_parser::Parser;
+    ///
+    /// let error = Parser::parse_expr(".age >= 18 && (.name == 'Tom'").unwrap_err();
+    /// assert_eq!(error.span, 29..29);
+    /// assert_eq!(error.expected, vec!["`)`"]);
+    /// ```
+    #[inline]
+    pub fn parse_expr(expression: &str) -> ParseResult<Expr> {
+        Parser::parse_expr_bytes(expression.as_bytes())
+    }
+
+    /// parses the provided expression as bytes into its `Expr` tree.
+    ///
+    /// # Errors
+    ///
+    /// Will return an `ExprError` with the part of the expression that is invalid.
+    pub fn parse_expr_bytes(expression: &[u8]) -> ParseResult<Expr> {
         let tokenizer = Tokenizer::new_bytes(expression).peekable();
         let mut parser = Parser::new(expression, tokenizer);
-        let result = parser.parse_expression()?;
+        let result = parser.parse_expression(0)?;
 
-        if let Some(result) = result {
-            Ok(result)
-        } else {
-            Err(anyhow!("no expression results found"))
+        match parser.next_token()? {
+            Some(token) => Err(parser.unexpected(&token, &[OPERATOR])),
+            None => Ok(result),
         }
     }
 
-    #[allow(clippy::too_many_lines)]
-    fn parse_expression(&mut self) -> anyhow::Result<Option<BoxedExpression>> {
-        let mut current: Option<BoxedExpression> = None;
+    /// Parses operators binding tighter than `min_binding_power`, see `Parser` for the precedence.
+    fn parse_expression(&mut self, min_binding_power: u8) -> ParseResult<Expr> {
+        let (current, bare_path) = self.parse_prefix()?;
+        self.parse_infix(current, bare_path, min_binding_power, false)
+    }
+
+    /// Parses a value or a prefix `!` or `NOT` and its operand, along with whether it is a
+    /// selector path without parentheses.
+    fn parse_prefix(&mut self) -> ParseResult<(Expr, bool)> {
+        let Some(token) = self.next_token()? else {
+            return Err(self.unexpected_end(&[VALUE]));
+        };
+        // `IS MISSING` looks the path up itself, so it only applies directly to a selector path
+        let bare_path = token.kind == TokenKind::SelectorPath;
+        let current = if token.kind == TokenKind::Not {
+            let expr = self.parse_expression(NOT_BINDING_POWER)?;
+            Expr::Unary { op: UnaryOp::Not, expr: Box::new(expr) }
+        } else {
+            self.parse_value(token)?
+        };
+        Ok((current, bare_path))
+    }
 
+    /// Parses the infix and postfix operators binding tighter than `min_binding_power` that
+    /// follow `current`, `bare_path` tells if `current` is a selector path without parentheses.
+    /// With `signs` a `-` or `+` that `is_sign` ends `current` instead, as between `BETWEEN` bounds.
+    fn parse_infix(
+        &mut self,
+        mut current: Expr,
+        mut bare_path: bool,
+        min_binding_power: u8,
+        signs: bool,
+    ) -> ParseResult<Expr> {
         loop {
-            if let Some(token) = self.tokenizer.next() {
-                let token = token?;
-                if let Some(expression) = current {
-                    // CloseParen is the end of an expression block, return parsed expression.
-                    if token.kind == TokenKind::CloseParen {
-                        return Ok(Some(expression));
-                    }
-                    // look for next operation
-                    current = self.parse_operation(token, expression)?;
-                } else {
-                    // look for next value
-                    current = Some(self.parse_value(token)?);
-                }
-            } else {
-                return Ok(current);
+            let token = match self.tokenizer.peek() {
+                None => break,
+                Some(Ok(token)) => token.clone(),
+                Some(Err(e)) => return Err(e.clone()),
+            };
+            let Some((left_binding_power, right_binding_power)) = infix_binding_power(&token.kind) else {
+                break;
+            };
+            if left_binding_power < min_binding_power || (signs && self.is_sign(&token)) {
+                break;
             }
+            let _ = self.tokenizer.next(); // consume peeked operator
+            current = match (&token.kind, current) {
+                (TokenKind::IsMissing, Expr::Path(path)) if bare_path => Expr::Missing(path),
+                (TokenKind::IsNotMissing, Expr::Path(path)) if bare_path => Expr::Exists(path),
+                (TokenKind::IsMissing | TokenKind::IsNotMissing, _) => {
+                    return Err(invalid(
+                        token.span(),
+                        format!("{} must follow a selector path", self.token_str(&token)),
+                    ));
+                }
+                (_, current) => self.parse_operation(token, current, right_binding_power)?,
+            };
+            bare_path = false;
         }
+        Ok(current)
     }
 
     #[allow(clippy::too_many_lines)]
-    fn parse_value(&mut self, token: Token) -> anyhow::Result<BoxedExpression> {
+    fn parse_value(&mut self, token: Token) -> ParseResult<Expr> {
         match token.kind {
             TokenKind::OpenBracket => {
                 let mut arr = Vec::new();
 
                 loop {
-                    if let Some(token) = self.tokenizer.next() {
-                        let token = token?;
-
-                        match token.kind {
-                            TokenKind::CloseBracket => {
-                                break;
-                            }
-                            TokenKind::Comma => continue, // optional for defining arrays
-                            _ => {
-                                arr.push(self.parse_value(token)?);
-                            }
-                        };
-                    } else {
-                        return Err(anyhow!("unclosed Array '['"));
+                    match self.tokenizer.peek() {
+                        Some(Ok(Token { kind: TokenKind::CloseBracket, .. })) => {
+                            let _ = self.tokenizer.next(); // consume peeked bracket
+                            break;
+                        }
+                        // optional for defining arrays
+                        Some(Ok(Token { kind: TokenKind::Comma, .. })) => {
+                            let _ = self.tokenizer.next(); // consume peeked comma
+                        }
+                        Some(_) => arr.push(self.parse_expression(0)?),
+                        None => return Err(self.unexpected_end(&["`]`", VALUE])),
+                    }
+                }
+                Ok(Expr::Array(arr))
+            }
+            TokenKind::OpenBrace => {
+                // {"<key>": <expression>, ...}
+                let mut members: Vec<(String, Expr)> = Vec::new();
+                loop {
+                    let key = match self.next_token()? {
+                        Some(token) if token.kind == TokenKind::CloseBrace => break,
+                        Some(token) if token.kind == TokenKind::QuotedString => token,
+                        Some(token) => return Err(self.unexpected(&token, &[QUOTED_STRING, "`}`"])),
+                        None => return Err(self.unexpected_end(&[QUOTED_STRING, "`}`"])),
+                    };
+                    let name = self.quoted(&key);
+                    if members.iter().any(|(k, _)| *k == name) {
+                        return Err(invalid(key.span(), format!("duplicate key `{name}`")));
+                    }
+                    self.expect(&TokenKind::Colon, "`:`")?;
+                    members.push((name, self.parse_expression(0)?));
+                    match self.next_token()? {
+                        Some(token) if token.kind == TokenKind::Comma => continue,
+                        Some(token) if token.kind == TokenKind::CloseBrace => break,
+                        Some(token) => return Err(self.unexpected(&token, &[COMMA, "`}`"])),
+                        None => return Err(self.unexpected_end(&[COMMA, "`}`"])),
                     }
                 }
-                Ok(Box::new(Arr { arr }))
+                Ok(Expr::Object(members))
             }
             TokenKind::OpenParen => {
-                if let Some(expression) = self.parse_expression()? {
-                    Ok(expression)
-                } else {
-                    Err(anyhow!(
-                        "expression after open parenthesis '(' ends unexpectedly."
-                    ))
+                let expression = self.parse_expression(0)?;
+                match self.next_token()? {
+                    Some(token) if token.kind == TokenKind::CloseParen => Ok(expression),
+                    Some(token) => Err(self.unexpected(&token, &[CLOSE_PAREN])),
+                    None => Err(self.unexpected_end(&[CLOSE_PAREN])),
                 }
             }
-            TokenKind::SelectorPath => {
-                let start = token.start as usize;
-                Ok(Box::new(SelectorPath {
-                    ident: String::from_utf8_lossy(
-                        &self.exp[start + 1..(start + token.len as usize)],
-                    )
-                        .into_owned(),
-                }))
+            TokenKind::SelectorPath => Ok(Expr::Path(self.selector_path(&token))),
+            TokenKind::Identifier => {
+                // <name>(<expression>, ...)
+                let name = self.token_str(&token);
+                self.expect(&TokenKind::OpenParen, OPEN_PAREN)?;
+                let mut args = Vec::new();
+                let end = match self.tokenizer.peek() {
+                    Some(Ok(close)) if close.kind == TokenKind::CloseParen => {
+                        let end = close.span().end;
+                        let _ = self.tokenizer.next(); // consume peeked parenthesis
+                        end
+                    }
+                    _ => loop {
+                        args.push(self.parse_expression(0)?);
+                        match self.next_token()? {
+                            Some(token) if token.kind == TokenKind::Comma => continue,
+                            Some(token) if token.kind == TokenKind::CloseParen => break token.span().end,
+                            Some(token) => return Err(self.unexpected(&token, &[COMMA, CLOSE_PAREN])),
+                            None => return Err(self.unexpected_end(&[COMMA, CLOSE_PAREN])),
+                        }
+                    },
+                };
+                check_arity(&name, args.len()).map_err(|e| invalid(token.span().start..end, e))?;
+                Ok(Expr::Call { name, args })
+            }
+            TokenKind::Variable => Ok(Expr::Variable(self.selector_path(&token))),
+            TokenKind::Case => {
+                // CASE WHEN <condition> THEN <value> ... [ELSE <otherwise>] END
+                let mut branches = Vec::new();
+                let mut otherwise = None;
+                self.expect(&TokenKind::When, "`WHEN`")?;
+                loop {
+                    let condition = self.parse_expression(0)?;
+                    self.expect(&TokenKind::Then, "`THEN`")?;
+                    branches.push((condition, self.parse_expression(0)?));
+                    match self.next_token()? {
+                        Some(token) if token.kind == TokenKind::When => continue,
+                        Some(token) if token.kind == TokenKind::End => break,
+                        Some(token) if token.kind == TokenKind::Else => {
+                            otherwise = Some(Box::new(self.parse_expression(0)?));
+                            self.expect(&TokenKind::End, "`END`")?;
+                            break;
+                        }
+                        Some(token) => return Err(self.unexpected(&token, &["`WHEN`", "`ELSE`", "`END`"])),
+                        None => return Err(self.unexpected_end(&["`WHEN`", "`ELSE`", "`END`"])),
+                    }
+                }
+                Ok(Expr::Case { branches, otherwise })
             }
-            TokenKind::QuotedString => {
-                let start = token.start as usize;
-                Ok(Box::new(Str {
-                    s: String::from_utf8_lossy(
-                        &self.exp[start + 1..(start + token.len as usize - 1)],
-                    )
-                        .into_owned(),
-                }))
+            TokenKind::Any | TokenKind::All => {
+                // ANY(<array>, <body>) or ALL(<array>, <body>)
+                self.expect(&TokenKind::OpenParen, OPEN_PAREN)?;
+                let array = self.parse_expression(0)?;
+                self.expect(&TokenKind::Comma, COMMA)?;
+                let body = self.parse_expression(0)?;
+                self.expect(&TokenKind::CloseParen, CLOSE_PAREN)?;
+                Ok(Expr::Iterate {
+                    op: if token.kind == TokenKind::Any { IterateOp::Any } else { IterateOp::All },
+                    array: Box::new(array),
+                    body: Box::new(body),
+                })
             }
-            TokenKind::Number => {
-                let start = token.start as usize;
-                Ok(Box::new(Num {
-                    n: String::from_utf8_lossy(&self.exp[start..start + token.len as usize])
-                        .parse()?,
-                }))
+            TokenKind::Exists => {
+                // EXISTS <selector path>
+                let next_token = self.next_operator_token(&[SELECTOR_PATH])?;
+                if next_token.kind != TokenKind::SelectorPath {
+                    return Err(self.unexpected(&next_token, &[SELECTOR_PATH]));
+                }
+                Ok(Expr::Exists(self.selector_path(&next_token)))
             }
-            TokenKind::BooleanTrue => Ok(Box::new(Bool { b: true })),
-            TokenKind::BooleanFalse => Ok(Box::new(Bool { b: false })),
-            TokenKind::Null => Ok(Box::new(Null {})),
+            TokenKind::QuotedString => Ok(Expr::Literal(Value::String(self.quoted(&token)))),
+            TokenKind::Number => Ok(Expr::Literal(
+                number_literal(&self.token_str(&token)).map_err(|e| invalid(token.span(), e))?,
+            )),
+            TokenKind::Duration => {
+                let d = parse_duration(&self.token_str(&token))
+                    .map_err(|e| anyhow!(e))
+                    .and_then(|d| Ok(chrono::Duration::from_std(d)?))
+                    .map_err(|e| invalid(token.span(), e))?;
+                Ok(Expr::Literal(Value::Duration(d)))
+            }
+            TokenKind::Subtract | TokenKind::Add => {
+                // -<expression> or +<expression>
+                let negative = token.kind == TokenKind::Subtract;
+                let literal = match self.tokenizer.peek() {
+                    Some(Ok(next)) if matches!(next.kind, TokenKind::Number | TokenKind::Duration) => {
+                        Some(next.clone())
+                    }
+                    _ => None,
+                };
+                let value = match literal {
+                    Some(literal) => {
+                        let _ = self.tokenizer.next(); // consume peeked literal
+                        let value = self.parse_value(literal.clone())?;
+                        if matches!(self.tokenizer.peek(), Some(Ok(Token { kind: TokenKind::Power, .. }))) {
+                            self.parse_infix(value, false, SIGN_BINDING_POWER, false)?
+                        } else if !negative {
+                            return Ok(value);
+                        } else if literal.kind == TokenKind::Number {
+                            // a signed literal, so that `-9223372036854775808` is an exact `Int`
+                            return Ok(Expr::Literal(
+                                number_literal(&format!("-{}", self.token_str(&literal)))
+                                    .map_err(|e| invalid(token.span().start..literal.span().end, e))?,
+                            ));
+                        } else {
+                            match value {
+                                Expr::Literal(Value::Duration(d)) => return Ok(Expr::Literal(Value::Duration(-d))),
+                                _ => unreachable!("duration literal"),
+                            }
+                        }
+                    }
+                    None => self.parse_expression(SIGN_BINDING_POWER)?,
+                };
+                Ok(Expr::Unary {
+                    op: if negative { UnaryOp::Neg } else { UnaryOp::Pos },
+                    expr: Box::new(value),
+                })
+            }
+            TokenKind::BooleanTrue => Ok(Expr::Literal(Value::Bool(true))),
+            TokenKind::BooleanFalse => Ok(Expr::Literal(Value::Bool(false))),
+            TokenKind::Null => Ok(Expr::Literal(Value::Null)),
             TokenKind::Coerce => {
                 // COERCE <expression> _<datatype>_
-                let next_token = self.next_operator_token(token)?;
-                let const_eligible = matches!(
-                    next_token.kind,
-                    TokenKind::QuotedString
-                        | TokenKind::Number
-                        | TokenKind::BooleanFalse
-                        | TokenKind::BooleanTrue
-                        | TokenKind::Null
-                );
+                let next_token = self.next_operator_token(&[VALUE])?;
                 let mut expression = self.parse_value(next_token)?;
                 loop {
-                    if let Some(token) = self.tokenizer.next() {
-                        let token = token?;
-                        let start = token.start as usize;
+                    if let Some(token) = self.next_token()? {
+                        let start = token.start;
 
                         if token.kind == TokenKind::Identifier {
                             let ident = String::from_utf8_lossy(
-                                &self.exp[start..start + token.len as usize],
+                                &self.exp[start..start + token.len],
                             );
-                            match ident.as_ref() {
+                            let to = match ident.as_ref() {
                                 "_datetime_" => {
-                                    let value = COERCEDateTime { value: expression };
-                                    if const_eligible {
-                                        expression = Box::new(CoercedConst {
-                                            value: value.calculate(&[])?,
-                                        });
-                                    } else {
-                                        expression = Box::new(value);
-                                    }
-                                }
-                                "_string_" => {
-                                    expression = Box::new(CoerceToString { value: expression });
-                                }
-                                "_number_" => {
-                                    expression = Box::new(CoerceToNumber { value: expression });
-                                }
-                                "_lowercase_" => {
-                                    expression = Box::new(CoerceLowercase { value: expression });
-                                }
-                                "_uppercase_" => {
-                                    expression = Box::new(CoerceUppercase { value: expression });
+                                    let (format, zone) = self.datetime_args()?;
+                                    Coercion::DateTime { format, zone }
                                 }
+                                "_string_" => Coercion::String,
+                                "_number_" => Coercion::Number,
+                                #[cfg(feature = "decimal")]
+                                "_decimal_" => Coercion::Decimal,
+                                "_lowercase_" => Coercion::Lowercase,
+                                "_uppercase_" => Coercion::Uppercase,
                                 _ => {
-                                    return Err(anyhow!("invalid COERCE data type '{:?}'", &ident))
+                                    return Err(invalid(
+                                        token.span(),
+                                        format!("invalid COERCE data type `{ident}`"),
+                                    )
+                                    .with_expected(&[DATA_TYPE]))
                                 }
                             };
+                            expression = Expr::Coerce {
+                                expr: Box::new(expression),
+                                to,
+                            };
                         } else {
-                            return Err(anyhow!(
-                                "COERCE missing data type identifier, found instead: {:?}",
-                                &self.exp[start..(start + token.len as usize)]
-                            ));
+                            return Err(self.unexpected(&token, &[DATA_TYPE]));
                         }
                     } else {
-                        return Err(anyhow!("no identifier after value for: COERCE"));
+                        return Err(self.unexpected_end(&[DATA_TYPE]));
                     }
                     if let Some(Ok(token)) = self.tokenizer.peek() {
                         if token.kind == TokenKind::Comma {
@@ -272,196 +775,366 @@ impl<'a> Parser<'a> {
                 Ok(expression)
             }
             TokenKind::Not => {
-                let next_token = self.next_operator_token(token)?;
-                let value = self.parse_value(next_token)?;
-                Ok(Box::new(Not { value }))
+                let next_token = self.next_operator_token(&[VALUE])?;
+                let expr = self.parse_value(next_token)?;
+                Ok(Expr::Unary { op: UnaryOp::Not, expr: Box::new(expr) })
             }
-            _ => Err(anyhow!("token is not a valid value: {:?}", token)),
+            _ => Err(self.unexpected(&token, &[VALUE])),
         }
     }
 
-    #[allow(clippy::too_many_lines, clippy::needless_pass_by_value)]
-    fn next_operator_token(&mut self, operation_token: Token) -> anyhow::Result<Token> {
-        if let Some(token) = self.tokenizer.next() {
-            Ok(token?)
-        } else {
-            let start = operation_token.start as usize;
-            Err(anyhow!(
-                "no value found after operation: {:?}",
-                &self.exp[start..(start + operation_token.len as usize)]
-            ))
+    /// The next token, `None` at the end of the expression.
+    fn next_token(&mut self) -> ParseResult<Option<Token>> {
+        self.tokenizer.next().transpose()
+    }
+
+    /// The next token, which must be of `kind`.
+    fn expect(&mut self, kind: &TokenKind, expected: &'static str) -> ParseResult<Token> {
+        match self.next_token()? {
+            Some(token) if token.kind == *kind => Ok(token),
+            Some(token) => Err(self.unexpected(&token, &[expected])),
+            None => Err(self.unexpected_end(&[expected])),
+        }
+    }
+
+    /// The token following an operator, which takes one of `expected`.
+    fn next_operator_token(&mut self, expected: &[&'static str]) -> ParseResult<Token> {
+        match self.next_token()? {
+            Some(token) => Ok(token),
+            None => Err(self.unexpected_end(expected)),
+        }
+    }
+
+    /// Error for `token` found where the parser expected one of `expected`.
+    fn unexpected(&self, token: &Token, expected: &[&'static str]) -> ExprError {
+        ExprError::new(ExprErrorKind::UnexpectedToken(self.token_str(token)), token.span())
+            .with_expected(expected)
+    }
+
+    /// Error for the expression ending where the parser expected one of `expected`.
+    fn unexpected_end(&self, expected: &[&'static str]) -> ExprError {
+        ExprError::new(ExprErrorKind::UnexpectedEnd, self.exp.len()..self.exp.len())
+            .with_expected(expected)
+    }
+
+    /// Parses the optional `('<format>', '<zone>')` arguments of `COERCE _datetime_`.
+    /// Either can be left out, the format is told apart by its `%`.
+    fn datetime_args(&mut self) -> ParseResult<(Option<String>, Option<String>)> {
+        let mut format = None;
+        let mut zone = None;
+        if !matches!(self.tokenizer.peek(), Some(Ok(Token { kind: TokenKind::OpenParen, .. }))) {
+            return Ok((format, zone));
+        }
+        let _ = self.tokenizer.next(); // consume peeked parenthesis
+        loop {
+            let Some(token) = self.next_token()? else {
+                return Err(self.unexpected_end(&[QUOTED_STRING, CLOSE_PAREN]));
+            };
+            match token.kind {
+                TokenKind::CloseParen => break,
+                TokenKind::Comma => continue,
+                TokenKind::QuotedString => {
+                    let arg = self.token_str(&token);
+                    let arg = &arg[1..arg.len() - 1];
+                    let duplicate = if arg.contains('%') {
+                        format.replace(arg.to_string()).is_some()
+                    } else {
+                        Zone::parse(arg)
+                            .ok_or_else(|| invalid(token.span(), format!("unknown time zone: {arg}")))?;
+                        zone.replace(arg.to_string()).is_some()
+                    };
+                    if duplicate {
+                        return Err(invalid(
+                            token.span(),
+                            "_datetime_ takes at most one format and one zone",
+                        ));
+                    }
+                }
+                _ => return Err(self.unexpected(&token, &[QUOTED_STRING, CLOSE_PAREN])),
+            }
         }
+        Ok((format, zone))
     }
 
+    /// Parses the right hand side of the infix operator `token` and combines it with `current`.
     #[allow(clippy::too_many_lines)]
     fn parse_operation(
         &mut self,
         token: Token,
-        current: BoxedExpression,
-    ) -> anyhow::Result<Option<BoxedExpression>> {
-        match token.kind {
-            TokenKind::Add => {
-                let next_token = self.next_operator_token(token)?;
-                let right = self.parse_value(next_token)?;
-                Ok(Some(Box::new(Add {
-                    left: current,
-                    right,
-                })))
-            }
-            TokenKind::Subtract => {
-                let next_token = self.next_operator_token(token)?;
-                let right = self.parse_value(next_token)?;
-                Ok(Some(Box::new(Sub {
-                    left: current,
-                    right,
-                })))
-            }
-            TokenKind::Multiply => {
-                let next_token = self.next_operator_token(token)?;
-                let right = self.parse_value(next_token)?;
-                Ok(Some(Box::new(Mult {
-                    left: current,
-                    right,
-                })))
-            }
-            TokenKind::Divide => {
-                let next_token = self.next_operator_token(token)?;
-                let right = self.parse_value(next_token)?;
-                Ok(Some(Box::new(Div {
-                    left: current,
-                    right,
-                })))
-            }
-            TokenKind::Equals => {
-                let next_token = self.next_operator_token(token)?;
-                let right = self.parse_value(next_token)?;
-                Ok(Some(Box::new(Eq {
-                    left: current,
-                    right,
-                })))
-            }
-            TokenKind::Gt => {
-                let next_token = self.next_operator_token(token)?;
-                let right = self.parse_value(next_token)?;
-                Ok(Some(Box::new(Gt {
-                    left: current,
-                    right,
-                })))
-            }
-            TokenKind::Gte => {
-                let next_token = self.next_operator_token(token)?;
-                let right = self.parse_value(next_token)?;
-                Ok(Some(Box::new(Gte {
-                    left: current,
-                    right,
-                })))
-            }
-            TokenKind::Lt => {
-                let next_token = self.next_operator_token(token)?;
-                let right = self.parse_value(next_token)?;
-                Ok(Some(Box::new(Lt {
-                    left: current,
-                    right,
-                })))
-            }
-            TokenKind::Lte => {
-                let next_token = self.next_operator_token(token)?;
-                let right = self.parse_value(next_token)?;
-                Ok(Some(Box::new(Lte {
-                    left: current,
-                    right,
-                })))
-            }
-            TokenKind::Or => {
-                let right = self
-                    .parse_expression()?
-                    .map_or_else(|| Err(anyhow!("invalid operation after ||")), Ok)?;
-                Ok(Some(Box::new(Or {
-                    left: current,
-                    right,
-                })))
-            }
-            TokenKind::And => {
-                let right = self
-                    .parse_expression()?
-                    .map_or_else(|| Err(anyhow!("invalid operation after &&")), Ok)?;
-                Ok(Some(Box::new(And {
-                    left: current,
-                    right,
-                })))
-            }
-            TokenKind::StartsWith => {
-                let next_token = self.next_operator_token(token)?;
-                let right = self.parse_value(next_token)?;
-                Ok(Some(Box::new(StartsWith {
-                    left: current,
-                    right,
-                })))
-            }
-            TokenKind::EndsWith => {
-                let next_token = self.next_operator_token(token)?;
-                let right = self.parse_value(next_token)?;
-                Ok(Some(Box::new(EndsWith {
-                    left: current,
-                    right,
-                })))
-            }
-            TokenKind::In => {
-                let next_token = self.next_operator_token(token)?;
-                let right = self.parse_value(next_token)?;
-                Ok(Some(Box::new(In {
-                    left: current,
-                    right,
-                })))
-            }
-            TokenKind::Contains => {
-                let next_token = self.next_operator_token(token)?;
-                let right = self.parse_value(next_token)?;
-                Ok(Some(Box::new(Contains {
-                    left: current,
-                    right,
-                })))
-            }
-            TokenKind::ContainsAny => {
-                let next_token = self.next_operator_token(token)?;
-                let right = self.parse_value(next_token)?;
-                Ok(Some(Box::new(ContainsAny {
-                    left: current,
-                    right,
-                })))
+        current: Expr,
+        binding_power: u8,
+    ) -> ParseResult<Expr> {
+        if token.kind == TokenKind::Between {
+            let (low, bare_path) = self.parse_prefix()?;
+            let low = self.parse_infix(low, bare_path, binding_power, true)?;
+            let high = self.parse_expression(binding_power)?;
+            return Ok(Expr::Between {
+                expr: Box::new(current),
+                low: Box::new(low),
+                high: Box::new(high),
+            });
+        }
+        if token.kind == TokenKind::Not {
+            // negated operator, eg. `.a !CONTAINS "b"`
+            let operator = self.next_operator_token(&[COMPARISON])?;
+            return match infix_binding_power(&operator.kind) {
+                Some((COMPARISON_BINDING_POWER, binding_power)) if operator.kind != TokenKind::Not => {
+                    let expr = self.parse_operation(operator, current, binding_power)?;
+                    Ok(Expr::Unary { op: UnaryOp::Not, expr: Box::new(expr) })
+                }
+                _ => Err(self.unexpected(&operator, &[COMPARISON])),
+            };
+        }
+
+        if token.kind == TokenKind::Question {
+            // <condition> ? <value> : <otherwise>
+            let value = self.parse_expression(0)?;
+            self.expect(&TokenKind::Colon, "`:`")?;
+            let otherwise = self.parse_expression(binding_power)?;
+            return Ok(Expr::Case {
+                branches: vec![(current, value)],
+                otherwise: Some(Box::new(otherwise)),
+            });
+        }
+
+        if matches!(token.kind, TokenKind::Filter | TokenKind::Map) {
+            // <array> FILTER (<body>) or <array> MAP (<body>)
+            self.expect(&TokenKind::OpenParen, OPEN_PAREN)?;
+            let body = self.parse_expression(0)?;
+            self.expect(&TokenKind::CloseParen, CLOSE_PAREN)?;
+            return Ok(Expr::Iterate {
+                op: if token.kind == TokenKind::Filter { IterateOp::Filter } else { IterateOp::Map },
+                array: Box::new(current),
+                body: Box::new(body),
+            });
+        }
+
+        if token.kind == TokenKind::Matches {
+            let pattern_token = self.next_operator_token(&[QUOTED_STRING])?;
+            if pattern_token.kind != TokenKind::QuotedString {
+                return Err(self.unexpected(&pattern_token, &[QUOTED_STRING]));
             }
-            TokenKind::ContainsAll => {
-                let next_token = self.next_operator_token(token)?;
-                let right = self.parse_value(next_token)?;
-                Ok(Some(Box::new(ContainsAll {
-                    left: current,
-                    right,
-                })))
+            let start = pattern_token.start;
+            let pattern = String::from_utf8_lossy(&self.exp[start + 1..start + pattern_token.len - 1])
+                .into_owned();
+            compile_regex(&pattern).map_err(|e| invalid(pattern_token.span(), e))?;
+            return Ok(Expr::Matches {
+                expr: Box::new(current),
+                pattern,
+            });
+        }
+        let op = match token.kind {
+            TokenKind::IsNull => Some(UnaryOp::IsNull),
+            TokenKind::IsNotNull => Some(UnaryOp::IsNotNull),
+            _ => None,
+        };
+        if let Some(op) = op {
+            return Ok(Expr::Unary { op, expr: Box::new(current) });
+        }
+
+        let op = match token.kind {
+            TokenKind::Add => BinaryOp::Add,
+            TokenKind::Subtract => BinaryOp::Sub,
+            TokenKind::Multiply => BinaryOp::Mul,
+            TokenKind::Divide => BinaryOp::Div,
+            TokenKind::IntDivide => BinaryOp::IntDiv,
+            TokenKind::Modulo => BinaryOp::Rem,
+            TokenKind::Power => BinaryOp::Pow,
+            TokenKind::Equals => BinaryOp::Eq,
+            TokenKind::NotEquals => BinaryOp::NotEq,
+            TokenKind::Gt => BinaryOp::Gt,
+            TokenKind::Gte => BinaryOp::Gte,
+            TokenKind::Lt => BinaryOp::Lt,
+            TokenKind::Lte => BinaryOp::Lte,
+            TokenKind::Or => BinaryOp::Or,
+            TokenKind::And => BinaryOp::And,
+            TokenKind::StartsWith => BinaryOp::StartsWith,
+            TokenKind::EndsWith => BinaryOp::EndsWith,
+            TokenKind::Like => BinaryOp::Like,
+            TokenKind::ILike => BinaryOp::ILike,
+            TokenKind::In => BinaryOp::In,
+            TokenKind::NotIn => BinaryOp::NotIn,
+            TokenKind::Contains => BinaryOp::Contains,
+            TokenKind::NotContains => BinaryOp::NotContains,
+            TokenKind::ContainsAny => BinaryOp::ContainsAny,
+            TokenKind::ContainsAll => BinaryOp::ContainsAll,
+            _ => return Err(self.unexpected(&token, &[OPERATOR])),
+        };
+        let right = self.parse_expression(binding_power)?;
+        Ok(Expr::Binary {
+            op,
+            left: Box::new(current),
+            right: Box::new(right),
+        })
+    }
+
+    /// The gjson path of a `SelectorPath` token or the name of a `Variable`, without the leading `.` or `$`.
+    fn selector_path(&self, token: &Token) -> String {
+        let start = token.start;
+        String::from_utf8_lossy(&self.exp[start + 1..start + token.len]).into_owned()
+    }
+
+    /// Whether a `-` or `+` after the low bound of `BETWEEN` is the sign of the high one, because it is
+    /// apart from what precedes it but written against what follows, eg. `BETWEEN -13 -12`.
+    fn is_sign(&self, token: &Token) -> bool {
+        let start = token.start;
+        matches!(token.kind, TokenKind::Subtract | TokenKind::Add)
+            && start > 0
+            && self.exp[start - 1].is_ascii_whitespace()
+            && self.exp.get(start + 1).is_some_and(|c| !c.is_ascii_whitespace())
+    }
+
+    /// The text of a `QuotedString` token, without its quotes.
+    fn quoted(&self, token: &Token) -> String {
+        String::from_utf8_lossy(&self.exp[token.start + 1..token.start + token.len - 1]).into_owned()
+    }
+
+    fn token_str(&self, token: &Token) -> String {
+        let start = token.start;
+        String::from_utf8_lossy(&self.exp[start..start + token.len]).into_owned()
+    }
+}
+
+/// Error for the `span` of a token that is well placed but invalid itself, eg. an unknown time zone.
+fn invalid(span: Range<usize>, message: impl Display) -> ExprError {
+    ExprError::new(ExprErrorKind::Invalid(message.to_string()), span)
+}
+
+/// Builds the `Expression` nodes of an `Expr`.
+#[allow(clippy::too_many_lines)]
+fn compile(expr: Expr, options: ParseOptions) -> anyhow::Result<BoxedExpression> {
+    let compile_box = |expr: Box<Expr>| compile(*expr, options);
+    Ok(match expr {
+        Expr::Literal(value) => match value {
+            Value::String(s) => Box::new(Str { s }),
+            Value::Bool(b) => Box::new(Bool { b }),
+            Value::Null => Box::new(Null),
+            Value::Duration(d) => Box::new(Dur { d }),
+            n @ (Value::Int(_) | Value::Number(_)) => Box::new(Num { n }),
+            value => Box::new(CoercedConst { value }),
+        },
+        Expr::Path(ident) => Box::new(SelectorPath {
+            ident,
+            missing_as_null: options.missing_as_null,
+        }),
+        Expr::Variable(name) => Box::new(Variable { name }),
+        Expr::Array(items) => Box::new(Arr {
+            arr: items
+                .into_iter()
+                .map(|item| compile(item, options))
+                .collect::<anyhow::Result<_>>()?,
+        }),
+        Expr::Object(members) => Box::new(Obj {
+            members: members
+                .into_iter()
+                .map(|(key, value)| Ok((key, compile(value, options)?)))
+                .collect::<anyhow::Result<_>>()?,
+        }),
+        Expr::Unary { op, expr } => Box::new(Unary {
+            op,
+            value: compile_box(expr)?,
+        }),
+        Expr::Binary {
+            op: op @ (BinaryOp::In | BinaryOp::NotIn),
+            left,
+            right,
+        } if LiteralSet::new(op, &right).is_some() => Box::new(InSet {
+            value: compile_box(left)?,
+            set: LiteralSet::new(op, &right).unwrap_or_default(),
+        }),
+        Expr::Binary { op, left, right } => Box::new(Binary {
+            op,
+            left: compile_box(left)?,
+            right: compile_box(right)?,
+        }),
+        Expr::Between { expr, low, high } => Box::new(Between {
+            value: compile_box(expr)?,
+            left: compile_box(low)?,
+            right: compile_box(high)?,
+        }),
+        // the regular expression is compiled once here instead of for every document
+        Expr::Matches { expr, pattern } => Box::new(Matches {
+            value: compile_box(expr)?,
+            regex: compile_regex(&pattern)?,
+        }),
+        Expr::Exists(ident) => Box::new(Exists { ident }),
+        Expr::Missing(ident) => Box::new(Missing { ident }),
+        Expr::Coerce { expr, to } => {
+            // a constant datetime is parsed once here
+            let constant = matches!(to, Coercion::DateTime { .. }) && is_constant(&expr);
+            let coerced = Coerced {
+                value: compile_box(expr)?,
+                to: Coerce::new(to)?,
+            };
+            if constant {
+                Box::new(CoercedConst {
+                    value: coerced.calculate(&[])?,
+                })
+            } else {
+                Box::new(coerced)
             }
-            TokenKind::Between => {
-                let lhs_token = self.next_operator_token(token.clone())?;
-                let left = self.parse_value(lhs_token)?;
-                let rhs_token = self.next_operator_token(token)?;
-                let right = self.parse_value(rhs_token)?;
-                Ok(Some(Box::new(Between {
-                    left,
-                    right,
-                    value: current,
-                })))
+        }
+        Expr::Case { branches, otherwise } => Box::new(Case {
+            branches: branches
+                .into_iter()
+                .map(|(condition, value)| Ok((compile(condition, options)?, compile(value, options)?)))
+                .collect::<anyhow::Result<_>>()?,
+            otherwise: otherwise.map(compile_box).transpose()?,
+        }),
+        Expr::Iterate { op, array, body } => Box::new(Iterate {
+            op,
+            array: compile_box(array)?,
+            body: compile_box(body)?,
+        }),
+        Expr::Call { name, args } => {
+            let args = args
+                .into_iter()
+                .map(|arg| compile(arg, options))
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            if name == "now" && args.is_empty() {
+                return Ok(Box::new(Now));
             }
-            TokenKind::Not => {
-                let next_token = self.next_operator_token(token)?;
-                let value = self
-                    .parse_operation(next_token, current)?
-                    .map_or_else(|| Err(anyhow!("invalid operation after !")), Ok)?;
-                Ok(Some(Box::new(Not { value })))
+            check_arity(&name, args.len())?;
+            match functions::lookup(&name) {
+                Some(builtin) => Box::new(Call { builtin, args }),
+                // resolved from the `EvalContext` when calculated
+                None => Box::new(HostCall { name, args }),
             }
-            TokenKind::CloseBracket => Ok(Some(current)),
-            _ => Err(anyhow!("invalid operation: {:?}", token)),
         }
+    })
+}
+
+/// Whether `expr` is a literal or a `COERCE` of one.
+pub(crate) fn is_constant(expr: &Expr) -> bool {
+    match expr {
+        Expr::Literal(_) => true,
+        Expr::Coerce { expr, .. } => is_constant(expr),
+        _ => false,
+    }
+}
+
+/// Fails if `name` is a built-in function that does not take `count` arguments.
+pub(crate) fn check_arity(name: &str, count: usize) -> anyhow::Result<()> {
+    match functions::lookup(name) {
+        Some(builtin) if count < builtin.min_args || builtin.max_args.is_some_and(|max| count > max) => {
+            Err(anyhow!("function {name} does not take {count} argument(s)"))
+        }
+        _ => Ok(()),
     }
 }
 
+pub(crate) fn compile_regex(pattern: &str) -> anyhow::Result<Regex> {
+    Regex::new(pattern).map_err(|e| anyhow!("invalid MATCHES regular expression: {e}"))
+}
+
+/// An `Int` when the literal is a whole number that fits, a `Number` otherwise.
+fn number_literal(literal: &str) -> anyhow::Result<Value> {
+    Ok(match literal.parse::<i64>() {
+        Ok(i) => Value::Int(i),
+        Err(_) => Value::Number(literal.parse()?),
+    })
+}
+
 #[derive(Debug)]
 struct Between {
     left: BoxedExpression,
@@ -470,335 +1143,627 @@ struct Between {
 }
 
 impl Expression for Between {
-    fn calculate(&self, json: &[u8]) -> Result<Value> {
-        let left = self.left.calculate(json)?;
-        let right = self.right.calculate(json)?;
-        let value = self.value.calculate(json)?;
+    fn calculate_document(&self, document: &dyn Document, ctx: &EvalContext) -> Result<Value> {
+        let value = self.value.calculate_document(document, ctx)?;
+        let left = self.left.calculate_document(document, ctx)?;
+        let right = self.right.calculate_document(document, ctx)?;
+        between(value, left, right)
+    }
+}
 
-        match (value, left, right) {
-            (Value::String(v), Value::String(lhs), Value::String(rhs)) => {
-                Ok(Value::Bool(v > lhs && v < rhs))
-            }
-            (Value::Number(v), Value::Number(lhs), Value::Number(rhs)) => {
-                Ok(Value::Bool(v > lhs && v < rhs))
-            }
-            (Value::DateTime(v), Value::DateTime(lhs), Value::DateTime(rhs)) => {
-                Ok(Value::Bool(v > lhs && v < rhs))
-            }
-            (Value::Null, _, _) | (_, Value::Null, _) | (_, _, Value::Null) => {
-                Ok(Value::Bool(false))
-            }
-            (v, lhs, rhs) => Err(Error::UnsupportedTypeComparison(format!(
-                "{v} BETWEEN {lhs} {rhs}",
-            ))),
+/// `<value> BETWEEN <left> <right>`, both bounds are exclusive.
+pub(crate) fn between(value: Value, left: Value, right: Value) -> Result<Value> {
+    match (value, left, right) {
+        (Value::String(v), Value::String(lhs), Value::String(rhs)) => {
+            Ok(Value::Bool(v > lhs && v < rhs))
+        }
+        (ref v, ref lhs, ref rhs)
+            if number::is_number(v) && number::is_number(lhs) && number::is_number(rhs) =>
+        {
+            Ok(Value::Bool(
+                number::compare(v, lhs).is_some_and(Ordering::is_gt)
+                    && number::compare(v, rhs).is_some_and(Ordering::is_lt),
+            ))
         }
+        (Value::DateTime(v), Value::DateTime(lhs), Value::DateTime(rhs)) => {
+            Ok(Value::Bool(v > lhs && v < rhs))
+        }
+        (Value::Duration(v), Value::Duration(lhs), Value::Duration(rhs)) => {
+            Ok(Value::Bool(v > lhs && v < rhs))
+        }
+        (Value::Null | Value::Missing, _, _)
+        | (_, Value::Null | Value::Missing, _)
+        | (_, _, Value::Null | Value::Missing) => {
+            Ok(Value::Bool(false))
+        }
+        (v, lhs, rhs) => Err(Error::UnsupportedTypeComparison(format!(
+            "{v} BETWEEN {lhs} {rhs}",
+        ))),
     }
 }
 
-#[derive(Debug)]
-struct COERCEDateTime {
-    value: BoxedExpression,
+/// Time zone of a `COERCE _datetime_`, a fixed offset such as `+08:00` or a name such as `Asia/Tokyo`.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Zone {
+    Fixed(FixedOffset),
+    Named(Tz),
 }
 
-impl Expression for COERCEDateTime {
-    fn calculate(&self, json: &[u8]) -> Result<Value> {
-        let value = self.value.calculate(json)?;
+impl Zone {
+    fn parse(zone: &str) -> Option<Zone> {
+        match zone {
+            "Z" | "UTC" => Some(Zone::Fixed(Utc.fix())),
+            _ if zone.starts_with(['+', '-']) => {
+                let sign = if zone.starts_with('-') { -1 } else { 1 };
+                let digits = zone[1..].replace(':', "");
+                if !matches!(digits.len(), 2 | 4) || !digits.bytes().all(|b| b.is_ascii_digit()) {
+                    return None;
+                }
+                let hours: i32 = digits[..2].parse().ok()?;
+                let minutes: i32 = digits.get(2..).filter(|m| !m.is_empty()).map_or(Ok(0), str::parse).ok()?;
+                FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60)).map(Zone::Fixed)
+            }
+            _ => zone.parse().ok().map(Zone::Named),
+        }
+    }
 
-        match value {
-            Value::String(ref s) => match anydate::parse_utc(s) {
-                Err(_) => Ok(Value::Null),
-                Ok(dt) => Ok(Value::DateTime(dt)),
-            },
-            Value::Null => Ok(value),
-            value => Err(Error::UnsupportedCOERCE(
-                format!("{value} COERCE datetime",),
-            )),
+    /// The time `naive` shows on a clock in this zone, the earlier one when the clock is turned back
+    /// and `None` for a time skipped when it is turned forward.
+    fn localize(self, naive: &NaiveDateTime) -> Option<DateTime<FixedOffset>> {
+        match self {
+            Zone::Fixed(offset) => offset.from_local_datetime(naive).single(),
+            Zone::Named(tz) => tz.from_local_datetime(naive).earliest().map(|dt| dt.fixed_offset()),
+        }
+    }
+
+    fn convert(self, dt: &DateTime<FixedOffset>) -> DateTime<FixedOffset> {
+        match self {
+            Zone::Fixed(offset) => dt.with_timezone(&offset),
+            Zone::Named(tz) => dt.with_timezone(&tz).fixed_offset(),
         }
     }
 }
 
+/// The type a `COERCE` converts to, with its time zone parsed.
 #[derive(Debug)]
-struct Add {
-    left: BoxedExpression,
-    right: BoxedExpression,
-}
+pub(crate) enum Coerce {
+    /// `_datetime_[('<format>', '<zone>')]`
+    ///
+    /// Without a format the string is read in any format `anydate` knows. Strings without an offset of
+    /// their own are local time of `zone`, or UTC without one. Strings and datetimes with an offset are
+    /// converted to `zone`, keeping the instant.
+    DateTime {
+        /// A `chrono` format such as `%d/%m/%Y %H:%M`, strings not matching it become `Null`.
+        format: Option<String>,
+        zone: Option<Zone>,
+    },
+    String,
+    Number,
+    #[cfg(feature = "decimal")]
+    Decimal,
+    Lowercase,
+    Uppercase,
+}
+
+impl Coerce {
+    pub(crate) fn new(to: Coercion) -> anyhow::Result<Coerce> {
+        Ok(match to {
+            Coercion::DateTime { format, zone } => Coerce::DateTime {
+                format,
+                zone: zone
+                    .map(|zone| Zone::parse(&zone).ok_or_else(|| anyhow!("unknown time zone: {zone}")))
+                    .transpose()?,
+            },
+            Coercion::String => Coerce::String,
+            Coercion::Number => Coerce::Number,
+            #[cfg(feature = "decimal")]
+            Coercion::Decimal => Coerce::Decimal,
+            Coercion::Lowercase => Coerce::Lowercase,
+            Coercion::Uppercase => Coerce::Uppercase,
+        })
+    }
 
-impl Expression for Add {
-    fn calculate(&self, json: &[u8]) -> Result<Value> {
-        let left = self.left.calculate(json)?;
-        let right = self.right.calculate(json)?;
+    pub(crate) fn apply(&self, value: Value) -> Result<Value> {
+        match self {
+            Coerce::DateTime { format, zone } => match value {
+                Value::String(ref s) => Ok(parse_datetime(s, format.as_deref(), *zone).map_or(Value::Null, Value::DateTime)),
+                Value::DateTime(dt) => Ok(Value::DateTime(zone.map_or(dt, |zone| zone.convert(&dt)))),
+                Value::Null | Value::Missing => Ok(value),
+                value => Err(Error::UnsupportedCOERCE(
+                    format!("{value} COERCE datetime",),
+                )),
+            },
+            Coerce::String => match value {
+                Value::Null => Ok(Value::String("null".to_string())),
+                Value::Missing => Ok(Value::Missing),
+                Value::String(s) => Ok(Value::String(s)),
+                Value::Int(i) => Ok(Value::String(i.to_string())),
+                Value::Number(num) => Ok(Value::String(num.to_string())),
+                #[cfg(feature = "decimal")]
+                Value::Decimal(d) => Ok(Value::String(d.to_string())),
+                Value::Bool(b) => Ok(Value::String(b.to_string())),
+                Value::DateTime(dt) => Ok(Value::String(
+                    dt.to_rfc3339_opts(SecondsFormat::AutoSi, true),
+                )),
+                Value::Duration(d) => Ok(Value::String(format_duration(&d))),
+                _ => Err(Error::UnsupportedCOERCE(
+                    format!("{value} COERCE datetime",),
+                )),
+            },
+            Coerce::Number => match value {
+                Value::String(s) => match s.parse::<i64>() {
+                    Ok(i) => Ok(Value::Int(i)),
+                    Err(_) => Ok(Value::Number(
+                        s.parse::<f64>()
+                            .map_err(|e| Error::UnsupportedCOERCE(e.to_string()))?,
+                    )),
+                },
+                Value::Int(i) => Ok(Value::Int(i)),
+                Value::Number(num) => Ok(Value::Number(num)),
+                #[cfg(feature = "decimal")]
+                Value::Decimal(d) => Ok(Value::Decimal(d)),
+                Value::Bool(b) => Ok(Value::Int(i64::from(b))),
+                Value::DateTime(dt) => dt.timestamp_nanos_opt().map(Value::Int).ok_or_else(|| {
+                    Error::UnsupportedCOERCE(format!("{dt} is out of range of nanoseconds"))
+                }),
+                Value::Missing => Ok(Value::Missing),
+                _ => Err(Error::UnsupportedCOERCE(
+                    format!("{value} COERCE datetime",),
+                )),
+            },
+            #[cfg(feature = "decimal")]
+            Coerce::Decimal => {
+                use rust_decimal::Decimal;
+
+                match value {
+                    Value::String(s) => Ok(Value::Decimal(
+                        Decimal::from_str_exact(&s)
+                            .or_else(|_| Decimal::from_scientific(&s))
+                            .map_err(|e| Error::UnsupportedCOERCE(e.to_string()))?,
+                    )),
+                    Value::Int(i) => Ok(Value::Decimal(Decimal::from(i))),
+                    Value::Number(num) => Ok(Value::Decimal(
+                        Decimal::try_from(num).map_err(|e| Error::UnsupportedCOERCE(e.to_string()))?,
+                    )),
+                    Value::Decimal(d) => Ok(Value::Decimal(d)),
+                    Value::Missing => Ok(Value::Missing),
+                    _ => Err(Error::UnsupportedCOERCE(
+                        format!("{value} COERCE decimal",),
+                    )),
+                }
+            }
+            Coerce::Lowercase => match value {
+                Value::String(s) => Ok(Value::String(s.to_lowercase())),
+                Value::Missing => Ok(Value::Missing),
+                v => Err(Error::UnsupportedCOERCE(format!("{v} COERCE lowercase",))),
+            },
+            Coerce::Uppercase => match value {
+                Value::String(s) => Ok(Value::String(s.to_uppercase())),
+                Value::Missing => Ok(Value::Missing),
+                v => Err(Error::UnsupportedCOERCE(format!("{v} COERCE uppercase",))),
+            },
+        }
+    }
+}
 
-        match (left, right) {
-            (Value::String(s1), Value::String(ref s2)) => Ok(Value::String(s1 + s2)),
-            (Value::String(s1), Value::Null) => Ok(Value::String(s1)),
-            (Value::Null, Value::String(s2)) => Ok(Value::String(s2)),
-            (Value::Number(n1), Value::Number(n2)) => Ok(Value::Number(n1 + n2)),
-            (Value::Number(n1), Value::Null) => Ok(Value::Number(n1)),
-            (Value::Null, Value::Number(n2)) => Ok(Value::Number(n2)),
-            (l, r) => Err(Error::UnsupportedTypeComparison(format!("{l} + {r}",))),
+fn parse_datetime(s: &str, format: Option<&str>, zone: Option<Zone>) -> Option<DateTime<FixedOffset>> {
+    let localize = |naive: &NaiveDateTime| match zone {
+        Some(zone) => zone.localize(naive),
+        None => Some(Utc.fix().from_utc_datetime(naive)),
+    };
+    match format {
+        Some(format) if format.contains("%z") || format.contains(":z") || format.contains("%#z") => {
+            let dt = DateTime::parse_from_str(s, format).ok()?;
+            Some(zone.map_or(dt, |zone| zone.convert(&dt)))
+        }
+        Some(format) => {
+            let naive = NaiveDateTime::parse_from_str(s, format).ok().or_else(|| {
+                NaiveDate::parse_from_str(s, format)
+                    .ok()
+                    .map(|date| date.and_time(NaiveTime::MIN))
+            })?;
+            localize(&naive)
+        }
+        None => {
+            let dt = anydate::parse(s).ok()?;
+            match zone {
+                // anydate reads a string without an offset as UTC
+                Some(_) if !has_offset(s, &dt) => localize(&dt.naive_utc()),
+                Some(zone) => Some(zone.convert(&dt)),
+                None => Some(dt),
+            }
         }
     }
 }
 
-#[derive(Debug)]
-struct Sub {
-    left: BoxedExpression,
-    right: BoxedExpression,
+/// Whether `s`, parsed as `dt`, names its offset or is a unix timestamp.
+fn has_offset(s: &str, dt: &DateTime<FixedOffset>) -> bool {
+    dt.offset().local_minus_utc() != 0
+        || s.bytes().all(|b| b.is_ascii_digit())
+        || s.ends_with(['Z', 'z'])
+        || ["UTC", "GMT", "+00", "-00"].iter().any(|utc| s.contains(utc))
 }
 
-impl Expression for Sub {
-    fn calculate(&self, json: &[u8]) -> Result<Value> {
-        let left = self.left.calculate(json)?;
-        let right = self.right.calculate(json)?;
+#[derive(Debug)]
+struct Coerced {
+    value: BoxedExpression,
+    to: Coerce,
+}
 
-        match (left, right) {
-            (Value::Number(n1), Value::Number(n2)) => Ok(Value::Number(n1 - n2)),
-            (l, r) => Err(Error::UnsupportedTypeComparison(format!("{l} - {r}",))),
-        }
+impl Expression for Coerced {
+    fn calculate_document(&self, document: &dyn Document, ctx: &EvalContext) -> Result<Value> {
+        self.to.apply(self.value.calculate_document(document, ctx)?)
     }
 }
 
 #[derive(Debug)]
-struct Mult {
+struct Binary {
+    op: BinaryOp,
     left: BoxedExpression,
     right: BoxedExpression,
 }
 
-impl Expression for Mult {
-    fn calculate(&self, json: &[u8]) -> Result<Value> {
-        let left = self.left.calculate(json)?;
-        let right = self.right.calculate(json)?;
-
-        match (left, right) {
-            (Value::Number(n1), Value::Number(n2)) => Ok(Value::Number(n1 * n2)),
-            (l, r) => Err(Error::UnsupportedTypeComparison(format!("{l} * {r}",))),
+impl Expression for Binary {
+    fn calculate_document(&self, document: &dyn Document, ctx: &EvalContext) -> Result<Value> {
+        let left = self.left.calculate_document(document, ctx)?;
+        if short_circuits(self.op, &left) {
+            return Ok(Value::Bool(self.op == BinaryOp::Or));
         }
+        let right = self.right.calculate_document(document, ctx)?;
+        binary(self.op, left, right)
     }
 }
 
-#[derive(Debug)]
-struct Div {
-    left: BoxedExpression,
-    right: BoxedExpression,
+/// Whether `left` alone gives the result of `&&` or `||`, which is then `false` for `&&` and
+/// `true` for `||`, so that the right side is not calculated.
+pub(crate) fn short_circuits(op: BinaryOp, left: &Value) -> bool {
+    match op {
+        BinaryOp::And => matches!(left, Value::Bool(false) | Value::Missing),
+        BinaryOp::Or => matches!(left, Value::Bool(true)),
+        _ => false,
+    }
 }
 
-impl Expression for Div {
-    fn calculate(&self, json: &[u8]) -> Result<Value> {
-        let left = self.left.calculate(json)?;
-        let right = self.right.calculate(json)?;
-
-        match (left, right) {
-            (Value::Number(n1), Value::Number(n2)) => Ok(Value::Number(n1 / n2)),
-            (l, r) => Err(Error::UnsupportedTypeComparison(format!("{l} / {r}",))),
-        }
+/// Whether the condition of a `CASE WHEN` or `?` holds, a missing one does not, like with `&&`.
+pub(crate) fn holds(condition: Value) -> Result<bool> {
+    match condition {
+        Value::Bool(b) => Ok(b),
+        Value::Missing => Ok(false),
+        v => Err(Error::UnsupportedTypeComparison(format!("CASE WHEN {v}"))),
     }
 }
 
-#[derive(Debug)]
-struct Eq {
-    left: BoxedExpression,
-    right: BoxedExpression,
+/// Applies the binary operator `op` to two calculated values.
+pub(crate) fn binary(op: BinaryOp, left: Value, right: Value) -> Result<Value> {
+    match op {
+        BinaryOp::Add => add(left, right),
+        BinaryOp::Sub => sub(left, right),
+        BinaryOp::Mul => arithmetic(Op::Mul, left, right),
+        BinaryOp::Div => arithmetic(Op::Div, left, right),
+        BinaryOp::IntDiv => arithmetic(Op::IntDiv, left, right),
+        BinaryOp::Rem => arithmetic(Op::Rem, left, right),
+        BinaryOp::Pow => arithmetic(Op::Pow, left, right),
+        BinaryOp::Eq => Ok(Value::Bool(left == right)),
+        BinaryOp::NotEq => Ok(Value::Bool(left != right)),
+        BinaryOp::Gt => order(op, left, right, Ordering::is_gt),
+        BinaryOp::Gte => order(op, left, right, Ordering::is_ge),
+        BinaryOp::Lt => order(op, left, right, Ordering::is_lt),
+        BinaryOp::Lte => order(op, left, right, Ordering::is_le),
+        BinaryOp::Or => or(left, right),
+        BinaryOp::And => and(left, right),
+        BinaryOp::StartsWith => starts_with(left, right),
+        BinaryOp::EndsWith => ends_with(left, right),
+        BinaryOp::Like => like(left, right),
+        BinaryOp::ILike => ilike(left, right),
+        BinaryOp::In => in_array(left, right),
+        BinaryOp::NotIn => not_in_array(left, right),
+        BinaryOp::Contains => contains(left, right),
+        BinaryOp::NotContains => not_contains(left, right),
+        BinaryOp::ContainsAny => contains_any(left, right),
+        BinaryOp::ContainsAll => contains_all(left, right),
+    }
 }
 
-impl Expression for Eq {
-    fn calculate(&self, json: &[u8]) -> Result<Value> {
-        let left = self.left.calculate(json)?;
-        let right = self.right.calculate(json)?;
-        Ok(Value::Bool(left == right))
+fn add(left: Value, right: Value) -> Result<Value> {
+    match (left, right) {
+        (Value::Missing, _) | (_, Value::Missing) => Ok(Value::Missing),
+        (Value::String(s1), Value::String(ref s2)) => Ok(Value::String(s1 + s2)),
+        (Value::String(s1), Value::Null) => Ok(Value::String(s1)),
+        (Value::Null, Value::String(s2)) => Ok(Value::String(s2)),
+        (ref l, ref r) if number::is_number(l) && number::is_number(r) => {
+            number::arithmetic(Op::Add, l, r).expect("both sides are numbers")
+        }
+        (Value::DateTime(dt), Value::Duration(d)) | (Value::Duration(d), Value::DateTime(dt)) => {
+            dt.checked_add_signed(d).map(Value::DateTime).ok_or_else(|| {
+                Error::UnsupportedTypeComparison(format!("{dt} + {d} overflows"))
+            })
+        }
+        (Value::Duration(d1), Value::Duration(d2)) => {
+            d1.checked_add(&d2).map(Value::Duration).ok_or_else(|| {
+                Error::UnsupportedTypeComparison(format!("{d1} + {d2} overflows"))
+            })
+        }
+        (n, Value::Null) | (Value::Null, n) if number::is_number(&n) => Ok(n),
+        (l, r) => Err(Error::UnsupportedTypeComparison(format!("{l} + {r}",))),
     }
 }
 
-#[derive(Debug)]
-struct Gt {
-    left: BoxedExpression,
-    right: BoxedExpression,
+fn sub(left: Value, right: Value) -> Result<Value> {
+    match (left, right) {
+        (Value::Missing, _) | (_, Value::Missing) => Ok(Value::Missing),
+        (ref l, ref r) if number::is_number(l) && number::is_number(r) => {
+            number::arithmetic(Op::Sub, l, r).expect("both sides are numbers")
+        }
+        (Value::DateTime(dt), Value::Duration(d)) => {
+            dt.checked_sub_signed(d).map(Value::DateTime).ok_or_else(|| {
+                Error::UnsupportedTypeComparison(format!("{dt} - {d} overflows"))
+            })
+        }
+        (Value::DateTime(dt1), Value::DateTime(dt2)) => Ok(Value::Duration(dt1 - dt2)),
+        (Value::Duration(d1), Value::Duration(d2)) => {
+            d1.checked_sub(&d2).map(Value::Duration).ok_or_else(|| {
+                Error::UnsupportedTypeComparison(format!("{d1} - {d2} overflows"))
+            })
+        }
+        (l, r) => Err(Error::UnsupportedTypeComparison(format!("{l} - {r}",))),
+    }
 }
 
-impl Expression for Gt {
-    fn calculate(&self, json: &[u8]) -> Result<Value> {
-        let left = self.left.calculate(json)?;
-        let right = self.right.calculate(json)?;
-
-        match (left, right) {
-            (Value::String(s1), Value::String(s2)) => Ok(Value::Bool(s1 > s2)),
-            (Value::Number(n1), Value::Number(n2)) => Ok(Value::Bool(n1 > n2)),
-            (Value::DateTime(dt1), Value::DateTime(dt2)) => Ok(Value::Bool(dt1 > dt2)),
-            (l, r) => Err(Error::UnsupportedTypeComparison(format!("{l} > {r}",))),
+/// `*`, `/`, `DIV`, `%` and `**`, which only apply to numbers.
+fn arithmetic(op: Op, left: Value, right: Value) -> Result<Value> {
+    match (left, right) {
+        (Value::Missing, _) | (_, Value::Missing) => Ok(Value::Missing),
+        (ref l, ref r) if number::is_number(l) && number::is_number(r) => {
+            number::arithmetic(op, l, r).expect("both sides are numbers")
         }
+        (l, r) => Err(Error::UnsupportedTypeComparison(format!("{l} {op} {r}",))),
     }
 }
 
-#[derive(Debug)]
-struct Gte {
-    left: BoxedExpression,
-    right: BoxedExpression,
+/// `>`, `>=`, `<` and `<=`, true when `accept` the ordering of `left` to `right`.
+fn order(op: BinaryOp, left: Value, right: Value, accept: fn(Ordering) -> bool) -> Result<Value> {
+    let ordering = match (&left, &right) {
+        (Value::String(s1), Value::String(s2)) => Some(s1.cmp(s2)),
+        (l, r) if number::is_number(l) && number::is_number(r) => number::compare(l, r),
+        (Value::DateTime(dt1), Value::DateTime(dt2)) => Some(dt1.cmp(dt2)),
+        (Value::Duration(d1), Value::Duration(d2)) => Some(d1.cmp(d2)),
+        (Value::Missing, _) | (_, Value::Missing) => None,
+        (l, r) => {
+            return Err(Error::UnsupportedTypeComparison(format!(
+                "{l} {} {r}",
+                op.symbol()
+            )))
+        }
+    };
+    Ok(Value::Bool(ordering.is_some_and(accept)))
 }
 
-impl Expression for Gte {
-    fn calculate(&self, json: &[u8]) -> Result<Value> {
-        let left = self.left.calculate(json)?;
-        let right = self.right.calculate(json)?;
+fn or(left: Value, right: Value) -> Result<Value> {
+    match (left, right) {
+        (Value::Bool(b1), Value::Bool(b2)) => Ok(Value::Bool(b1 || b2)),
+        (Value::Bool(b), Value::Missing) | (Value::Missing, Value::Bool(b)) => Ok(Value::Bool(b)),
+        (Value::Missing, Value::Missing) => Ok(Value::Bool(false)),
+        (l, r) => Err(Error::UnsupportedTypeComparison(format!("{l} || {r}",))),
+    }
+}
 
-        match (left, right) {
-            (Value::String(s1), Value::String(s2)) => Ok(Value::Bool(s1 >= s2)),
-            (Value::Number(n1), Value::Number(n2)) => Ok(Value::Bool(n1 >= n2)),
-            (Value::DateTime(dt1), Value::DateTime(dt2)) => Ok(Value::Bool(dt1 >= dt2)),
-            (l, r) => Err(Error::UnsupportedTypeComparison(format!("{l} >= {r}",))),
+fn and(left: Value, right: Value) -> Result<Value> {
+    match (left, right) {
+        (Value::Bool(b1), Value::Bool(b2)) => Ok(Value::Bool(b1 && b2)),
+        (Value::Bool(_) | Value::Missing, Value::Missing) | (Value::Missing, Value::Bool(_)) => {
+            Ok(Value::Bool(false))
         }
+        (l, r) => Err(Error::UnsupportedTypeComparison(format!("{l} && {r}",))),
     }
 }
 
-#[derive(Debug)]
-struct Lt {
-    left: BoxedExpression,
-    right: BoxedExpression,
+fn contains(left: Value, right: Value) -> Result<Value> {
+    match (left, right) {
+        (Value::String(s1), Value::String(s2)) => Ok(Value::Bool(s1.contains(&s2))),
+        (Value::Array(arr1), v) => Ok(Value::Bool(arr1.contains(&v))),
+        (Value::Missing, _) | (_, Value::Missing) => Ok(Value::Bool(false)),
+        (l, r) => Err(Error::UnsupportedTypeComparison(format!(
+            "{l} CONTAINS {r}",
+        ))),
+    }
 }
 
-impl Expression for Lt {
-    fn calculate(&self, json: &[u8]) -> Result<Value> {
-        let left = self.left.calculate(json)?;
-        let right = self.right.calculate(json)?;
+fn not_contains(left: Value, right: Value) -> Result<Value> {
+    match (left, right) {
+        (Value::String(s1), Value::String(s2)) => Ok(Value::Bool(!s1.contains(&s2))),
+        (Value::Array(arr1), v) => Ok(Value::Bool(!arr1.contains(&v))),
+        (Value::Missing, _) | (_, Value::Missing) => Ok(Value::Bool(true)),
+        (l, r) => Err(Error::UnsupportedTypeComparison(format!(
+            "{l} NOT CONTAINS {r}",
+        ))),
+    }
+}
 
-        match (left, right) {
-            (Value::String(s1), Value::String(s2)) => Ok(Value::Bool(s1 < s2)),
-            (Value::Number(n1), Value::Number(n2)) => Ok(Value::Bool(n1 < n2)),
-            (Value::DateTime(dt1), Value::DateTime(dt2)) => Ok(Value::Bool(dt1 < dt2)),
-            (l, r) => Err(Error::UnsupportedTypeComparison(format!("{l} < {r}",))),
+fn contains_any(left: Value, right: Value) -> Result<Value> {
+    match (left, right) {
+        (Value::String(s1), Value::String(s2)) => {
+            let b1: Vec<char> = s1.chars().collect();
+            // betting that lists are short and so less expensive than iterating one to create a hash set
+            Ok(Value::Bool(s2.chars().any(|b| b1.contains(&b))))
         }
+        (Value::Array(arr1), Value::Array(arr2)) => {
+            Ok(Value::Bool(arr2.iter().any(|v| arr1.contains(v))))
+        }
+        (Value::Array(arr), Value::String(s)) => Ok(Value::Bool(
+            s.chars()
+                .any(|v| arr.contains(&Value::String(v.to_string()))),
+        )),
+        (Value::String(s), Value::Array(arr)) => Ok(Value::Bool(arr.iter().any(|v| match v {
+            Value::String(s2) => s.contains(s2),
+            _ => false,
+        }))),
+        (Value::Missing, _) | (_, Value::Missing) => Ok(Value::Bool(false)),
+        (l, r) => Err(Error::UnsupportedTypeComparison(format!(
+            "{l} CONTAINS_ANY {r}",
+        ))),
     }
 }
 
-#[derive(Debug)]
-struct Lte {
-    left: BoxedExpression,
-    right: BoxedExpression,
+fn contains_all(left: Value, right: Value) -> Result<Value> {
+    match (left, right) {
+        (Value::String(s1), Value::String(s2)) => {
+            let b1: Vec<char> = s1.chars().collect();
+            Ok(Value::Bool(s2.chars().all(|b| b1.contains(&b))))
+        }
+        (Value::Array(arr1), Value::Array(arr2)) => {
+            Ok(Value::Bool(arr2.iter().all(|v| arr1.contains(v))))
+        }
+        (Value::Array(arr), Value::String(s)) => Ok(Value::Bool(
+            s.chars()
+                .all(|v| arr.contains(&Value::String(v.to_string()))),
+        )),
+        (Value::String(s), Value::Array(arr)) => Ok(Value::Bool(arr.iter().all(|v| match v {
+            Value::String(s2) => s.contains(s2),
+            _ => false,
+        }))),
+        (Value::Missing, _) | (_, Value::Missing) => Ok(Value::Bool(false)),
+        (l, r) => Err(Error::UnsupportedTypeComparison(format!(
+            "{l} CONTAINS_ALL {r}",
+        ))),
+    }
 }
 
-impl Expression for Lte {
-    fn calculate(&self, json: &[u8]) -> Result<Value> {
-        let left = self.left.calculate(json)?;
-        let right = self.right.calculate(json)?;
+fn starts_with(left: Value, right: Value) -> Result<Value> {
+    match (left, right) {
+        (Value::String(s1), Value::String(s2)) => Ok(Value::Bool(s1.starts_with(&s2))),
+        (Value::Missing, _) | (_, Value::Missing) => Ok(Value::Bool(false)),
+        (l, r) => Err(Error::UnsupportedTypeComparison(format!("{l} STARTS_WITH {r}",))),
+    }
+}
 
-        match (left, right) {
-            (Value::String(s1), Value::String(s2)) => Ok(Value::Bool(s1 <= s2)),
-            (Value::Number(n1), Value::Number(n2)) => Ok(Value::Bool(n1 <= n2)),
-            (Value::DateTime(dt1), Value::DateTime(dt2)) => Ok(Value::Bool(dt1 <= dt2)),
-            (l, r) => Err(Error::UnsupportedTypeComparison(format!("{l} <= {r}",))),
-        }
+fn ends_with(left: Value, right: Value) -> Result<Value> {
+    match (left, right) {
+        (Value::String(s1), Value::String(s2)) => Ok(Value::Bool(s1.ends_with(&s2))),
+        (Value::Missing, _) | (_, Value::Missing) => Ok(Value::Bool(false)),
+        (l, r) => Err(Error::UnsupportedTypeComparison(format!("{l} ENDS_WITH {r}",))),
     }
 }
 
-#[derive(Debug)]
-struct CoercedConst {
-    value: Value,
+/// `*` matches any sequence of characters, `?` a single one and `\` escapes them.
+fn like(left: Value, right: Value) -> Result<Value> {
+    match (left, right) {
+        (Value::String(s), Value::String(pattern)) => Ok(Value::Bool(pmatch(pattern, s))),
+        (Value::Missing, _) | (_, Value::Missing) => Ok(Value::Bool(false)),
+        (l, r) => Err(Error::UnsupportedTypeComparison(format!("{l} LIKE {r}",))),
+    }
 }
 
-impl Expression for CoercedConst {
-    fn calculate(&self, _json: &[u8]) -> Result<Value> {
-        Ok(self.value.clone())
+/// Case-insensitive `like`.
+fn ilike(left: Value, right: Value) -> Result<Value> {
+    match (left, right) {
+        (Value::String(s), Value::String(pattern)) => Ok(Value::Bool(pmatch(
+            pattern.to_lowercase(),
+            s.to_lowercase(),
+        ))),
+        (Value::Missing, _) | (_, Value::Missing) => Ok(Value::Bool(false)),
+        (l, r) => Err(Error::UnsupportedTypeComparison(format!("{l} ILIKE {r}",))),
     }
 }
 
-#[derive(Debug)]
-struct CoerceToNumber {
-    value: BoxedExpression,
+fn in_array(left: Value, right: Value) -> Result<Value> {
+    match (left, right) {
+        (v, Value::Array(a)) => Ok(Value::Bool(a.contains(&v))),
+        (Value::Missing, _) | (_, Value::Missing) => Ok(Value::Bool(false)),
+        (l, r) => Err(Error::UnsupportedTypeComparison(format!("{l} IN {r}",))),
+    }
 }
 
-impl Expression for CoerceToNumber {
-    #[allow(clippy::cast_precision_loss)]
-    fn calculate(&self, json: &[u8]) -> Result<Value> {
-        let value = self.value.calculate(json)?;
-        match value {
-            Value::String(s) => Ok(Value::Number(
-                s.parse::<f64>()
-                    .map_err(|e| Error::UnsupportedCOERCE(e.to_string()))?,
-            )),
-            Value::Number(num) => Ok(Value::Number(num)),
-            Value::Bool(b) => Ok(Value::Number(if b { 1.0 } else { 0.0 })),
-            Value::DateTime(dt) => Ok(Value::Number(dt.timestamp_nanos() as f64)),
-            _ => Err(Error::UnsupportedCOERCE(
-                format!("{value} COERCE datetime",),
-            )),
-        }
+fn not_in_array(left: Value, right: Value) -> Result<Value> {
+    match (left, right) {
+        (v, Value::Array(a)) => Ok(Value::Bool(!a.contains(&v))),
+        (Value::Missing, _) | (_, Value::Missing) => Ok(Value::Bool(true)),
+        (l, r) => Err(Error::UnsupportedTypeComparison(format!("{l} NOT IN {r}",))),
     }
 }
 
 #[derive(Debug)]
-struct CoerceToString {
+struct Unary {
+    op: UnaryOp,
     value: BoxedExpression,
 }
 
-impl Expression for CoerceToString {
-    fn calculate(&self, json: &[u8]) -> Result<Value> {
-        let value = self.value.calculate(json)?;
-        match value {
-            Value::Null => Ok(Value::String("null".to_string())),
-            Value::String(s) => Ok(Value::String(s)),
-            Value::Number(num) => Ok(Value::String(num.to_string())),
-            Value::Bool(b) => Ok(Value::String(b.to_string())),
-            Value::DateTime(dt) => Ok(Value::String(
-                dt.to_rfc3339_opts(SecondsFormat::AutoSi, true),
-            )),
-            _ => Err(Error::UnsupportedCOERCE(
-                format!("{value} COERCE datetime",),
-            )),
-        }
+impl Expression for Unary {
+    fn calculate_document(&self, document: &dyn Document, ctx: &EvalContext) -> Result<Value> {
+        unary(self.op, self.value.calculate_document(document, ctx)?)
+    }
+}
+
+/// Applies the prefix or postfix operator `op` to a calculated value.
+pub(crate) fn unary(op: UnaryOp, value: Value) -> Result<Value> {
+    match op {
+        UnaryOp::Not => match value {
+            Value::Bool(b) => Ok(Value::Bool(!b)),
+            Value::Missing => Ok(Value::Bool(true)),
+            v => Err(Error::UnsupportedTypeComparison(format!("{v:?} for !"))),
+        },
+        UnaryOp::Neg => match value {
+            Value::Missing => Ok(Value::Missing),
+            Value::Null => Ok(Value::Null),
+            Value::Duration(d) => Ok(Value::Duration(-d)),
+            v => number::negate(&v)
+                .ok_or_else(|| Error::UnsupportedTypeComparison(format!("-{v}"))),
+        },
+        UnaryOp::Pos => match value {
+            Value::Missing | Value::Null | Value::Duration(_) => Ok(value),
+            v if number::is_number(&v) => Ok(v),
+            v => Err(Error::UnsupportedTypeComparison(format!("+{v}"))),
+        },
+        UnaryOp::IsNull => Ok(Value::Bool(value == Value::Null)),
+        UnaryOp::IsNotNull => Ok(Value::Bool(!matches!(value, Value::Null | Value::Missing))),
     }
 }
 
 #[derive(Debug)]
-struct CoerceLowercase {
-    value: BoxedExpression,
+struct Exists {
+    ident: String,
 }
 
-impl Expression for CoerceLowercase {
-    fn calculate(&self, json: &[u8]) -> Result<Value> {
-        let v = self.value.calculate(json)?;
-        match v {
-            Value::String(s) => Ok(Value::String(s.to_lowercase())),
-            v => Err(Error::UnsupportedCOERCE(format!("{v} COERCE lowercase",))),
-        }
+impl Expression for Exists {
+    fn calculate_document(&self, document: &dyn Document, _: &EvalContext) -> Result<Value> {
+        Ok(Value::Bool(document.exists(&self.ident)))
     }
 }
 
 #[derive(Debug)]
-struct CoerceUppercase {
-    value: BoxedExpression,
+struct Missing {
+    ident: String,
 }
 
-impl Expression for CoerceUppercase {
-    fn calculate(&self, json: &[u8]) -> Result<Value> {
-        let v = self.value.calculate(json)?;
-        match v {
-            Value::String(s) => Ok(Value::String(s.to_uppercase())),
-            v => Err(Error::UnsupportedCOERCE(format!("{v} COERCE uppercase",))),
-        }
+impl Expression for Missing {
+    fn calculate_document(&self, document: &dyn Document, _: &EvalContext) -> Result<Value> {
+        Ok(Value::Bool(!document.exists(&self.ident)))
     }
 }
 
 #[derive(Debug)]
-struct Not {
-    value: BoxedExpression,
+struct CoercedConst {
+    value: Value,
 }
 
-impl Expression for Not {
-    fn calculate(&self, json: &[u8]) -> Result<Value> {
-        let v = self.value.calculate(json)?;
-        match v {
-            Value::Bool(b) => Ok(Value::Bool(!b)),
-            v => Err(Error::UnsupportedTypeComparison(format!("{v:?} for !"))),
-        }
+impl Expression for CoercedConst {
+    fn calculate_document(&self, _: &dyn Document, _ctx: &EvalContext) -> Result<Value> {
+        Ok(self.value.clone())
     }
 }
 
 #[derive(Debug)]
 struct SelectorPath {
     ident: String,
+    missing_as_null: bool,
 }
 
 impl Expression for SelectorPath {
-    fn calculate(&self, json: &[u8]) -> Result<Value> {
-        Ok(unsafe { get_bytes(json, &self.ident).into() })
+    fn calculate_document(&self, document: &dyn Document, _: &EvalContext) -> Result<Value> {
+        match document.get(&self.ident) {
+            Some(value) => Ok(value),
+            None if self.missing_as_null => Ok(Value::Null),
+            None => Ok(Value::Missing),
+        }
     }
 }
 
@@ -808,19 +1773,39 @@ struct Str {
 }
 
 impl Expression for Str {
-    fn calculate(&self, _: &[u8]) -> Result<Value> {
+    fn calculate_document(&self, _: &dyn Document, _: &EvalContext) -> Result<Value> {
         Ok(Value::String(self.s.clone()))
     }
 }
 
 #[derive(Debug)]
 struct Num {
-    n: f64,
+    n: Value,
 }
 
 impl Expression for Num {
-    fn calculate(&self, _: &[u8]) -> Result<Value> {
-        Ok(Value::Number(self.n))
+    fn calculate_document(&self, _: &dyn Document, _: &EvalContext) -> Result<Value> {
+        Ok(self.n.clone())
+    }
+}
+
+#[derive(Debug)]
+struct Dur {
+    d: chrono::Duration,
+}
+
+impl Expression for Dur {
+    fn calculate_document(&self, _: &dyn Document, _: &EvalContext) -> Result<Value> {
+        Ok(Value::Duration(self.d))
+    }
+}
+
+#[derive(Debug)]
+struct Now;
+
+impl Expression for Now {
+    fn calculate_document(&self, _: &dyn Document, ctx: &EvalContext) -> Result<Value> {
+        Ok(Value::DateTime(ctx.now().fixed_offset()))
     }
 }
 
@@ -830,7 +1815,7 @@ struct Bool {
 }
 
 impl Expression for Bool {
-    fn calculate(&self, _: &[u8]) -> Result<Value> {
+    fn calculate_document(&self, _: &dyn Document, _: &EvalContext) -> Result<Value> {
         Ok(Value::Bool(self.b))
     }
 }
@@ -839,185 +1824,267 @@ impl Expression for Bool {
 struct Null;
 
 impl Expression for Null {
-    fn calculate(&self, _: &[u8]) -> Result<Value> {
+    fn calculate_document(&self, _: &dyn Document, _: &EvalContext) -> Result<Value> {
         Ok(Value::Null)
     }
 }
 
 #[derive(Debug)]
-struct Or {
-    left: BoxedExpression,
-    right: BoxedExpression,
+struct Matches {
+    value: BoxedExpression,
+    regex: Regex,
 }
 
-impl Expression for Or {
-    fn calculate(&self, json: &[u8]) -> Result<Value> {
-        let left = self.left.calculate(json)?;
-        let right = self.right.calculate(json)?;
-
-        match (left, right) {
-            (Value::Bool(b1), Value::Bool(b2)) => Ok(Value::Bool(b1 || b2)),
-            (l, r) => Err(Error::UnsupportedTypeComparison(format!("{l} || {r}",))),
-        }
+impl Expression for Matches {
+    fn calculate_document(&self, document: &dyn Document, ctx: &EvalContext) -> Result<Value> {
+        matches(self.value.calculate_document(document, ctx)?, &self.regex)
     }
 }
 
 #[derive(Debug)]
-struct And {
-    left: BoxedExpression,
-    right: BoxedExpression,
+struct Case {
+    branches: Vec<(BoxedExpression, BoxedExpression)>,
+    otherwise: Option<BoxedExpression>,
 }
 
-impl Expression for And {
-    fn calculate(&self, json: &[u8]) -> Result<Value> {
-        let left = self.left.calculate(json)?;
-        let right = self.right.calculate(json)?;
-
-        match (left, right) {
-            (Value::Bool(b1), Value::Bool(b2)) => Ok(Value::Bool(b1 && b2)),
-            (l, r) => Err(Error::UnsupportedTypeComparison(format!("{l} && {r}",))),
+impl Expression for Case {
+    fn calculate_document(&self, document: &dyn Document, ctx: &EvalContext) -> Result<Value> {
+        for (condition, value) in &self.branches {
+            if holds(condition.calculate_document(document, ctx)?)? {
+                return value.calculate_document(document, ctx);
+            }
+        }
+        match &self.otherwise {
+            Some(otherwise) => otherwise.calculate_document(document, ctx),
+            None => Ok(Value::Null),
         }
     }
 }
 
 #[derive(Debug)]
-struct Contains {
-    left: BoxedExpression,
-    right: BoxedExpression,
+struct Iterate {
+    op: IterateOp,
+    array: BoxedExpression,
+    body: BoxedExpression,
 }
 
-impl Expression for Contains {
-    fn calculate(&self, json: &[u8]) -> Result<Value> {
-        let left = self.left.calculate(json)?;
-        let right = self.right.calculate(json)?;
-        match (left, right) {
-            (Value::String(s1), Value::String(s2)) => Ok(Value::Bool(s1.contains(&s2))),
-            (Value::Array(arr1), v) => Ok(Value::Bool(arr1.contains(&v))),
-            (l, r) => Err(Error::UnsupportedTypeComparison(format!(
-                "{l} CONTAINS {r}",
-            ))),
-        }
+impl Expression for Iterate {
+    fn calculate_document(&self, document: &dyn Document, ctx: &EvalContext) -> Result<Value> {
+        let array = self.array.calculate_document(document, ctx)?;
+        iterate(self.op, array, |element| self.body.calculate_document(element, ctx))
     }
 }
 
-#[derive(Debug)]
-struct ContainsAny {
-    left: BoxedExpression,
-    right: BoxedExpression,
-}
-
-impl Expression for ContainsAny {
-    fn calculate(&self, json: &[u8]) -> Result<Value> {
-        let left = self.left.calculate(json)?;
-        let right = self.right.calculate(json)?;
-        match (left, right) {
-            (Value::String(s1), Value::String(s2)) => {
-                let b1: Vec<char> = s1.chars().collect();
-                // betting that lists are short and so less expensive than iterating one to create a hash set
-                Ok(Value::Bool(s2.chars().any(|b| b1.contains(&b))))
+/// `ANY`, `ALL`, `FILTER` or `MAP` over the elements of `array`, `body` calculating the body for
+/// an element. No element of a missing or null array matches, `FILTER` and `MAP` keep it as it is.
+pub(crate) fn iterate(
+    op: IterateOp,
+    array: Value,
+    body: impl Fn(&Value) -> Result<Value>,
+) -> Result<Value> {
+    let items = match array {
+        Value::Array(items) => items,
+        Value::Null | Value::Missing if matches!(op, IterateOp::Any | IterateOp::All) => {
+            return Ok(Value::Bool(false))
+        }
+        v @ (Value::Null | Value::Missing) => return Ok(v),
+        v => {
+            return Err(Error::UnsupportedTypeComparison(format!(
+                "{} over {v}",
+                op.keyword()
+            )))
+        }
+    };
+    let is_match = |element: &Value| Ok::<_, Error>(body(element)? == Value::Bool(true));
+    match op {
+        IterateOp::Any => {
+            for element in &items {
+                if is_match(element)? {
+                    return Ok(Value::Bool(true));
+                }
+            }
+            Ok(Value::Bool(false))
+        }
+        IterateOp::All => {
+            for element in &items {
+                if !is_match(element)? {
+                    return Ok(Value::Bool(false));
+                }
             }
-            (Value::Array(arr1), Value::Array(arr2)) => {
-                Ok(Value::Bool(arr2.iter().any(|v| arr1.contains(v))))
+            Ok(Value::Bool(true))
+        }
+        IterateOp::Filter => {
+            let mut kept = Vec::new();
+            for element in items {
+                if is_match(&element)? {
+                    kept.push(element);
+                }
             }
-            (Value::Array(arr), Value::String(s)) => Ok(Value::Bool(
-                s.chars()
-                    .any(|v| arr.contains(&Value::String(v.to_string()))),
-            )),
-            (Value::String(s), Value::Array(arr)) => Ok(Value::Bool(arr.iter().any(|v| match v {
-                Value::String(s2) => s.contains(s2),
-                _ => false,
-            }))),
-            (l, r) => Err(Error::UnsupportedTypeComparison(format!(
-                "{l} CONTAINS_ANY {r}",
-            ))),
+            Ok(Value::Array(kept))
         }
+        IterateOp::Map => items.iter().map(body).collect::<Result<_>>().map(Value::Array),
     }
 }
 
+pub(crate) fn matches(value: Value, regex: &Regex) -> Result<Value> {
+    match value {
+        Value::String(s) => Ok(Value::Bool(regex.is_match(&s))),
+        Value::Missing => Ok(Value::Bool(false)),
+        v => Err(Error::UnsupportedTypeComparison(format!(
+            "{v} MATCHES {regex}"
+        ))),
+    }
+}
+
+/// `IN` or `NOT IN` an array of literals, looked up in a hash set instead of compared one by one.
 #[derive(Debug)]
-struct ContainsAll {
-    left: BoxedExpression,
-    right: BoxedExpression,
+struct InSet {
+    value: BoxedExpression,
+    set: LiteralSet,
 }
 
-impl Expression for ContainsAll {
-    fn calculate(&self, json: &[u8]) -> Result<Value> {
-        let left = self.left.calculate(json)?;
-        let right = self.right.calculate(json)?;
-        match (left, right) {
-            (Value::String(s1), Value::String(s2)) => {
-                let b1: Vec<char> = s1.chars().collect();
-                Ok(Value::Bool(s2.chars().all(|b| b1.contains(&b))))
+impl Expression for InSet {
+    fn calculate_document(&self, document: &dyn Document, ctx: &EvalContext) -> Result<Value> {
+        Ok(self.set.find(&self.value.calculate_document(document, ctx)?))
+    }
+}
+
+/// The elements of the array literal of an `IN` or `NOT IN`.
+#[derive(Debug, Default)]
+pub(crate) struct LiteralSet {
+    keys: HashSet<Key>,
+    negated: bool,
+}
+
+impl LiteralSet {
+    /// The set of `right`, `None` if it is not an array literal or an element has no key.
+    pub(crate) fn new(op: BinaryOp, right: &Expr) -> Option<LiteralSet> {
+        let keys = match right {
+            Expr::Array(items) => items
+                .iter()
+                .map(|item| match item {
+                    Expr::Literal(value) => Key::new(value),
+                    _ => None,
+                })
+                .collect::<Option<_>>()?,
+            Expr::Literal(Value::Array(items)) => items.iter().map(Key::new).collect::<Option<_>>()?,
+            _ => return None,
+        };
+        Some(LiteralSet {
+            keys,
+            negated: op == BinaryOp::NotIn,
+        })
+    }
+
+    /// The result of `IN`, or `NOT IN`, for `value`.
+    pub(crate) fn find(&self, value: &Value) -> Value {
+        // values without a key, such as `Missing`, equal none of the elements
+        let found = Key::new(value).is_some_and(|key| self.keys.contains(&key));
+        Value::Bool(found != self.negated)
+    }
+}
+
+/// A `Value` that can be hashed, numbers that are equal have the same key whatever their kind.
+#[derive(Debug, PartialEq, Eq, Hash)]
+enum Key {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(u64),
+    String(String),
+}
+
+impl Key {
+    #[allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+    fn new(value: &Value) -> Option<Key> {
+        match value {
+            Value::Null => Some(Key::Null),
+            Value::Bool(b) => Some(Key::Bool(*b)),
+            Value::Int(i) => Some(Key::Int(*i)),
+            Value::Number(n) if n.is_nan() => None,
+            // i64::MIN is a power of two so the bounds are exact
+            Value::Number(n) if n.fract() == 0.0 && *n >= i64::MIN as f64 && *n < -(i64::MIN as f64) => {
+                Some(Key::Int(*n as i64))
             }
-            (Value::Array(arr1), Value::Array(arr2)) => {
-                Ok(Value::Bool(arr2.iter().all(|v| arr1.contains(v))))
+            Value::Number(n) => Some(Key::Float(n.to_bits())),
+            #[cfg(feature = "decimal")]
+            Value::Decimal(d) => {
+                use rust_decimal::prelude::ToPrimitive;
+
+                // like their comparison, decimals with a fraction equal a float by its value
+                if d.fract().is_zero() {
+                    d.to_i64().map(Key::Int)
+                } else {
+                    Key::new(&Value::Number(d.to_f64()?))
+                }
             }
-            (Value::Array(arr), Value::String(s)) => Ok(Value::Bool(
-                s.chars()
-                    .all(|v| arr.contains(&Value::String(v.to_string()))),
-            )),
-            (Value::String(s), Value::Array(arr)) => Ok(Value::Bool(arr.iter().all(|v| match v {
-                Value::String(s2) => s.contains(s2),
-                _ => false,
-            }))),
-            (l, r) => Err(Error::UnsupportedTypeComparison(format!(
-                "{l} CONTAINS_ALL {r}",
-            ))),
+            Value::String(s) => Some(Key::String(s.clone())),
+            _ => None,
         }
     }
 }
 
 #[derive(Debug)]
-struct StartsWith {
-    left: BoxedExpression,
-    right: BoxedExpression,
+struct Call {
+    builtin: Builtin,
+    args: Vec<BoxedExpression>,
 }
 
-impl Expression for StartsWith {
-    fn calculate(&self, json: &[u8]) -> Result<Value> {
-        let left = self.left.calculate(json)?;
-        let right = self.right.calculate(json)?;
+impl Expression for Call {
+    fn calculate_document(&self, document: &dyn Document, ctx: &EvalContext) -> Result<Value> {
+        let mut args = Vec::with_capacity(self.args.len());
+        for arg in &self.args {
+            args.push(arg.calculate_document(document, ctx)?);
+        }
+        call(&self.builtin, &args)
+    }
+}
 
-        match (left, right) {
-            (Value::String(s1), Value::String(s2)) => Ok(Value::Bool(s1.starts_with(&s2))),
-            (l, r) => Err(Error::UnsupportedTypeComparison(format!("{l} + {r}",))),
+/// Calls a built-in function with its calculated arguments.
+pub(crate) fn call(builtin: &Builtin, args: &[Value]) -> Result<Value> {
+    if !builtin.accepts_null {
+        if args.contains(&Value::Missing) {
+            return Ok(Value::Missing);
+        }
+        if args.contains(&Value::Null) {
+            return Ok(Value::Null);
         }
     }
+    (builtin.function)(args)
 }
 
 #[derive(Debug)]
-struct EndsWith {
-    left: BoxedExpression,
-    right: BoxedExpression,
+struct HostCall {
+    name: String,
+    args: Vec<BoxedExpression>,
 }
 
-impl Expression for EndsWith {
-    fn calculate(&self, json: &[u8]) -> Result<Value> {
-        let left = self.left.calculate(json)?;
-        let right = self.right.calculate(json)?;
-
-        match (left, right) {
-            (Value::String(s1), Value::String(s2)) => Ok(Value::Bool(s1.ends_with(&s2))),
-            (l, r) => Err(Error::UnsupportedTypeComparison(format!("{l} + {r}",))),
+impl Expression for HostCall {
+    fn calculate_document(&self, document: &dyn Document, ctx: &EvalContext) -> Result<Value> {
+        let mut args = Vec::with_capacity(self.args.len());
+        for arg in &self.args {
+            args.push(arg.calculate_document(document, ctx)?);
         }
+        host_call(&self.name, &args, ctx)
     }
 }
 
-#[derive(Debug)]
-struct In {
-    left: BoxedExpression,
-    right: BoxedExpression,
+/// Calls the function `name` of the host with its calculated arguments.
+pub(crate) fn host_call(name: &str, args: &[Value], ctx: &EvalContext) -> Result<Value> {
+    match ctx.functions.and_then(|functions| functions.get(name)) {
+        Some(function) => function(args),
+        None => Err(Error::UnknownFunction(name.to_string())),
+    }
 }
 
-impl Expression for In {
-    fn calculate(&self, json: &[u8]) -> Result<Value> {
-        let left = self.left.calculate(json)?;
-        let right = self.right.calculate(json)?;
+#[derive(Debug)]
+struct Variable {
+    name: String,
+}
 
-        match (left, right) {
-            (v, Value::Array(a)) => Ok(Value::Bool(a.contains(&v))),
-            (l, r) => Err(Error::UnsupportedTypeComparison(format!("{l} + {r}",))),
-        }
+impl Expression for Variable {
+    fn calculate_document(&self, _: &dyn Document, ctx: &EvalContext) -> Result<Value> {
+        ctx.variable(&self.name)
     }
 }
 
@@ -1027,24 +2094,652 @@ struct Arr {
 }
 
 impl Expression for Arr {
-    fn calculate(&self, json: &[u8]) -> Result<Value> {
+    fn calculate_document(&self, document: &dyn Document, ctx: &EvalContext) -> Result<Value> {
         let mut arr = Vec::new();
         for e in &self.arr {
-            arr.push(e.calculate(json)?);
+            arr.push(e.calculate_document(document, ctx)?);
         }
         Ok(Value::Array(arr))
     }
 }
 
+#[derive(Debug)]
+struct Obj {
+    members: Vec<(String, BoxedExpression)>,
+}
+
+impl Expression for Obj {
+    fn calculate_document(&self, document: &dyn Document, ctx: &EvalContext) -> Result<Value> {
+        let mut members = Vec::with_capacity(self.members.len());
+        for (_, value) in &self.members {
+            members.push(value.calculate_document(document, ctx)?);
+        }
+        Ok(object(self.members.iter().map(|(key, _)| key), members))
+    }
+}
+
+/// The object of `keys` and their `values`, without the members whose value is missing.
+pub(crate) fn object<'a>(keys: impl IntoIterator<Item = &'a String>, values: Vec<Value>) -> Value {
+    Value::Object(
+        keys.into_iter()
+            .zip(values)
+            .filter(|(_, value)| *value != Value::Missing)
+            .map(|(key, value)| (key.clone(), value))
+            .collect(),
+    )
+}
+
 /// Result type for the `parse` function.
 pub type Result<T> = std::result::Result<T, Error>;
 
 /// Error type for the expression parser.
-#[derive(Error, Debug, PartialEq, Eq)]
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
 pub enum Error {
     #[error("unsupported type comparison: {0}")]
     UnsupportedTypeComparison(String),
 
     #[error("unsupported COERCE: {0}")]
     UnsupportedCOERCE(String),
-}
\ No newline at end of file
+
+    #[error("unsupported function call: {0}")]
+    UnsupportedFunctionCall(String),
+
+    #[error("unknown function: {0}")]
+    UnknownFunction(String),
+
+    #[error("unknown variable: ${0}")]
+    UnknownVariable(String),
+
+    #[error("division by zero: {0}")]
+    DivisionByZero(String),
+
+    #[error("arithmetic overflow: {0}")]
+    Overflow(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Error, EvalContext, ExprError, ParseOptions, Parser, Value};
+    use crate::functions::FunctionRegistry;
+
+    const DOC: &[u8] = br#"{"a":1,"b":2,"x":1,"y":3,"z":3,"name":"Tom","tags":["a","b"],"flag":true,"date":"2023-01-01 12:00:01"}"#;
+
+    fn eval(expression: &str) -> Value {
+        Parser::parse(expression)
+            .unwrap_or_else(|e| panic!("{expression}: {e}"))
+            .calculate(DOC)
+            .unwrap_or_else(|e| panic!("{expression}: {e}"))
+    }
+
+    /// `assert_eq` that also tells `Int` and `Number` apart, which `Value` equality does not.
+    #[track_caller]
+    fn assert_identical<T: PartialEq + std::fmt::Debug>(left: T, right: T) {
+        assert_eq!(format!("{left:?}"), format!("{right:?}"));
+    }
+
+    #[test]
+    fn precedence() {
+        assert_identical(eval(".a + .b * 2"), Value::Int(5));
+        assert_identical(eval("(.a + .b) * 2"), Value::Int(6));
+        assert_identical(eval(".y - .b - .a"), Value::Int(0));
+        assert_identical(eval(".y * 4 / 2 / 3"), Value::Int(2));
+        assert_eq!(eval(".a + 1 == .b"), Value::Bool(true));
+        assert_eq!(eval(".x == 1 || .y == 2 && .z == 4"), Value::Bool(true));
+        assert_eq!(eval(".x == 2 && .y == 3 || .z == 3"), Value::Bool(true));
+        assert_eq!(eval(".x == 2 && (.y == 3 || .z == 3)"), Value::Bool(false));
+        assert_eq!(eval("!.x == 2 && .flag"), Value::Bool(true));
+        assert_eq!(eval("!(.x == 1 || .flag)"), Value::Bool(false));
+        assert_eq!(eval(".a + .b BETWEEN .a .y + 1"), Value::Bool(true));
+    }
+
+    #[test]
+    fn existing_expressions() {
+        assert_eq!(eval(".name == \"Tom\""), Value::Bool(true));
+        assert_eq!(eval(".name STARTS_WITH \"To\" OR .name ENDS_WITH \"x\""), Value::Bool(true));
+        assert_eq!(eval(".name !STARTS_WITH \"To\""), Value::Bool(false));
+        assert_eq!(eval(".tags CONTAINS \"a\" && .tags CONTAINS_ALL [\"a\",\"b\"]"), Value::Bool(true));
+        assert_eq!(eval(".tags CONTAINS_ANY [\"c\" \"b\"]"), Value::Bool(true));
+        assert_eq!(eval(".name IN [\"Tom\", \"Ann\"]"), Value::Bool(true));
+        assert_eq!(eval(".name !IN [\"Tom\", \"Ann\"]"), Value::Bool(false));
+        assert_eq!(eval(".a BETWEEN 0 2"), Value::Bool(true));
+        assert_eq!(eval(".a >= 1 && .a <= 1 && .a < 2 && .a > 0"), Value::Bool(true));
+        assert_eq!(eval("COERCE .date _datetime_ > COERCE \"2022-12-31\" _datetime_"), Value::Bool(true));
+        assert_eq!(eval("COERCE .name _lowercase_,_uppercase_"), Value::String("TOM".to_string()));
+        assert_identical(eval("COERCE \"2\" _number_ + .a"), Value::Int(3));
+        assert_eq!(eval(".missing == NULL"), Value::Bool(false));
+        assert_eq!(eval("!.flag"), Value::Bool(false));
+        assert_identical(eval(".a + -1"), Value::Int(0));
+    }
+
+    #[test]
+    fn negations_and_presence() {
+        const OPTIONAL: &[u8] = br#"{"name":"Tom","tags":["a","b"],"deleted_at":null}"#;
+        let eval = |expression: &str| Parser::parse(expression).unwrap().calculate(OPTIONAL).unwrap();
+
+        assert_eq!(eval(".name != \"Ann\""), Value::Bool(true));
+        assert_eq!(eval(".name !== \"Tom\""), Value::Bool(false));
+        assert_eq!(eval(".name NOT IN [\"Ann\", \"Bob\"]"), Value::Bool(true));
+        assert_eq!(eval(".tags NOT CONTAINS \"a\""), Value::Bool(false));
+        assert_eq!(eval(".name NOT   CONTAINS \"x\""), Value::Bool(true));
+        assert_eq!(eval(".deleted_at IS NULL"), Value::Bool(true));
+        assert_eq!(eval(".name IS NOT NULL && .name != NULL"), Value::Bool(true));
+        assert_eq!(eval(".deleted_at IS MISSING"), Value::Bool(false));
+        assert_eq!(eval(".age IS MISSING"), Value::Bool(true));
+        assert_eq!(eval(".name IS NOT MISSING"), Value::Bool(true));
+        assert_eq!(eval("EXISTS .deleted_at && !EXISTS .age"), Value::Bool(true));
+        assert_eq!(eval("EXISTS .tags.1 || .x IS NULL"), Value::Bool(true));
+
+        assert!(Parser::parse("EXISTS \"a\"").is_err());
+        assert!(Parser::parse("(.a) IS MISSING").is_err());
+        assert_eq!(eval(".tags NOT CONTAINS_ANY [\"x\", \"y\"]"), Value::Bool(true));
+        assert!(Parser::parse(".a NOT AND .b").is_err());
+        assert!(Parser::parse(".a IS").is_err());
+    }
+
+    #[test]
+    fn iterate() {
+        const ORDER: &[u8] = br#"{"items":[{"sku":"a1","qty":2,"price":12.5},{"sku":"b7","qty":7,"price":3}],"tags":["xmas","xl"],"none":null}"#;
+        let eval = |expression: &str| Parser::parse(expression).unwrap().calculate(ORDER).unwrap();
+
+        assert_eq!(eval("ANY(.items, .qty > 5)"), Value::Bool(true));
+        assert_eq!(eval("ALL(.items, .qty > 5)"), Value::Bool(false));
+        assert_eq!(eval("ALL(.tags, . STARTS_WITH \"x\")"), Value::Bool(true));
+        assert_eq!(eval("any(.items, .sku == 'b7') and all([], false)"), Value::Bool(true));
+        assert_eq!(eval("ANY(.missing, true) || ANY(.none, true) || ALL(.missing, true)"), Value::Bool(false));
+        assert_eq!(
+            eval(".items FILTER (.price > 10) MAP (.sku)"),
+            Value::Array(vec![Value::String("a1".to_string())])
+        );
+        assert_identical(
+            eval(".items MAP (.price * .qty)"),
+            Value::Array(vec![Value::Number(25.0), Value::Int(21)])
+        );
+        assert_eq!(eval("sum(.items MAP (.qty)) == 9 && size(.items FILTER (.qty > 1)) == 2"), Value::Bool(true));
+        assert_eq!(eval(".items MAP (.qty) CONTAINS 7"), Value::Bool(true));
+        assert_identical(eval("[1, 2, 3] MAP (. * 10) FILTER (. > 10)"), Value::Array(vec![Value::Int(20), Value::Int(30)]));
+        assert_eq!(eval(".missing MAP (.qty)"), Value::Missing);
+
+        assert!(Parser::parse("ANY(.tags, . == 'x') MAP (.)").unwrap().calculate(ORDER).is_err());
+        assert_eq!(
+            Parser::parse("ANY(.items, $sku == .sku)").unwrap().calculate(ORDER),
+            Err(Error::UnknownVariable("sku".to_string()))
+        );
+        assert!(Parser::parse(".items FILTER .qty > 1").is_err());
+        assert!(Parser::parse("ANY(.items)").is_err());
+        assert!(Parser::parse("ALL .items, true").is_err());
+    }
+
+    #[test]
+    fn conditionals() {
+        assert_eq!(eval("CASE WHEN .a > 1 THEN 'big' WHEN .a == 1 THEN 'one' ELSE 'small' END"), Value::String("one".to_string()));
+        assert_eq!(eval("CASE WHEN .a > 1 THEN 'big' END"), Value::Null);
+        assert_identical(eval("case when .missing then 1 when .flag then .b * 10 end"), Value::Int(20));
+        assert_identical(eval("CASE WHEN false THEN 1 / 0 ELSE .y END + 1"), Value::Int(4));
+        assert_eq!(eval(".a == 1 ? 'one' : 'other'"), Value::String("one".to_string()));
+        assert_identical(eval(".a > 1 || .b > 1 ? .x : .y"), Value::Int(1));
+        assert_eq!(eval(".a > 1 ? 'a' : .b > 5 ? 'b' : 'c'"), Value::String("c".to_string()));
+        assert_identical(eval(".flag ? (.a == 1 ? .x + 1 : 0) : -1"), Value::Int(2));
+        assert_eq!(eval("coalesce(.missing ? 1 : NULL, .name)"), Value::String("Tom".to_string()));
+        assert_identical(eval("[(false ? 'x' : 'y'), 2]"), Value::Array(vec![Value::String("y".to_string()), Value::Int(2)]));
+
+        // a condition must be a boolean, or missing
+        for expression in ["CASE WHEN .a THEN 1 ELSE 2 END", ".name ? 1 : 2", "CASE WHEN .missing THEN 1 WHEN NULL THEN 2 END"] {
+            assert!(
+                matches!(Parser::parse(expression).unwrap().calculate(DOC), Err(Error::UnsupportedTypeComparison(_))),
+                "{expression}"
+            );
+        }
+
+        assert!(Parser::parse("CASE .a THEN 1 END").is_err());
+        assert!(Parser::parse("CASE WHEN .a THEN 1").is_err());
+        assert!(Parser::parse("CASE WHEN .a THEN 1 ELSE 2").is_err());
+        assert!(Parser::parse("CASE END").is_err());
+        assert!(Parser::parse(".a ? 1").is_err());
+        assert!(Parser::parse(".a ? 1 2").is_err());
+    }
+
+    #[test]
+    fn objects() {
+        let object = |members: &[(&str, Value)]| {
+            Value::Object(members.iter().map(|(k, v)| ((*k).to_string(), v.clone())).collect())
+        };
+
+        assert_identical(
+            eval("{ \"sum\": .a + .b, 'name': upper(.name), \"missing\": .missing, \"flag\": .flag }"),
+            object(&[
+                ("sum", Value::Int(3)),
+                ("name", Value::String("TOM".to_string())),
+                ("flag", Value::Bool(true)),
+            ])
+        );
+        assert_eq!(eval("{}"), object(&[]));
+        assert_identical(
+            eval("{'nested': {'n': .x * 10, 'tags': .tags MAP ({'tag': .})},}"),
+            object(&[(
+                "nested",
+                object(&[
+                    ("n", Value::Int(10)),
+                    (
+                        "tags",
+                        Value::Array(vec![
+                            object(&[("tag", Value::String("a".to_string()))]),
+                            object(&[("tag", Value::String("b".to_string()))]),
+                        ])
+                    ),
+                ])
+            )])
+        );
+        assert_eq!(
+            serde_json::to_string(&eval("{'total': .a * 2.5, 'at': [.a + .b, .y > 2 ? 'big' : 'small']}")).unwrap(),
+            r#"{"at":[3,"big"],"total":2.5}"#
+        );
+        assert_identical(eval("[.a + 1 .b * 2, -.y]"), Value::Array(vec![Value::Int(2), Value::Int(4), Value::Int(-3)]));
+        assert_identical(eval("[false ? 'x' : 'y', 2]"), Value::Array(vec![Value::String("y".to_string()), Value::Int(2)]));
+
+        assert!(Parser::parse("{'a': 1, 'a': 2}").is_err());
+        assert!(Parser::parse("{'a' 1}").is_err());
+        assert!(Parser::parse("{a: 1}").is_err());
+        assert!(Parser::parse("{'a': 1").is_err());
+        assert!(Parser::parse("[1, 2").is_err());
+    }
+
+    #[test]
+    fn keywords() {
+        assert_eq!(eval(".a == 1 and not .b == 1 AND .name in ['Tom']"), Value::Bool(true));
+        assert_eq!(eval(".x == 2 Or .name Is Not Null"), Value::Bool(true));
+        assert_eq!(eval("NOT .flag OR .b div 2 == 1"), Value::Bool(true));
+        assert_eq!(eval(".name IN['Tom'] && .tags CONTAINS('a')"), Value::Bool(true));
+        assert_eq!(eval("TRUE && exists .a && .b IS NOT NULL"), Value::Bool(true));
+        assert_eq!(
+            eval(".a == 1 # the first\n  && .b == 2 #the second\n# and the end"),
+            Value::Bool(true)
+        );
+        assert_identical(eval(".tags.#"), Value::Int(2));
+
+        let long = "x".repeat(70_000);
+        assert_identical(eval(&format!("len('{long}')")), Value::Int(70_000));
+    }
+
+    #[test]
+    fn missing() {
+        const OPTIONAL: &[u8] = br#"{"a":1,"deleted_at":null,"flag":true}"#;
+        let eval = |expression: &str| Parser::parse(expression).unwrap().calculate(OPTIONAL).unwrap();
+
+        assert_eq!(eval(".b"), Value::Missing);
+        assert_eq!(eval(".b").to_string(), "missing");
+        assert_eq!(serde_json::to_string(&eval("[.a .b]")).unwrap(), "[1,null]");
+        assert_eq!(eval(".deleted_at == NULL"), Value::Bool(true));
+        assert_eq!(eval(".b == NULL"), Value::Bool(false));
+        assert_eq!(eval(".b != NULL"), Value::Bool(true));
+        assert_eq!(eval(".b == .c"), Value::Bool(true));
+        assert_eq!(eval(".b IS NULL || .b IS NOT NULL"), Value::Bool(false));
+        assert_eq!(eval(".b > 1 || .b <= 1 || .b BETWEEN 0 2"), Value::Bool(false));
+        assert_eq!(eval(".b CONTAINS \"x\" || .b STARTS_WITH \"x\" || .b IN [1]"), Value::Bool(false));
+        assert_eq!(eval(".b NOT IN [1] && .b NOT CONTAINS \"x\""), Value::Bool(true));
+        assert_eq!(eval(".b + 1"), Value::Missing);
+        assert_eq!(eval(".a * .b > 0"), Value::Bool(false));
+        assert_eq!(eval(".b || .flag"), Value::Bool(true));
+        assert_eq!(eval(".b && .flag"), Value::Bool(false));
+        assert_eq!(eval("!.b"), Value::Bool(true));
+        assert_eq!(eval("COERCE .b _string_,_uppercase_"), Value::Missing);
+
+        let legacy = |expression: &str| {
+            Parser::parse_with_options(expression, ParseOptions { missing_as_null: true })
+                .unwrap()
+                .calculate(OPTIONAL)
+                .unwrap()
+        };
+        assert_eq!(legacy(".b"), Value::Null);
+        assert_eq!(legacy(".b == NULL && .b IS NULL"), Value::Bool(true));
+        assert_identical(legacy(".b + 1"), Value::Int(1));
+        assert_eq!(legacy(".b IS MISSING"), Value::Bool(true));
+    }
+
+    #[test]
+    fn patterns() {
+        assert_eq!(eval(".name LIKE \"T*\""), Value::Bool(true));
+        assert_eq!(eval(".name LIKE \"t*\""), Value::Bool(false));
+        assert_eq!(eval(".name ILIKE \"t?M\""), Value::Bool(true));
+        assert_eq!(eval(".name LIKE \"T?\""), Value::Bool(false));
+        assert_eq!(eval(".name !LIKE \"*x\" && .missing LIKE \"*\" == false"), Value::Bool(true));
+        assert_eq!(eval(".date MATCHES \"^\\d{4}-\\d{2}-\\d{2} \""), Value::Bool(true));
+        assert_eq!(eval(".name MATCHES \"^(?i)tom$\" || .name MATCHES \"x\""), Value::Bool(true));
+        assert_eq!(eval(".name !MATCHES \"o\""), Value::Bool(false));
+        assert!(Parser::parse(".name LIKE \"T*\"").unwrap().calculate(br#"{"name":1}"#).is_err());
+
+        assert!(Parser::parse(".name MATCHES \"(\"").is_err());
+        assert!(Parser::parse(".name MATCHES .pattern").is_err());
+        assert!(Parser::parse(".name MATCHES").is_err());
+    }
+
+    #[test]
+    fn functions() {
+        const DOC: &[u8] = br#"{"name":"  Tom Hanks ","n":-2.567,"scores":[3,1,2],"tags":["a","b"],"nick":null,"user":{"a":1,"b":2}}"#;
+        let eval = |expression: &str| {
+            Parser::parse(expression)
+                .unwrap_or_else(|e| panic!("{expression}: {e}"))
+                .calculate(DOC)
+                .unwrap_or_else(|e| panic!("{expression}: {e}"))
+        };
+        let string = |s: &str| Value::String(s.to_string());
+
+        assert_identical(eval("len(trim(.name))"), Value::Int(9));
+        assert_eq!(eval("upper(trim(.name)) == \"TOM HANKS\""), Value::Bool(true));
+        assert_eq!(eval("lower(substr(trim(.name), 4))"), string("hanks"));
+        assert_eq!(eval("substr(trim(.name), 0, 3)"), string("Tom"));
+        assert_eq!(eval("split(trim(.name), \" \")"), Value::Array(vec![string("Tom"), string("Hanks")]));
+        assert_eq!(eval("concat(\"a\", 1, \"b\")"), string("a1b"));
+        assert_eq!(eval("replace(.name, \" \", \"\")"), string("TomHanks"));
+        assert_identical(eval("abs(.n) + floor(.n) + ceil(.n)"), Value::Number(-2.433));
+        assert_identical(eval("round(.n)"), Value::Number(-3.0));
+        assert_identical(eval("round(.n, 2)"), Value::Number(-2.57));
+        assert_identical(eval("min(3, .n, 1)"), Value::Number(-2.567));
+        assert_identical(eval("max(.scores)"), Value::Int(3));
+        assert_eq!(eval("max(\"a\", \"c\", \"b\")"), string("c"));
+        assert_identical(eval("size(.scores) + size(.user)"), Value::Int(5));
+        assert_eq!(eval("first(.tags)"), string("a"));
+        assert_eq!(eval("last(.tags)"), string("b"));
+        assert_eq!(eval("first([])"), Value::Null);
+        assert_eq!(eval("sum(.scores) == 6 && avg(.scores) == 2"), Value::Bool(true));
+        assert_eq!(eval("avg([])"), Value::Null);
+        assert_eq!(eval("coalesce(.nick, .nope, \"anon\")"), string("anon"));
+        assert_eq!(eval("coalesce(.nick)"), Value::Null);
+        assert_eq!(eval("lower(.nope)"), Value::Missing);
+        assert_eq!(eval("lower(.nick)"), Value::Null);
+        assert_eq!(eval("len(COERCE .n _string_) > 2 && first(.tags) IN [\"a\"]"), Value::Bool(true));
+
+        assert!(Parser::parse("upper(.name)").unwrap().calculate(br#"{"name":1}"#).is_err());
+        assert!(Parser::parse("substr(\"a\", -1)").unwrap().calculate(DOC).is_err());
+    }
+
+    #[test]
+    fn context() {
+        let mut functions = FunctionRegistry::new();
+        functions.register("is_business_day", |args| match args {
+            [Value::String(date)] => Ok(Value::Bool(!date.ends_with("-07"))),
+            _ => Err(Error::UnsupportedFunctionCall("is_business_day".to_string())),
+        });
+        // built-in functions can't be replaced
+        functions.register("lower", |_| Ok(Value::Null));
+        let ctx = EvalContext::new()
+            .with_functions(&functions)
+            .with_variable("user_id", Value::Number(7.0))
+            .with_variable("names", Value::Array(vec![Value::String("Tom".to_string())]));
+
+        let eval = |expression: &str, json: &[u8]| Parser::parse(expression).unwrap().calculate_with(json, &ctx);
+        let doc = br#"{"owner":7,"name":"Tom","date":"2024-01-08"}"#;
+        assert_eq!(eval(".owner == $user_id && .name IN $names", doc), Ok(Value::Bool(true)));
+        assert_eq!(eval("is_business_day(.date)", doc), Ok(Value::Bool(true)));
+        assert_eq!(eval("is_business_day(\"2024-01-07\") || lower(.name) == \"tom\"", doc), Ok(Value::Bool(true)));
+        assert_eq!(eval("$nope == 1", doc), Err(Error::UnknownVariable("nope".to_string())));
+        assert_eq!(eval("$nope", doc).unwrap_err().to_string(), "unknown variable: $nope");
+        assert_eq!(eval("is_business_day(1)", doc), Err(Error::UnsupportedFunctionCall("is_business_day".to_string())));
+        assert_eq!(eval("nope()", doc), Err(Error::UnknownFunction("nope".to_string())));
+
+        let expression = Parser::parse("is_business_day(.date) && $user_id == 7").unwrap();
+        assert_eq!(expression.calculate(doc), Err(Error::UnknownFunction("is_business_day".to_string())));
+        assert!(Parser::parse("$").is_err());
+    }
+
+    #[test]
+    fn dates() {
+        let now = anydate::parse_utc("2024-03-13 15:45:30").unwrap();
+        let ctx = EvalContext::new().with_now(now);
+        const DOC: &[u8] = br#"{"created":"2024-03-07 09:00:00","expires":"2024-03-14 15:45:30"}"#;
+        let eval = |expression: &str| {
+            Parser::parse(expression)
+                .unwrap_or_else(|e| panic!("{expression}: {e}"))
+                .calculate_with(DOC, &ctx)
+                .unwrap_or_else(|e| panic!("{expression}: {e}"))
+        };
+        let datetime = |s: &str| Value::DateTime(anydate::parse(s).unwrap());
+
+        assert_eq!(eval("now()"), Value::DateTime(now.fixed_offset()));
+        assert_eq!(eval("7d"), Value::Duration(chrono::Duration::days(7)));
+        assert_eq!(eval("1h30m + 30m == 2h"), Value::Bool(true));
+        assert_eq!(eval("-1h30m").to_string(), "\"-1h30m\"");
+        assert_eq!(eval("now() - 1d"), datetime("2024-03-12 15:45:30"));
+        assert_eq!(eval("2h + now()"), datetime("2024-03-13 17:45:30"));
+        assert_eq!(eval("COERCE .created _datetime_ > now() - 7d"), Value::Bool(true));
+        assert_eq!(eval("COERCE .created _datetime_ > now() - 6d"), Value::Bool(false));
+        assert_eq!(eval("COERCE .expires _datetime_ - now() == 1d"), Value::Bool(true));
+        assert_eq!(eval("COERCE .expires _datetime_ - now() BETWEEN 23h 25h"), Value::Bool(true));
+        assert_eq!(eval("COERCE (now() - COERCE .created _datetime_) _string_"), Value::String("6d6h45m30s".to_string()));
+        assert_identical(eval("year(.created) * 100 + month(.created)"), Value::Int(202_403));
+        assert_identical(eval("day(.created) + hour(.created) + minute(now())"), Value::Int(61));
+        assert_eq!(eval("day_of_week(.created) == 4 && day_of_week(\"2024-03-10\") == 7"), Value::Bool(true));
+        assert_eq!(eval("date_trunc('day', now())"), datetime("2024-03-13 00:00:00"));
+        assert_eq!(eval("date_trunc('week', now())"), datetime("2024-03-11 00:00:00"));
+        assert_eq!(eval("date_trunc('month', .created)"), datetime("2024-03-01 00:00:00"));
+        assert_eq!(eval("date_trunc('year', now())"), datetime("2024-01-01 00:00:00"));
+        assert_eq!(eval("date_trunc('hour', now())"), datetime("2024-03-13 15:00:00"));
+
+        assert!(Parser::parse("7x").is_err());
+        assert!(Parser::parse("date_trunc('century', now())").unwrap().calculate(DOC).is_err());
+        assert!(Parser::parse("year(\"not a date\")").unwrap().calculate(DOC).is_err());
+        assert!(Parser::parse("now() + 1").unwrap().calculate(DOC).is_err());
+    }
+
+    #[test]
+    fn time_zones() {
+        const DOC: &[u8] = br#"{"utc":"2024-03-08T01:30:00Z","local":"2024-03-08 09:30:00","offset":"2024-03-08T09:30:00+08:00","us":"03/09/2024 02:30","summer":"2024-07-01 12:00"}"#;
+        let eval = |expression: &str| {
+            Parser::parse(expression)
+                .unwrap_or_else(|e| panic!("{expression}: {e}"))
+                .calculate(DOC)
+                .unwrap_or_else(|e| panic!("{expression}: {e}"))
+        };
+        let string = |s: &str| Value::String(s.to_string());
+
+        assert_eq!(eval("COERCE .offset _datetime_,_string_"), string("2024-03-08T09:30:00+08:00"));
+        assert_eq!(eval("COERCE .local _datetime_,_string_"), string("2024-03-08T09:30:00Z"));
+        assert_eq!(eval("COERCE .local _datetime_('+08:00'),_string_"), string("2024-03-08T09:30:00+08:00"));
+        assert_eq!(eval("COERCE .utc _datetime_('Asia/Kuala_Lumpur'),_string_"), string("2024-03-08T09:30:00+08:00"));
+        assert_eq!(eval("COERCE .us _datetime_('%m/%d/%Y %H:%M', 'America/New_York'),_string_"), string("2024-03-09T02:30:00-05:00"));
+        assert_eq!(eval("COERCE \"2024-03-10 02:30\" _datetime_('America/New_York')"), Value::Null);
+        assert_eq!(eval("COERCE .summer _datetime_('America/New_York'),_string_"), string("2024-07-01T12:00:00-04:00"));
+        assert_eq!(eval("COERCE \"08/03/2024\" _datetime_('%d/%m/%Y'),_string_"), string("2024-03-08T00:00:00Z"));
+        assert_eq!(eval("COERCE \"2024-03-08 09:30 +0530\" _datetime_('%Y-%m-%d %H:%M %z', '-0100'),_string_"), string("2024-03-08T03:00:00-01:00"));
+        assert_eq!(eval("COERCE .local _datetime_('%d/%m/%Y')"), Value::Null);
+
+        // comparisons are by instant, whatever the offset
+        assert_eq!(eval("COERCE .utc _datetime_ == COERCE .offset _datetime_"), Value::Bool(true));
+        assert_eq!(eval("COERCE .local _datetime_('+08:00') == COERCE .utc _datetime_"), Value::Bool(true));
+        assert_eq!(eval("COERCE .local _datetime_ > COERCE .offset _datetime_"), Value::Bool(true));
+        assert_eq!(eval("COERCE .offset _datetime_ - COERCE .utc _datetime_"), Value::Duration(chrono::Duration::zero()));
+
+        // parts are those of the local time
+        assert_identical(eval("day_of_week(COERCE .utc _datetime_('Pacific/Kiritimati'))"), Value::Int(5));
+        assert_identical(eval("day_of_week(COERCE .utc _datetime_('America/Los_Angeles'))"), Value::Int(4));
+        assert_eq!(eval("hour(COERCE now() _datetime_('+05:45')) == hour(COERCE now() _datetime_('Asia/Kathmandu'))"), Value::Bool(true));
+        assert_eq!(eval("COERCE date_trunc('day', .offset) _string_"), string("2024-03-08T00:00:00+08:00"));
+
+        assert!(Parser::parse("COERCE .utc _datetime_('Mars/Olympus')").is_err());
+        assert!(Parser::parse("COERCE .utc _datetime_('+8')").is_err());
+        assert!(Parser::parse("COERCE .utc _datetime_('UTC', 'Z')").is_err());
+        assert!(Parser::parse("COERCE .utc _datetime_(8)").is_err());
+        assert!(Parser::parse("COERCE .utc _datetime_('UTC'").is_err());
+    }
+
+    #[test]
+    fn numbers() {
+        let eval = |expression: &str| {
+            Parser::parse(expression)
+                .unwrap()
+                .calculate(br#"{"id":9007199254740993,"other":9007199254740992,"cents":1999,"price":19.99,"max":9223372036854775807}"#)
+                .unwrap()
+        };
+
+        // 64-bit ids compare exactly, unlike their f64 approximations
+        assert_eq!(eval(".id == 9007199254740993"), Value::Bool(true));
+        assert_eq!(eval(".id == .other"), Value::Bool(false));
+        assert_eq!(eval(".id > .other"), Value::Bool(true));
+        assert_identical(eval(".id - .other"), Value::Int(1));
+        assert_eq!(eval("COERCE .id _string_"), Value::String("9007199254740993".to_string()));
+        assert_eq!(eval("COERCE \"9007199254740993\" _number_ == .id"), Value::Bool(true));
+
+        // integers and floats compare by value
+        assert_eq!(eval(".cents / 100 == 19.99"), Value::Bool(true));
+        assert_eq!(eval("2 == 2.0 && 2 < 2.5 && 3 > 2.5"), Value::Bool(true));
+        assert_eq!(eval(".other == 9007199254740992.0 && .id > 9007199254740992.0"), Value::Bool(true));
+        assert_eq!(eval(".price BETWEEN 19 20"), Value::Bool(true));
+
+        assert!(matches!(eval(".cents * 2 + 2"), Value::Int(4000)));
+        assert!(matches!(eval(".cents / 1999"), Value::Int(1)));
+        assert!(matches!(eval(".cents / 2"), Value::Number(n) if n == 999.5));
+        assert!(matches!(eval(".cents + .price"), Value::Number(_)));
+        assert!(matches!(eval(".max + 1"), Value::Number(n) if n == 9_223_372_036_854_775_808.0));
+        assert!(matches!(eval("sum([1, 2, 3])"), Value::Int(6)));
+        assert!(matches!(eval("avg([1, 2])"), Value::Number(n) if n == 1.5));
+        assert!(matches!(eval("max(1, 2.5, 2)"), Value::Number(n) if n == 2.5));
+        assert!(matches!(eval("len('abc')"), Value::Int(3)));
+        assert_eq!(serde_json::to_string(&eval(".id")).unwrap(), "9007199254740993");
+    }
+
+    #[test]
+    fn arithmetic_operators() {
+        let eval = |expression: &str| {
+            Parser::parse(expression)
+                .unwrap_or_else(|e| panic!("{expression}: {e}"))
+                .calculate(br#"{"a":7,"b":2,"balance":-12.5,"zero":0}"#)
+                .unwrap_or_else(|e| panic!("{expression}: {e}"))
+        };
+
+        assert_eq!(eval(".a % 2 == 1 && .a % .b == 1"), Value::Bool(true));
+        assert_identical(eval("-.a % 3"), Value::Int(-1));
+        assert_identical(eval("7.5 % 2"), Value::Number(1.5));
+        assert_identical(eval(".a DIV .b"), Value::Int(3));
+        assert_identical(eval("-7 DIV 2"), Value::Int(-3));
+        assert_identical(eval("7.5 DIV 2"), Value::Number(3.0));
+        assert_identical(eval(".b ** 10"), Value::Int(1024));
+        assert_identical(eval(".b ^ 3 ^ 2"), Value::Int(512));
+        assert_identical(eval(".b ** -1"), Value::Number(0.5));
+        assert_identical(eval("4 ** 0.5"), Value::Number(2.0));
+        assert!(matches!(eval("2 ** 64"), Value::Number(_)));
+        assert_identical(eval(".a * .b ** 2 % 5"), Value::Int(3));
+
+        // unary minus and plus
+        assert_identical(eval("-.balance"), Value::Number(12.5));
+        assert_identical(eval("-(.a + .b)"), Value::Int(-9));
+        assert_identical(eval("- -.a"), Value::Int(7));
+        assert_identical(eval("+.a"), Value::Int(7));
+        assert_identical(eval("-.a ** 2"), Value::Int(-49));
+        assert_identical(eval("-2 ** 2"), Value::Int(-4));
+        assert_identical(eval("(-2) ** 2"), Value::Int(4));
+        assert_identical(eval("-.a * 2"), Value::Int(-14));
+        assert_identical(eval(".a - 1"), Value::Int(6));
+        assert_identical(eval("3-1"), Value::Int(2));
+        assert_identical(eval(".a - -1"), Value::Int(8));
+        assert_eq!(eval("-.missing"), Value::Missing);
+        assert_eq!(eval("-1d + 1d"), Value::Duration(chrono::Duration::zero()));
+        assert_identical(eval("-9223372036854775808"), Value::Int(i64::MIN));
+        assert!(matches!(eval("-9223372036854775808"), Value::Int(i64::MIN)));
+        assert!(matches!(eval("-(-9223372036854775808)"), Value::Number(_)));
+        assert_identical(eval("(-9223372036854775807 - 1) % -1"), Value::Int(0));
+        assert_eq!(eval(".balance BETWEEN -13 -12"), Value::Bool(true));
+        assert_eq!(eval("[-1, 2] CONTAINS -1"), Value::Bool(true));
+        assert_identical(eval("1e-3 * 1000"), Value::Number(1.0));
+
+        // division by zero is an error instead of inf or NaN
+        let by_zero = [".a / .zero", ".a % 0", ".a DIV 0.0", "1.5 / 0", "0 / 0", "0 ** -1", "0.0 ** -2"];
+        for expression in by_zero {
+            assert!(
+                matches!(
+                    Parser::parse(expression).unwrap().calculate(br#"{"a":7,"zero":0}"#),
+                    Err(Error::DivisionByZero(_))
+                ),
+                "{expression}"
+            );
+        }
+        assert!(Parser::parse("-'a'").unwrap().calculate(DOC).is_err());
+        assert!(Parser::parse(".a **").is_err());
+        assert!(Parser::parse("-").is_err());
+        assert_identical(eval(".a -1"), Value::Int(6));
+        assert_identical(eval("2 -1"), Value::Int(1));
+        assert_identical(eval("(.a) -1"), Value::Int(6));
+        assert_identical(eval(".a -.b +1"), Value::Int(6));
+        assert_eq!(eval(".a BETWEEN 1 -1 +9"), Value::Bool(true));
+        assert_eq!(eval(".a BETWEEN (.b -3) (.a +1)"), Value::Bool(true));
+    }
+
+    #[cfg(feature = "decimal")]
+    #[test]
+    fn decimals() {
+        let eval = |expression: &str| Parser::parse(expression).unwrap().calculate(br#"{"price":"0.1","qty":3}"#).unwrap();
+
+        assert_eq!(eval("COERCE .price _decimal_ * .qty == COERCE \"0.3\" _decimal_"), Value::Bool(true));
+        assert_eq!(eval("COERCE .price _number_ * .qty == 0.3"), Value::Bool(false));
+        assert_eq!(eval("COERCE (COERCE .price _decimal_ + 0.2) _string_"), Value::String("0.30000000000000004".to_string()));
+        assert_eq!(eval("COERCE (COERCE .price _decimal_ + COERCE 0.2 _decimal_) _string_"), Value::String("0.3".to_string()));
+        assert_eq!(eval("round((COERCE \"2.345\" _decimal_), 2) == COERCE \"2.34\" _decimal_"), Value::Bool(true));
+        assert_eq!(serde_json::to_string(&eval("COERCE .price _decimal_")).unwrap(), "\"0.1\"");
+        assert_eq!(eval("COERCE .price _decimal_ ** 2 == COERCE \"0.01\" _decimal_"), Value::Bool(true));
+        assert!(matches!(
+            Parser::parse("COERCE .price _decimal_ % 0").unwrap().calculate(br#"{"price":"0.1"}"#),
+            Err(Error::DivisionByZero(_))
+        ));
+        assert!(matches!(
+            Parser::parse("COERCE \"0\" _decimal_ ** -1").unwrap().calculate(b"{}"),
+            Err(Error::DivisionByZero(_))
+        ));
+        assert!(matches!(
+            Parser::parse("COERCE \"79228162514264337593543950335\" _decimal_ * 2").unwrap().calculate(b"{}"),
+            Err(Error::Overflow(_))
+        ));
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(Parser::parse("").is_err());
+        assert!(Parser::parse(".a ==").is_err());
+        assert!(Parser::parse("(.a == 1").is_err());
+        assert!(Parser::parse(".a == 1)").is_err());
+        assert!(Parser::parse(".a == 1 2").is_err());
+        assert!(Parser::parse(".a !&& .b").is_err());
+        assert!(Parser::parse("nope(.a)").unwrap().calculate(DOC).is_err());
+        assert!(Parser::parse("lower").is_err());
+        assert!(Parser::parse("lower(.a").is_err());
+        assert!(Parser::parse("lower(.a, .b)").is_err());
+        assert!(Parser::parse("substr(.a)").is_err());
+    }
+
+    #[test]
+    fn error_spans() {
+        use crate::error::ExprErrorKind;
+        use crate::lexer;
+
+        let error = |expression: &str| Parser::parse_expr(expression).unwrap_err();
+
+        let e = error("");
+        assert_eq!((e.kind, e.span, e.expected), (ExprErrorKind::UnexpectedEnd, 0..0, vec!["a value"]));
+        let e = error(".a == 1 2");
+        assert_eq!((e.kind, e.span, e.expected), (ExprErrorKind::UnexpectedToken("2".to_string()), 8..9, vec!["an operator"]));
+        let e = error("(.a == 1 .b");
+        assert_eq!((e.span, e.expected), (9..11, vec!["`)`"]));
+        let e = error("lower(.a .b)");
+        assert_eq!((e.span, e.expected), (9..11, vec!["`,`", "`)`"]));
+        let e = error(".a MATCHES .b");
+        assert_eq!((e.span, e.expected), (11..13, vec!["a quoted string"]));
+        let e = error("EXISTS 1");
+        assert_eq!((e.span, e.expected), (7..8, vec!["a selector path"]));
+        let e = error(".a == 1 && .b ; 2");
+        assert_eq!((e.kind, e.span), (ExprErrorKind::Lex(lexer::Error::UnsupportedCharacter(';')), 14..15));
+        let e = error(".a == é");
+        assert_eq!(e.to_string(), "Unsupported Character `é` at 6..8");
+        assert_eq!((e.kind, e.span), (ExprErrorKind::Lex(lexer::Error::UnsupportedCharacter('é')), 6..8));
+        let e = error(".a == 'Tom && .b");
+        assert_eq!((e.kind, e.span), (ExprErrorKind::Lex(lexer::Error::UnterminatedString('\'')), 6..7));
+        let e = error(".a ISNT NULL");
+        assert_eq!((e.kind, e.span), (ExprErrorKind::UnexpectedToken("ISNT".to_string()), 3..7));
+        assert_eq!(error("substr(.a) == 'b'").span, 0..10);
+        assert_eq!(error("COERCE .a _datetime_('Mars/Olympus')").span, 21..35);
+        assert_eq!(error(".a MATCHES '('").span, 11..14);
+
+        assert_eq!(
+            error(".age >= 18 && (.name == 'Tom' .admin)").render(".age >= 18 && (.name == 'Tom' .admin)"),
+            "unexpected `.admin` at 30..36, expected `)`\n  |\n1 | .age >= 18 && (.name == 'Tom' .admin)\n  |                               ^^^^^^"
+        );
+        let e = Parser::parse(".a == )").err().unwrap();
+        assert_eq!(e.downcast_ref::<ExprError>().map(|e| e.span.clone()), Some(6..7));
+    }
+}
@@ -0,0 +1,413 @@
+use std::cmp::Ordering;
+use chrono::{DateTime, Datelike, FixedOffset, NaiveTime, TimeZone, Timelike};
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+use crate::exp_parser::{Error, Result, Value};
+use crate::number::{self, Op};
+
+/// A function callable from an expression as `name(args...)`.
+pub type Function = fn(&[Value]) -> Result<Value>;
+
+/// A function registered by the host in a `FunctionRegistry`.
+pub type HostFunction = dyn Fn(&[Value]) -> Result<Value> + Send + Sync;
+
+/// Functions of the host application, callable from expressions evaluated with an
+/// `EvalContext` that uses the registry.
+///
+/// Unlike built-in functions they are resolved when the expression is calculated, so an
+/// expression calling an unregistered function only fails then. Their arguments are handed
+/// over as they are, including `Null` and `Missing`.
+#[derive(Default)]
+pub struct FunctionRegistry {
+    functions: HashMap<String, Box<HostFunction>>,
+}
+
+impl FunctionRegistry {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers `function` as `name`, replacing a function registered before with that name.
+    pub fn register<F>(&mut self, name: impl Into<String>, function: F)
+        where
+            F: Fn(&[Value]) -> Result<Value> + Send + Sync + 'static,
+    {
+        self.functions.insert(name.into(), Box::new(function));
+    }
+
+    #[must_use]
+    pub fn get(&self, name: &str) -> Option<&HostFunction> {
+        self.functions.get(name).map(AsRef::as_ref)
+    }
+}
+
+impl Debug for FunctionRegistry {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_set().entries(self.functions.keys()).finish()
+    }
+}
+
+/// A built-in function with the number of arguments it accepts.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Builtin {
+    pub function: Function,
+    pub min_args: usize,
+    /// `None` for a variadic function.
+    pub max_args: Option<usize>,
+    /// Whether a `Null` or `Missing` argument is handed to the function instead of being
+    /// returned right away.
+    pub accepts_null: bool,
+}
+
+const fn builtin(function: Function, min_args: usize, max_args: Option<usize>) -> Builtin {
+    Builtin {
+        function,
+        min_args,
+        max_args,
+        accepts_null: false,
+    }
+}
+
+/// Looks up a built-in function by name.
+///
+/// Unless noted otherwise a function returns `Missing` when one of its arguments is `Missing`,
+/// or `Null` when one is `Null`.
+///
+/// | Function | Returns |
+/// |----------|---------|
+/// | `len(s)` | number of characters of the string `s` |
+/// | `lower(s)`, `upper(s)`, `trim(s)` | `s` lowercased, uppercased or without surrounding whitespace |
+/// | `substr(s, start[, count])` | `count` characters of `s` from the index `start`, to the end when `count` is omitted |
+/// | `split(s, separator)` | array of the parts of `s` |
+/// | `concat(v, ...)` | the string and number arguments joined into one string |
+/// | `replace(s, from, to)` | `s` with every `from` replaced by `to` |
+/// | `abs(n)`, `floor(n)`, `ceil(n)` | the absolute value, `n` rounded down or up |
+/// | `round(n[, digits])` | `n` rounded to `digits` decimal places, 0 by default |
+/// | `min(v, ...)`, `max(v, ...)` | the smallest or largest number or string, of the arguments or of a single array argument |
+/// | `size(v)` | number of elements of an array or object |
+/// | `first(a)`, `last(a)` | first or last element of an array, `Null` when empty |
+/// | `sum(a)`, `avg(a)` | sum or average of an array of numbers, `avg` is `Null` when empty |
+/// | `coalesce(v, ...)` | the first argument that is neither `Null` nor `Missing`, else `Null` |
+/// | `now()` | the current time, see `EvalContext::with_now` |
+/// | `year(d)`, `month(d)`, `day(d)`, `hour(d)`, `minute(d)` | the part of the date `d`, months and days count from 1 |
+/// | `day_of_week(d)` | the day of the week of `d`, from 1 for Monday to 7 for Sunday |
+/// | `date_trunc(unit, d)` | `d` truncated to the start of its `'year'`, `'month'`, `'week'`, `'day'`, `'hour'`, `'minute'` or `'second'` |
+///
+/// Dates are datetime values or strings in any format `COERCE _datetime_` reads. The parts and
+/// truncation are those of the local time at the offset of the date.
+pub(crate) fn lookup(name: &str) -> Option<Builtin> {
+    Some(match name {
+        "len" => builtin(len, 1, Some(1)),
+        "lower" => builtin(lower, 1, Some(1)),
+        "upper" => builtin(upper, 1, Some(1)),
+        "trim" => builtin(trim, 1, Some(1)),
+        "substr" => builtin(substr, 2, Some(3)),
+        "split" => builtin(split, 2, Some(2)),
+        "concat" => builtin(concat, 1, None),
+        "replace" => builtin(replace, 3, Some(3)),
+        "abs" => builtin(abs, 1, Some(1)),
+        "floor" => builtin(floor, 1, Some(1)),
+        "ceil" => builtin(ceil, 1, Some(1)),
+        "round" => builtin(round, 1, Some(2)),
+        "min" => builtin(min, 1, None),
+        "max" => builtin(max, 1, None),
+        "size" => builtin(size, 1, Some(1)),
+        "first" => builtin(first, 1, Some(1)),
+        "last" => builtin(last, 1, Some(1)),
+        "sum" => builtin(sum, 1, Some(1)),
+        "avg" => builtin(avg, 1, Some(1)),
+        "year" => builtin(year, 1, Some(1)),
+        "month" => builtin(month, 1, Some(1)),
+        "day" => builtin(day, 1, Some(1)),
+        "day_of_week" => builtin(day_of_week, 1, Some(1)),
+        "hour" => builtin(hour, 1, Some(1)),
+        "minute" => builtin(minute, 1, Some(1)),
+        "date_trunc" => builtin(date_trunc, 2, Some(2)),
+        "coalesce" => Builtin {
+            accepts_null: true,
+            ..builtin(coalesce, 1, None)
+        },
+        _ => return None,
+    })
+}
+
+fn unsupported(function: &str, args: &[Value]) -> Error {
+    let args: Vec<String> = args.iter().map(ToString::to_string).collect();
+    Error::UnsupportedFunctionCall(format!("{function}({})", args.join(", ")))
+}
+
+fn string<'a>(function: &str, args: &'a [Value], i: usize) -> Result<&'a str> {
+    match &args[i] {
+        Value::String(s) => Ok(s),
+        _ => Err(unsupported(function, args)),
+    }
+}
+
+fn number(function: &str, args: &[Value], i: usize) -> Result<f64> {
+    number::to_f64(&args[i]).ok_or_else(|| unsupported(function, args))
+}
+
+/// A non-negative whole number argument, eg. an index.
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+fn count(function: &str, args: &[Value], i: usize) -> Result<usize> {
+    match args[i] {
+        Value::Int(n) => usize::try_from(n).map_err(|_| unsupported(function, args)),
+        _ => match number(function, args, i)? {
+            n if n >= 0.0 && n.fract() == 0.0 => Ok(n as usize),
+            _ => Err(unsupported(function, args)),
+        },
+    }
+}
+
+fn int(n: usize) -> Value {
+    i64::try_from(n).map_or(Value::Null, Value::Int)
+}
+
+fn array<'a>(function: &str, args: &'a [Value], i: usize) -> Result<&'a [Value]> {
+    match &args[i] {
+        Value::Array(arr) => Ok(arr),
+        _ => Err(unsupported(function, args)),
+    }
+}
+
+fn datetime(function: &str, args: &[Value], i: usize) -> Result<DateTime<FixedOffset>> {
+    match &args[i] {
+        Value::DateTime(dt) => Ok(*dt),
+        Value::String(s) => anydate::parse(s).map_err(|_| unsupported(function, args)),
+        _ => Err(unsupported(function, args)),
+    }
+}
+
+fn len(args: &[Value]) -> Result<Value> {
+    Ok(int(string("len", args, 0)?.chars().count()))
+}
+
+fn lower(args: &[Value]) -> Result<Value> {
+    Ok(Value::String(string("lower", args, 0)?.to_lowercase()))
+}
+
+fn upper(args: &[Value]) -> Result<Value> {
+    Ok(Value::String(string("upper", args, 0)?.to_uppercase()))
+}
+
+fn trim(args: &[Value]) -> Result<Value> {
+    Ok(Value::String(string("trim", args, 0)?.trim().to_string()))
+}
+
+fn substr(args: &[Value]) -> Result<Value> {
+    let s = string("substr", args, 0)?;
+    let start = count("substr", args, 1)?;
+    let chars = s.chars().skip(start);
+    Ok(Value::String(match args.get(2) {
+        Some(_) => chars.take(count("substr", args, 2)?).collect(),
+        None => chars.collect(),
+    }))
+}
+
+fn split(args: &[Value]) -> Result<Value> {
+    let s = string("split", args, 0)?;
+    let separator = string("split", args, 1)?;
+    Ok(Value::Array(
+        s.split(separator)
+            .map(|part| Value::String(part.to_string()))
+            .collect(),
+    ))
+}
+
+fn concat(args: &[Value]) -> Result<Value> {
+    let mut s = String::new();
+    for arg in args {
+        match arg {
+            Value::String(part) => s.push_str(part),
+            Value::Int(n) => s.push_str(&n.to_string()),
+            Value::Number(n) => s.push_str(&n.to_string()),
+            #[cfg(feature = "decimal")]
+            Value::Decimal(n) => s.push_str(&n.to_string()),
+            _ => return Err(unsupported("concat", args)),
+        }
+    }
+    Ok(Value::String(s))
+}
+
+fn replace(args: &[Value]) -> Result<Value> {
+    let s = string("replace", args, 0)?;
+    let from = string("replace", args, 1)?;
+    let to = string("replace", args, 2)?;
+    Ok(Value::String(s.replace(from, to)))
+}
+
+#[allow(clippy::cast_precision_loss)]
+fn abs(args: &[Value]) -> Result<Value> {
+    match args[0] {
+        Value::Int(n) => Ok(n
+            .checked_abs()
+            .map_or(Value::Number(n.unsigned_abs() as f64), Value::Int)),
+        #[cfg(feature = "decimal")]
+        Value::Decimal(d) => Ok(Value::Decimal(d.abs())),
+        _ => Ok(Value::Number(number("abs", args, 0)?.abs())),
+    }
+}
+
+fn floor(args: &[Value]) -> Result<Value> {
+    match args[0] {
+        Value::Int(n) => Ok(Value::Int(n)),
+        #[cfg(feature = "decimal")]
+        Value::Decimal(d) => Ok(Value::Decimal(d.floor())),
+        _ => Ok(Value::Number(number("floor", args, 0)?.floor())),
+    }
+}
+
+fn ceil(args: &[Value]) -> Result<Value> {
+    match args[0] {
+        Value::Int(n) => Ok(Value::Int(n)),
+        #[cfg(feature = "decimal")]
+        Value::Decimal(d) => Ok(Value::Decimal(d.ceil())),
+        _ => Ok(Value::Number(number("ceil", args, 0)?.ceil())),
+    }
+}
+
+#[allow(clippy::cast_possible_truncation, clippy::cast_possible_wrap)]
+fn round(args: &[Value]) -> Result<Value> {
+    let digits = match args.get(1) {
+        Some(_) => count("round", args, 1)?,
+        None => 0,
+    };
+    match args[0] {
+        Value::Int(n) => Ok(Value::Int(n)),
+        #[cfg(feature = "decimal")]
+        Value::Decimal(d) => Ok(Value::Decimal(d.round_dp(digits as u32))),
+        _ => {
+            let n = number("round", args, 0)?;
+            let factor = 10_f64.powi(digits as i32);
+            Ok(Value::Number((n * factor).round() / factor))
+        }
+    }
+}
+
+fn extreme(function: &str, args: &[Value], wanted: Ordering) -> Result<Value> {
+    let values = match args {
+        [Value::Array(arr)] => arr.as_slice(),
+        _ => args,
+    };
+    let mut result: Option<&Value> = None;
+    for value in values {
+        let ordering = match (value, result) {
+            (_, None) => wanted,
+            (Value::String(s1), Some(Value::String(s2))) => s1.cmp(s2),
+            (n1, Some(n2)) if number::is_number(n1) && number::is_number(n2) => {
+                number::compare(n1, n2).unwrap_or(Ordering::Equal)
+            }
+            _ => return Err(unsupported(function, args)),
+        };
+        if ordering == wanted {
+            result = Some(value);
+        }
+    }
+    match result {
+        Some(value) if number::is_number(value) || matches!(value, Value::String(_)) => Ok(value.clone()),
+        Some(_) => Err(unsupported(function, args)),
+        None => Ok(Value::Null),
+    }
+}
+
+fn min(args: &[Value]) -> Result<Value> {
+    extreme("min", args, Ordering::Less)
+}
+
+fn max(args: &[Value]) -> Result<Value> {
+    extreme("max", args, Ordering::Greater)
+}
+
+fn size(args: &[Value]) -> Result<Value> {
+    match &args[0] {
+        Value::Array(arr) => Ok(int(arr.len())),
+        Value::Object(obj) => Ok(int(obj.len())),
+        _ => Err(unsupported("size", args)),
+    }
+}
+
+fn first(args: &[Value]) -> Result<Value> {
+    Ok(array("first", args, 0)?.first().cloned().unwrap_or(Value::Null))
+}
+
+fn last(args: &[Value]) -> Result<Value> {
+    Ok(array("last", args, 0)?.last().cloned().unwrap_or(Value::Null))
+}
+
+/// Sum of an array of numbers, exact while all of them are `Int` or `Decimal`.
+fn total(function: &str, args: &[Value]) -> Result<Value> {
+    array(function, args, 0)?
+        .iter()
+        .try_fold(Value::Int(0), |sum, v| {
+            number::arithmetic(Op::Add, &sum, v).unwrap_or_else(|| Err(unsupported(function, args)))
+        })
+}
+
+fn sum(args: &[Value]) -> Result<Value> {
+    total("sum", args)
+}
+
+fn avg(args: &[Value]) -> Result<Value> {
+    let total = total("avg", args)?;
+    match array("avg", args, 0)?.len() {
+        0 => Ok(Value::Null),
+        len => number::arithmetic(Op::Div, &total, &int(len)).unwrap_or(Ok(Value::Null)),
+    }
+}
+
+fn coalesce(args: &[Value]) -> Result<Value> {
+    Ok(args
+        .iter()
+        .find(|v| !matches!(v, Value::Null | Value::Missing))
+        .cloned()
+        .unwrap_or(Value::Null))
+}
+
+fn year(args: &[Value]) -> Result<Value> {
+    Ok(Value::Int(i64::from(datetime("year", args, 0)?.year())))
+}
+
+fn month(args: &[Value]) -> Result<Value> {
+    Ok(Value::Int(i64::from(datetime("month", args, 0)?.month())))
+}
+
+fn day(args: &[Value]) -> Result<Value> {
+    Ok(Value::Int(i64::from(datetime("day", args, 0)?.day())))
+}
+
+fn day_of_week(args: &[Value]) -> Result<Value> {
+    Ok(Value::Int(i64::from(
+        datetime("day_of_week", args, 0)?.weekday().number_from_monday(),
+    )))
+}
+
+fn hour(args: &[Value]) -> Result<Value> {
+    Ok(Value::Int(i64::from(datetime("hour", args, 0)?.hour())))
+}
+
+fn minute(args: &[Value]) -> Result<Value> {
+    Ok(Value::Int(i64::from(datetime("minute", args, 0)?.minute())))
+}
+
+fn date_trunc(args: &[Value]) -> Result<Value> {
+    let dt = datetime("date_trunc", args, 1)?;
+    let date = dt.date_naive();
+    let truncated = match string("date_trunc", args, 0)? {
+        "year" => date.with_day(1).and_then(|d| d.with_month(1)).map(|d| d.and_time(NaiveTime::MIN)),
+        "month" => date.with_day(1).map(|d| d.and_time(NaiveTime::MIN)),
+        "week" => Some(
+            (date - chrono::Days::new(u64::from(dt.weekday().num_days_from_monday())))
+                .and_time(NaiveTime::MIN),
+        ),
+        "day" => Some(date.and_time(NaiveTime::MIN)),
+        "hour" => date.and_hms_opt(dt.hour(), 0, 0),
+        "minute" => date.and_hms_opt(dt.hour(), dt.minute(), 0),
+        "second" => date.and_hms_opt(dt.hour(), dt.minute(), dt.second()),
+        _ => None,
+    };
+    truncated
+        .and_then(|naive| dt.offset().from_local_datetime(&naive).single())
+        .map(Value::DateTime)
+        .ok_or_else(|| unsupported("date_trunc", args))
+}
@@ -2,6 +2,6 @@ pub(crate) mod gjson;
 mod modifiers;
 mod multipath;
 mod path;
-mod pretty;
-mod util;
-mod valid;
\ No newline at end of file
+pub(crate) mod pretty;
+pub(crate) mod util;
+pub(crate) mod valid;
\ No newline at end of file
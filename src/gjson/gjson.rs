@@ -3,6 +3,7 @@
 // license that can be found in the LICENSE file.
 
 
+use std::borrow::Cow;
 use std::cmp::Ordering;
 use std::fmt;
 use crate::gjson::{modifiers, multipath};
@@ -1082,6 +1083,71 @@ pub unsafe fn get_bytes<'a>(json: &'a [u8], path: &'a str) -> Value<'a> {
     return get(tostr(json), path)
 }
 
+/// Iterates through the values of a JSON Lines document, the same values that
+/// a `..` path visits. Return `false` from the iterator to stop.
+pub fn for_each_line<'a>(json: &'a str, mut iter: impl FnMut(Value<'a>) -> bool) {
+    for_each(json.as_bytes(), 0, true, Kind::Array, |_, value| iter(value));
+}
+
+/// The members of the object at the start of a json, each a key and the raw json of its value.
+/// The values are skipped over like by a path that does not match them.
+pub struct RawMembers<'a> {
+    json: &'a [u8],
+    i: usize,
+}
+
+impl<'a> RawMembers<'a> {
+    /// The members of `json`, `None` if it is not an object.
+    pub fn new(json: &'a [u8]) -> Option<RawMembers<'a>> {
+        let i = json.iter().position(|b| *b > b' ')?;
+        (json[i] == b'{').then_some(RawMembers { json, i: i + 1 })
+    }
+}
+
+impl<'a> Iterator for RawMembers<'a> {
+    type Item = (Cow<'a, str>, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let json = self.json;
+        let mut i = self.i;
+        // ends the iteration, also when the json is invalid
+        self.i = json.len();
+        while i < json.len() && json[i] != b'"' {
+            if json[i] == b'}' {
+                return None;
+            }
+            i += 1;
+        }
+        if i == json.len() {
+            return None;
+        }
+        let (key, info, next_i) = scan_string(json, i);
+        i = next_i;
+        while i < json.len() && (json[i] <= b' ' || json[i] == b':') {
+            i += 1;
+        }
+        if i == json.len() || key.len() < 2 {
+            return None;
+        }
+        let s = i;
+        i = match json[i] {
+            b'"' => scan_string(json, i).2,
+            b'{' | b'[' => scan_squash(json, i).1,
+            b'0'..=b'9' | b'-' => scan_number(json, i).2,
+            b't' | b'n' => (i + 4).min(json.len()),
+            b'f' => (i + 5).min(json.len()),
+            _ => return None,
+        };
+        self.i = i;
+        let key = if info & INFO_ESC == INFO_ESC {
+            Cow::Owned(unescape(tostr(key)))
+        } else {
+            Cow::Borrowed(tostr(&key[1..key.len() - 1]))
+        };
+        Some((key, &json[s..i]))
+    }
+}
+
 pub fn json_into_owned<'a>(json: Value) -> Value<'a> {
     Value {
         slice: "",
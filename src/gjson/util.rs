@@ -164,6 +164,19 @@ pub fn escape(s: &str) -> String {
     unsafe { std::mem::transmute::<Vec<u8>, String>(out) }
 }
 
+/// escape_path escapes a key so it matches itself as a path component, rather than
+/// being taken for a nested path, a wildcard, a query or a modifier.
+pub fn escape_path(key: &str) -> String {
+    let mut out = String::with_capacity(key.len());
+    for c in key.chars() {
+        if !(c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | ':') || !c.is_ascii()) {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
 /// pmatch returns true if str matches pattern. This is a very
 /// simple wildcard match where '*' matches on any number characters
 /// and '?' matches on any one character.
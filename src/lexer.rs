@@ -1,27 +1,42 @@
+use crate::error::ExprError;
+use std::ops::Range;
 use thiserror::Error;
 
 /// The lexed token.
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct Token {
-    pub start: u32,
-    pub len: u16,
+    pub start: usize,
+    pub len: usize,
     pub kind: TokenKind,
 }
 
+impl Token {
+    /// The bytes of the expression the token was lexed from.
+    #[must_use]
+    pub fn span(&self) -> Range<usize> {
+        self.start..self.start + self.len
+    }
+}
+
 /// The kind of `Token`.
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub enum TokenKind {
     SelectorPath,
     QuotedString,
     Number,
+    Duration,
     BooleanTrue,
     BooleanFalse,
     Null,
     Equals,
+    NotEquals,
     Add,
     Subtract,
     Multiply,
     Divide,
+    IntDivide,
+    Modulo,
+    Power,
     Gt,
     Gte,
     Lt,
@@ -30,12 +45,22 @@ pub enum TokenKind {
     And,
     Or,
     Contains,
+    NotContains,
     ContainsAny,
     ContainsAll,
     In,
+    NotIn,
+    IsNull,
+    IsNotNull,
+    IsMissing,
+    IsNotMissing,
+    Exists,
     Between,
     StartsWith,
     EndsWith,
+    Like,
+    ILike,
+    Matches,
     OpenBracket,
     CloseBracket,
     Comma,
@@ -43,10 +68,64 @@ pub enum TokenKind {
     CloseParen,
     Coerce,
     Identifier,
+    Variable,
+    Any,
+    All,
+    Filter,
+    Map,
+    Case,
+    When,
+    Then,
+    Else,
+    End,
+    Question,
+    Colon,
+    OpenBrace,
+    CloseBrace,
 }
 
+/// The keywords and the words they are made of, which are matched in any case and end at a word
+/// boundary. A keyword comes before the shorter ones its words start with, eg. `IS NOT NULL`
+/// before `IS NULL` and `NOT IN` before `NOT`.
+const KEYWORDS: &[(&[&str], TokenKind)] = &[
+    (&["IS", "NOT", "NULL"], TokenKind::IsNotNull),
+    (&["IS", "NOT", "MISSING"], TokenKind::IsNotMissing),
+    (&["IS", "NULL"], TokenKind::IsNull),
+    (&["IS", "MISSING"], TokenKind::IsMissing),
+    (&["NOT", "IN"], TokenKind::NotIn),
+    (&["NOT", "CONTAINS"], TokenKind::NotContains),
+    (&["NOT"], TokenKind::Not),
+    (&["AND"], TokenKind::And),
+    (&["OR"], TokenKind::Or),
+    (&["IN"], TokenKind::In),
+    (&["CONTAINS"], TokenKind::Contains),
+    (&["CONTAINS_ANY"], TokenKind::ContainsAny),
+    (&["CONTAINS_ALL"], TokenKind::ContainsAll),
+    (&["EXISTS"], TokenKind::Exists),
+    (&["BETWEEN"], TokenKind::Between),
+    (&["STARTS_WITH"], TokenKind::StartsWith),
+    (&["ENDS_WITH"], TokenKind::EndsWith),
+    (&["LIKE"], TokenKind::Like),
+    (&["ILIKE"], TokenKind::ILike),
+    (&["MATCHES"], TokenKind::Matches),
+    (&["DIV"], TokenKind::IntDivide),
+    (&["COERCE"], TokenKind::Coerce),
+    (&["ANY"], TokenKind::Any),
+    (&["ALL"], TokenKind::All),
+    (&["FILTER"], TokenKind::Filter),
+    (&["MAP"], TokenKind::Map),
+    (&["CASE"], TokenKind::Case),
+    (&["WHEN"], TokenKind::When),
+    (&["THEN"], TokenKind::Then),
+    (&["ELSE"], TokenKind::Else),
+    (&["END"], TokenKind::End),
+    (&["TRUE"], TokenKind::BooleanTrue),
+    (&["FALSE"], TokenKind::BooleanFalse),
+    (&["NULL"], TokenKind::Null),
+];
+
 pub struct Tokenizer<'a> {
-    pos: u32,
+    pos: usize,
     remaining: &'a [u8],
 }
 
@@ -67,13 +146,14 @@ impl<'a> Tokenizer<'a> {
         }
     }
 
-    fn next_token(&mut self) -> Result<Option<Token>> {
+    fn next_token(&mut self) -> std::result::Result<Option<Token>, ExprError> {
         self.skip_whitespace();
 
         if self.remaining.is_empty() {
             Ok(None)
         } else {
-            let (kind, bytes_read) = tokenize_single_token(self.remaining)?;
+            let (kind, bytes_read) = tokenize_single_token(self.remaining)
+                .map_err(|e| ExprError::new(e.clone(), self.pos..self.pos + e.len()))?;
             let token = Token {
                 kind,
                 start: self.pos,
@@ -84,19 +164,27 @@ impl<'a> Tokenizer<'a> {
         }
     }
 
+    /// Skips whitespace and `#` comments, which run to the end of the line.
     fn skip_whitespace(&mut self) {
-        let skipped = skip_whitespace(self.remaining);
-        self.chomp(skipped);
+        loop {
+            let skipped = skip_whitespace(self.remaining);
+            self.chomp(skipped);
+            if self.remaining.first() != Some(&b'#') {
+                break;
+            }
+            let comment = self.remaining.iter().position(|c| *c == b'\n').unwrap_or(self.remaining.len());
+            self.chomp(comment);
+        }
     }
 
-    fn chomp(&mut self, len: u16) {
-        self.remaining = &self.remaining[len as usize..];
-        self.pos += u32::from(len);
+    fn chomp(&mut self, len: usize) {
+        self.remaining = &self.remaining[len..];
+        self.pos += len;
     }
 }
 
 impl Iterator for Tokenizer<'_> {
-    type Item = Result<Token>;
+    type Item = std::result::Result<Token, ExprError>;
 
     fn next(&mut self) -> Option<Self::Item> {
         self.next_token().transpose()
@@ -104,13 +192,13 @@ impl Iterator for Tokenizer<'_> {
 }
 
 #[inline]
-fn skip_whitespace(data: &[u8]) -> u16 {
+fn skip_whitespace(data: &[u8]) -> usize {
     take_while(data, |c| c.is_ascii_whitespace()).unwrap_or(0)
 }
 
 #[inline]
 /// Consumes bytes while a predicate evaluates to true.
-fn take_while<F>(data: &[u8], mut pred: F) -> Option<u16>
+fn take_while<F>(data: &[u8], mut pred: F) -> Option<usize>
     where
         F: FnMut(u8) -> bool,
 {
@@ -133,8 +221,8 @@ fn take_while<F>(data: &[u8], mut pred: F) -> Option<u16>
 /// Result of a single tokenization attempt.
 pub type Result<T> = std::result::Result<T, Error>;
 
-/// Error type for the lexer.
-#[derive(Error, Debug, PartialEq, Eq)]
+/// Error type for the lexer, with the text it could not lex.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
 pub enum Error {
     #[error("invalid identifier: {0}")]
     InvalidIdentifier(String),
@@ -149,14 +237,30 @@ pub enum Error {
     InvalidKeyword(String),
 
     #[error("Unsupported Character `{0}`")]
-    UnsupportedCharacter(u8),
+    UnsupportedCharacter(char),
 
-    #[error("Unterminated string `{0}`")]
-    UnterminatedString(String),
+    /// A string missing the closing quote, with the opening one.
+    #[error("Unterminated string, missing the closing {0}")]
+    UnterminatedString(char),
+}
+
+impl Error {
+    /// The number of bytes of the text that could not be lexed.
+    fn len(&self) -> usize {
+        match self {
+            Error::InvalidIdentifier(text)
+            | Error::InvalidNumber(text)
+            | Error::InvalidBool(text)
+            | Error::InvalidKeyword(text) => text.len(),
+            Error::UnsupportedCharacter(c) => c.len_utf8(),
+            // the span is the opening quote
+            Error::UnterminatedString(_) => 1,
+        }
+    }
 }
 
 /// Try to lex a single token from the input stream.
-fn tokenize_single_token(data: &[u8]) -> Result<(TokenKind, u16)> {
+fn tokenize_single_token(data: &[u8]) -> Result<(TokenKind, usize)> {
     let Some(b) = data.first() else {
         panic!("invalid data passed")
     };
@@ -164,22 +268,14 @@ fn tokenize_single_token(data: &[u8]) -> Result<(TokenKind, u16)> {
     let (token, end) = match b {
         b'=' if data.get(1) == Some(&b'=') => (TokenKind::Equals, 2),
         b'=' => (TokenKind::Equals, 1),
-        b'+' => {
-            if data.get(1).map_or_else(|| false, u8::is_ascii_digit) {
-                tokenize_number(data)?
-            } else {
-                (TokenKind::Add, 1)
-            }
-        }
-        b'-' => {
-            if data.get(1).map_or_else(|| false, u8::is_ascii_digit) {
-                tokenize_number(data)?
-            } else {
-                (TokenKind::Subtract, 1)
-            }
-        }
+        // signs are unary operators of the parser, so `.a -1` is a subtraction
+        b'+' => (TokenKind::Add, 1),
+        b'-' => (TokenKind::Subtract, 1),
+        b'*' if data.get(1) == Some(&b'*') => (TokenKind::Power, 2),
         b'*' => (TokenKind::Multiply, 1),
         b'/' => (TokenKind::Divide, 1),
+        b'%' => (TokenKind::Modulo, 1),
+        b'^' => (TokenKind::Power, 1),
         b'>' if data.get(1) == Some(&b'=') => (TokenKind::Gte, 2),
         b'>' => (TokenKind::Gt, 1),
         b'<' if data.get(1) == Some(&b'=') => (TokenKind::Lte, 2),
@@ -189,59 +285,59 @@ fn tokenize_single_token(data: &[u8]) -> Result<(TokenKind, u16)> {
         b'[' => (TokenKind::OpenBracket, 1),
         b']' => (TokenKind::CloseBracket, 1),
         b',' => (TokenKind::Comma, 1),
+        b'{' => (TokenKind::OpenBrace, 1),
+        b'}' => (TokenKind::CloseBrace, 1),
+        b'?' => (TokenKind::Question, 1),
+        b':' => (TokenKind::Colon, 1),
+        b'!' if data.get(1) == Some(&b'=') => {
+            // `!==` is accepted as well, it was the negated `==` before `!=` existed
+            if data.get(2) == Some(&b'=') {
+                (TokenKind::NotEquals, 3)
+            } else {
+                (TokenKind::NotEquals, 2)
+            }
+        }
         b'!' => (TokenKind::Not, 1),
         b'"' | b'\'' => tokenize_string(data, *b)?,
         b'.' => tokenize_selector_path(data)?,
-        b't' | b'f' => tokenize_bool(data)?,
+        b'$' => tokenize_variable(data)?,
         b'&' if data.get(1) == Some(&b'&') => (TokenKind::And, 2),
         b'|' if data.get(1) == Some(&b'|') => (TokenKind::Or, 2),
-        b'O' => tokenize_keyword(data, "OR".as_bytes(), TokenKind::Or)?,
-        b'C' => {
-            if data.get(2) == Some(&b'N') {
-                // can be one of CONTAINS, CONTAINS_ANY, CONTAINS_ALL
-                if data.get(8) == Some(&b'_') {
-                    if data.get(10) == Some(&b'N') {
-                        tokenize_keyword(data, "CONTAINS_ANY".as_bytes(), TokenKind::ContainsAny)?
-                    } else {
-                        tokenize_keyword(data, "CONTAINS_ALL".as_bytes(), TokenKind::ContainsAll)?
-                    }
-                } else {
-                    tokenize_keyword(data, "CONTAINS".as_bytes(), TokenKind::Contains)?
-                }
-            } else {
-                tokenize_keyword(data, "COERCE".as_bytes(), TokenKind::Coerce)?
-            }
-        }
-        b'I' => tokenize_keyword(data, "IN".as_bytes(), TokenKind::In)?,
-        b'S' => tokenize_keyword(data, "STARTS_WITH".as_bytes(), TokenKind::StartsWith)?,
-        b'E' => tokenize_keyword(data, "ENDS_WITH".as_bytes(), TokenKind::EndsWith)?,
-        b'B' => tokenize_keyword(data, "BETWEEN".as_bytes(), TokenKind::Between)?,
-        b'N' => tokenize_null(data)?,
+        b'a'..=b'z' | b'A'..=b'Z' => tokenize_word(data),
         b'_' => tokenize_identifier(data)?,
         b'0'..=b'9' => tokenize_number(data)?,
-        _ => return Err(Error::UnsupportedCharacter(*b)),
+        _ => {
+            // the first character, which is not ASCII when it starts with a byte past 0x7F
+            let head = String::from_utf8_lossy(&data[..data.len().min(4)]);
+            let c = head.chars().next().unwrap_or(char::REPLACEMENT_CHARACTER);
+            return Err(Error::UnsupportedCharacter(c));
+        }
     };
     Ok((token, end))
 }
 
+/// The text from the start of `data` up to the next whitespace, for an `Error`.
+fn word(data: &[u8]) -> String {
+    let end = data.iter().position(u8::is_ascii_whitespace).unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..end.max(1)]).into_owned()
+}
+
 #[inline]
-fn tokenize_identifier(data: &[u8]) -> Result<(TokenKind, u16)> {
+fn tokenize_identifier(data: &[u8]) -> Result<(TokenKind, usize)> {
     // TODO: take until end underscore found!
     match take_while(data, |c| {
-        !c.is_ascii_whitespace() && c != b')' && c != b']' && c != b','
+        !c.is_ascii_whitespace() && c != b'(' && c != b')' && c != b']' && c != b','
     }) {
         // identifier must start and end with underscore
-        Some(end) if end > 0 && data.get(end as usize - 1) == Some(&b'_') => {
+        Some(end) if end > 0 && data.get(end - 1) == Some(&b'_') => {
             Ok((TokenKind::Identifier, end))
         }
-        _ => Err(Error::InvalidIdentifier(
-            String::from_utf8_lossy(data).to_string(),
-        )),
+        _ => Err(Error::InvalidIdentifier(word(data))),
     }
 }
 
 #[inline]
-fn tokenize_string(data: &[u8], quote: u8) -> Result<(TokenKind, u16)> {
+fn tokenize_string(data: &[u8], quote: u8) -> Result<(TokenKind, usize)> {
     let mut last_backslash = false;
     let mut ended_with_terminator = false;
 
@@ -268,16 +364,12 @@ fn tokenize_string(data: &[u8], quote: u8) -> Result<(TokenKind, u16)> {
             if ended_with_terminator {
                 Ok((TokenKind::QuotedString, end + 2))
             } else {
-                Err(Error::UnterminatedString(
-                    String::from_utf8_lossy(data).to_string(),
-                ))
+                Err(Error::UnterminatedString(char::from(quote)))
             }
         }
         None => {
             if !ended_with_terminator || data.len() < 2 {
-                Err(Error::UnterminatedString(
-                    String::from_utf8_lossy(data).to_string(),
-                ))
+                Err(Error::UnterminatedString(char::from(quote)))
             } else {
                 Ok((TokenKind::QuotedString, 2))
             }
@@ -286,76 +378,157 @@ fn tokenize_string(data: &[u8], quote: u8) -> Result<(TokenKind, u16)> {
 }
 
 #[inline]
-fn tokenize_selector_path(data: &[u8]) -> Result<(TokenKind, u16)> {
-    match take_while(&data[1..], |c| {
-        !c.is_ascii_whitespace() && c != b')' && c != b']'
+fn tokenize_selector_path(data: &[u8]) -> Result<(TokenKind, usize)> {
+    // whitespace, commas and closing brackets and braces only end the path outside of gjson
+    // queries and multipaths, eg. `.friends.#(age>40)` or `.{name,age}`
+    let mut depth = 0_usize;
+    match take_while(&data[1..], |c| match c {
+        b'(' | b'[' | b'{' => {
+            depth += 1;
+            true
+        }
+        b')' | b']' | b'}' if depth > 0 => {
+            depth -= 1;
+            true
+        }
+        b')' | b']' | b'}' | b',' => false,
+        _ => depth > 0 || !c.is_ascii_whitespace(),
     }) {
         Some(end) => Ok((TokenKind::SelectorPath, end + 1)),
-        None => Err(Error::InvalidIdentifier(
-            String::from_utf8_lossy(data).to_string(),
-        )),
+        // `.` alone is the whole document, or the element of `ANY`, `ALL`, `FILTER` and `MAP`
+        None => Ok((TokenKind::SelectorPath, 1)),
     }
 }
 
 #[inline]
-fn tokenize_bool(data: &[u8]) -> Result<(TokenKind, u16)> {
-    match take_while(data, |c| c.is_ascii_alphabetic()) {
-        Some(end) => match data[..end as usize] {
-            [b't', b'r', b'u', b'e'] => Ok((TokenKind::BooleanTrue, end)),
-            [b'f', b'a', b'l', b's', b'e'] => Ok((TokenKind::BooleanFalse, end)),
-            _ => Err(Error::InvalidBool(
-                String::from_utf8_lossy(data).to_string(),
-            )),
-        },
-        None => Err(Error::InvalidBool(
-            String::from_utf8_lossy(data).to_string(),
-        )),
+fn tokenize_variable(data: &[u8]) -> Result<(TokenKind, usize)> {
+    match take_while(&data[1..], |c| c.is_ascii_alphanumeric() || c == b'_') {
+        Some(end) => Ok((TokenKind::Variable, end + 1)),
+        None => Err(Error::InvalidIdentifier(word(data))),
     }
 }
 
+/// Tokenizes a keyword of `KEYWORDS` or else the name of a function, eg. `lower`.
 #[inline]
-fn tokenize_keyword(data: &[u8], keyword: &[u8], kind: TokenKind) -> Result<(TokenKind, u16)> {
-    match take_while(data, |c| !c.is_ascii_whitespace()) {
-        Some(end) if data.len() > keyword.len() && &data[..end as usize] == keyword => {
-            Ok((kind, end))
+fn tokenize_word(data: &[u8]) -> (TokenKind, usize) {
+    KEYWORDS
+        .iter()
+        .find_map(|(words, kind)| match_words(data, words).map(|end| (kind.clone(), end)))
+        .unwrap_or((TokenKind::Identifier, word_end(data)))
+}
+
+/// The length of the `words` at the start of `data`, separated by whitespace, if they are there.
+fn match_words(data: &[u8], words: &[&str]) -> Option<usize> {
+    let mut end = 0;
+    for (i, word) in words.iter().enumerate() {
+        if i > 0 {
+            match skip_whitespace(&data[end..]) {
+                0 => return None,
+                skipped => end += skipped,
+            }
+        }
+        let len = word_end(&data[end..]);
+        if !data[end..end + len].eq_ignore_ascii_case(word.as_bytes()) {
+            return None;
         }
-        _ => Err(Error::InvalidKeyword(
-            String::from_utf8_lossy(data).to_string(),
-        )),
+        end += len;
     }
+    Some(end)
 }
 
-#[inline]
-fn tokenize_null(data: &[u8]) -> Result<(TokenKind, u16)> {
-    match take_while(data, |c| c.is_ascii_alphabetic()) {
-        Some(end) if data[..end as usize] == [b'N', b'U', b'L', b'L'] => Ok((TokenKind::Null, end)),
-        _ => Err(Error::InvalidKeyword(
-            String::from_utf8_lossy(data).to_string(),
-        )),
-    }
+/// The length of the word at the start of `data`, made of letters, digits and underscores.
+fn word_end(data: &[u8]) -> usize {
+    data.iter()
+        .position(|c| !c.is_ascii_alphanumeric() && *c != b'_')
+        .unwrap_or(data.len())
 }
 
 #[inline]
-fn tokenize_number(data: &[u8]) -> Result<(TokenKind, u16)> {
+fn tokenize_number(data: &[u8]) -> Result<(TokenKind, usize)> {
     let mut dot_seen = false;
     let mut bad_number = false;
+    let mut previous = 0;
 
-    match take_while(data, |c| match c {
-        b'.' => {
-            if dot_seen {
-                bad_number = true;
-                false
-            } else {
-                dot_seen = true;
-                true
+    match take_while(data, |c| {
+        let take = match c {
+            b'.' => {
+                if dot_seen {
+                    bad_number = true;
+                    false
+                } else {
+                    dot_seen = true;
+                    true
+                }
             }
-        }
-        b'-' | b'+' | b'e' => true,
-        _ => c.is_ascii_digit(),
+            // only the exponent is signed, eg. `1e-3`
+            b'-' | b'+' => previous == b'e',
+            b'e' => true,
+            _ => c.is_ascii_digit(),
+        };
+        previous = c;
+        take
     }) {
+        // a number directly followed by a unit is a duration, eg. `7d` or `1h30m`
+        Some(end) if !bad_number && !dot_seen && data.get(end).is_some_and(u8::is_ascii_alphabetic) => {
+            let unit = take_while(&data[end..], |c| c.is_ascii_alphanumeric()).unwrap_or(0);
+            Ok((TokenKind::Duration, end + unit))
+        }
         Some(end) if !bad_number => Ok((TokenKind::Number, end)),
-        _ => Err(Error::InvalidNumber(
-            String::from_utf8_lossy(data).to_string(),
-        )),
+        _ => Err(Error::InvalidNumber(word(data))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Token, TokenKind, Tokenizer};
+
+    fn kinds(expression: &str) -> Vec<TokenKind> {
+        Tokenizer::new(expression).map(|token| token.unwrap().kind).collect()
+    }
+
+    #[test]
+    fn keywords() {
+        assert_eq!(
+            kinds("is not null Is Null IS  NOT\tMISSING is missing"),
+            vec![TokenKind::IsNotNull, TokenKind::IsNull, TokenKind::IsNotMissing, TokenKind::IsMissing]
+        );
+        assert_eq!(
+            kinds("not in NOT INSIDE not contains NOT CONTAINS_ANY"),
+            vec![
+                TokenKind::NotIn,
+                TokenKind::Not,
+                TokenKind::Identifier,
+                TokenKind::NotContains,
+                TokenKind::Not,
+                TokenKind::ContainsAny,
+            ]
+        );
+        assert_eq!(
+            kinds("and or AND OR Exists EXISTS"),
+            vec![TokenKind::And, TokenKind::Or, TokenKind::And, TokenKind::Or, TokenKind::Exists, TokenKind::Exists]
+        );
+        assert_eq!(
+            kinds("Score Contest Estimate ends_with"),
+            vec![TokenKind::Identifier, TokenKind::Identifier, TokenKind::Identifier, TokenKind::EndsWith]
+        );
+        assert_eq!(
+            kinds("IN[1]"),
+            vec![TokenKind::In, TokenKind::OpenBracket, TokenKind::Number, TokenKind::CloseBracket]
+        );
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn comments_and_lengths() {
+        let tokens: Vec<Token> = Tokenizer::new("# leading\n.a # trailing\n== 1 #")
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(
+            tokens.iter().map(Token::span).collect::<Vec<_>>(),
+            vec![10..12, 24..26, 27..28]
+        );
+
+        let string = format!("'{}'", "x".repeat(100_000));
+        let token = Tokenizer::new(&string).next().unwrap().unwrap();
+        assert_eq!((token.kind, token.len), (TokenKind::QuotedString, 100_002));
+    }
+}
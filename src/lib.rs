@@ -1,14 +1,32 @@
+pub mod ast;
 pub mod lexer;
+pub mod error;
 pub mod exp_parser;
+pub mod functions;
+pub mod optimizer;
+pub mod vm;
+pub mod batch;
+pub mod document;
 pub mod gjson;
+pub mod search;
+pub mod duration;
+pub mod sql;
+pub mod transfer;
+mod number;
 
 #[macro_use]
 extern crate pest_derive;
 extern crate pest;
 
+use std::time::Duration;
+use chrono::{DateTime, Utc};
 use pest::Parser;
 use pest::iterators::{Pair};
 use crate::exp_parser::BoxedExpression;
+use crate::search::{SearchSpec, unquote};
+use crate::duration::parse_duration;
+use crate::sql::SqlStatement;
+use crate::transfer::TransferFormat;
 
 
 /// **Create collection** <br>
@@ -17,6 +35,21 @@ use crate::exp_parser::BoxedExpression;
 /// **Drop collection** <br>
 /// `drop('');` <br>
 ///
+/// **Expire documents matching a condition after a duration** <br>
+/// `ttl('15m').if('').into('');` <br>
+///
+/// **Expire documents matching a condition at a point in time** <br>
+/// `ttl.at('2023-06-01T00:00:00Z').if('').into('');` <br>
+///
+/// **Expire a Pointer after a duration** <br>
+/// `ttl.pointer('', '1h').into('');` <br>
+///
+/// **Get remaining TTL of a Pointer** <br>
+/// `ttl.get('').from('');` <br>
+///
+/// **Clear TTL of a Pointer** <br>
+/// `ttl.clear('').from('');` <br>
+///
 /// **Check if pointer exists in collection** <br>
 /// `exists('').into('');` <br>
 ///
@@ -47,6 +80,18 @@ use crate::exp_parser::BoxedExpression;
 /// **Get Clip from collection** <br>
 /// `get.clip('').from('');` <br>
 ///
+/// **Copy documents matching a condition to another collection** <br>
+/// `copy.when('').from('').project('{name,age}').into('');` <br>
+///
+/// **Move documents matching a condition to another collection** <br>
+/// `move.when('').from('').into('');` <br>
+///
+/// **Export collection to a file** <br>
+/// `export('').when('').to('users.csv').format('csv', ['name','age']);` <br>
+///
+/// **Import a file into collection** <br>
+/// `import('users.jsonl').into('');` <br>
+///
 /// **Delete from collection** <br>
 /// `delete.from('');` <br>
 ///
@@ -100,10 +145,18 @@ use crate::exp_parser::BoxedExpression;
 ///                     Flql::Length(_) => {}
 ///                     Flql::Flush(_) => {}
 ///                     Flql::Ttl(_,_,_) => {}
+///                     Flql::TtlAt(_,_,_) => {}
+///                     Flql::TtlPointer(_,_,_) => {}
+///                     Flql::TtlGet(_,_) => {}
+///                     Flql::TtlClear(_,_) => {}
 ///                     Flql::Put(_, _) => {}
 ///                     Flql::PutWhen(_, _, _) => {}
 ///                     Flql::PutPointer(_, _, _) => {}
 ///                     Flql::SearchTyping(_,_) => {}
+///                     Flql::Copy(_,_,_,_) => {}
+///                     Flql::Move(_,_,_,_) => {}
+///                     Flql::Export(_,_,_,_) => {}
+///                     Flql::Import(_,_,_) => {}
 ///                     Flql::Get(_,_,_) => {}
 ///                     Flql::GetWhen(_, _,_,_) => {}
 ///                     Flql::GetPointer(_, _) => {}
@@ -460,11 +513,19 @@ pub enum Flql {
     Exists(String, String),
     Length(String),
     Flush(String),
-    Ttl(String, String, String),
+    Ttl(Duration, String, String),
+    TtlAt(DateTime<Utc>, String, String),
+    TtlPointer(String, Duration, String),
+    TtlGet(String, String),
+    TtlClear(String, String),
     Put(String,String),
     PutWhen(String, String, String),
     PutPointer(String, String, String),
-    SearchTyping(String, String),
+    SearchTyping(SearchSpec, String),
+    Copy(Option<String>, String, Option<String>, String),
+    Move(Option<String>, String, Option<String>, String),
+    Export(String, Option<String>, String, TransferFormat),
+    Import(String, TransferFormat, String),
     Get(String, Option<String>, Option<String>),
     GetWhen(String, String, Option<String>, Option<String>),
     GetPointer(String, String),
@@ -479,9 +540,9 @@ pub enum Flql {
     None
 }
 
-fn pair_parser(pair: Pair<Rule>) -> Flql {
-    match pair.as_rule() {
-        Rule::expr => pair_parser(pair.into_inner().next().unwrap()),
+fn pair_parser(pair: Pair<Rule>) -> Result<Flql, String> {
+    Ok(match pair.as_rule() {
+        Rule::expr => return pair_parser(pair.into_inner().next().unwrap()),
         Rule::new_db => {
             Flql::DbNew(one(pair).to_string())
         },
@@ -518,13 +579,48 @@ fn pair_parser(pair: Pair<Rule>) -> Flql {
             Flql::Flush(one(pair).to_string())
         }
         Rule::ttl => {
-            let three = three(pair);
+            let mut inner = pair.into_inner();
+            let duration = ttl_duration(inner.next().unwrap())?;
             Flql::Ttl(
-                three[0].to_string(),
+                duration,
+                str(inner.next().unwrap()),
+                str(inner.next().unwrap())
+            )
+        }
+        Rule::ttl_at => {
+            let three = three(pair);
+            let at = anydate::parse_utc(&unquote(&three[0]))
+                .map_err(|e| format!("invalid ttl datetime {}: {e}", three[0]))?;
+            Flql::TtlAt(
+                at,
                 three[1].to_string(),
                 three[2].to_string()
             )
         }
+        Rule::ttl_pointer => {
+            let mut inner = pair.into_inner();
+            let pointer = str(inner.next().unwrap());
+            let duration = ttl_duration(inner.next().unwrap())?;
+            Flql::TtlPointer(
+                pointer,
+                duration,
+                str(inner.next().unwrap())
+            )
+        }
+        Rule::ttl_get => {
+            let two = two(pair);
+            Flql::TtlGet(
+                two[0].to_string(),
+                two[1].to_string()
+            )
+        }
+        Rule::ttl_clear => {
+            let two = two(pair);
+            Flql::TtlClear(
+                two[0].to_string(),
+                two[1].to_string()
+            )
+        }
         Rule::put => {
             let two = two(pair);
             Flql::Put(
@@ -549,11 +645,72 @@ fn pair_parser(pair: Pair<Rule>) -> Flql {
             )
         }
         Rule::search_typing => {
-            let two = two(pair);
-            Flql::SearchTyping(
-                two[0].to_string(),
-                two[1].to_string()
-            )
+            let mut inner = pair.into_inner();
+            let mut spec = SearchSpec::new(&unquote(inner.next().unwrap().as_str()))?;
+            let collection = str(inner.next().unwrap());
+            for opt in inner {
+                match opt.as_rule() {
+                    Rule::search_fields => {
+                        spec.fields = opt.into_inner().map(|f| unquote(f.as_str())).collect();
+                    }
+                    Rule::search_fuzzy => {
+                        spec.fuzzy = Some(one(opt).parse().map_err(|e| format!("invalid fuzzy: {e}"))?);
+                    }
+                    Rule::search_prefix => {
+                        spec.prefix = Some(one(opt) == "true");
+                    }
+                    Rule::search_limit => {
+                        spec.limit = Some(one(opt).parse().map_err(|e| format!("invalid limit: {e}"))?);
+                    }
+                    Rule::search_highlight => {
+                        spec.highlight = Some(one(opt) == "true");
+                    }
+                    _ => {}
+                }
+            }
+            Flql::SearchTyping(spec, collection)
+        }
+        Rule::copy_into => {
+            let (condition, source, projection, target) = transfer_source(pair);
+            Flql::Copy(condition, source, projection, target)
+        }
+        Rule::move_into => {
+            let (condition, source, projection, target) = transfer_source(pair);
+            Flql::Move(condition, source, projection, target)
+        }
+        Rule::export => {
+            let mut inner = pair.into_inner();
+            let collection = str(inner.next().unwrap());
+            let mut next = inner.next().unwrap();
+            let condition = if next.as_rule() == Rule::condition {
+                let condition = str(next);
+                next = inner.next().unwrap();
+                Some(condition)
+            } else {
+                None
+            };
+            let file = str(next);
+            let format = match inner.next() {
+                Some(format) => transfer_format(format)?,
+                None => TransferFormat::from_path(&unquote(&file)),
+            };
+            Flql::Export(collection, condition, file, format)
+        }
+        Rule::import => {
+            let mut inner = pair.into_inner();
+            let file = str(inner.next().unwrap());
+            let mut next = inner.next().unwrap();
+            let format = if next.as_rule() == Rule::transfer_format {
+                let format = transfer_format(next)?;
+                if format != TransferFormat::Csv(vec![]) && format != TransferFormat::JsonLines {
+                    return Err("import does not take CSV columns, they are read from the header".to_string());
+                }
+                next = inner.next().unwrap();
+                format
+            } else {
+                TransferFormat::from_path(&unquote(&file))
+            };
+            Flql::Import(file, format, str(next))
         }
         Rule::get => {
             let opts = four_opt(pair);
@@ -644,7 +801,7 @@ fn pair_parser(pair: Pair<Rule>) -> Flql {
             )
         }
         _ => Flql::None
-    }
+    })
 }
 
 fn one(opt: Pair<Rule>) -> String {
@@ -694,6 +851,42 @@ fn four(opt:Pair<Rule>) -> [String; 4] {
     [f,s,t,fr]
 }
 
+fn transfer_source(opt: Pair<Rule>) -> (Option<String>, String, Option<String>, String) {
+    let mut condition = None;
+    let mut collections = vec![];
+    let mut projection = None;
+    for pair in opt.into_inner() {
+        match pair.as_rule() {
+            Rule::condition => condition = Some(str(pair)),
+            Rule::projection => projection = Some(str(pair)),
+            _ => collections.push(str(pair)),
+        }
+    }
+    let target = collections.pop().unwrap();
+    let source = collections.pop().unwrap();
+    (condition, source, projection, target)
+}
+
+fn transfer_format(opt: Pair<Rule>) -> Result<TransferFormat, String> {
+    let mut inner = opt.into_inner();
+    let format = unquote(inner.next().unwrap().as_str());
+    match format.to_lowercase().as_str() {
+        "csv" => Ok(TransferFormat::Csv(inner.map(|c| unquote(c.as_str())).collect())),
+        "jsonl" | "json" if inner.next().is_none() => Ok(TransferFormat::JsonLines),
+        "jsonl" | "json" => Err(format!("format '{format}' does not take columns")),
+        _ => Err(format!("unsupported format '{format}', expected 'jsonl' or 'csv'")),
+    }
+}
+
+fn ttl_duration(opt: Pair<Rule>) -> Result<Duration, String> {
+    match opt.as_rule() {
+        Rule::u64 => opt.as_str().parse()
+            .map(Duration::from_secs)
+            .map_err(|e| format!("invalid ttl {}: {e}", opt.as_str())),
+        _ => parse_duration(&unquote(opt.as_str())),
+    }
+}
+
 fn str(opt: Pair<Rule>) -> String {
     opt.as_str().to_string()
 }
@@ -705,7 +898,7 @@ pub fn parse(dql: &str) -> Result<Flql, String> {
         let pairs = pairs.unwrap();
         for pair in pairs {
             node = match pair.as_rule() {
-                Rule::expr => Some(pair_parser(pair)),
+                Rule::expr => Some(pair_parser(pair)?),
                 _ => None
             };
             if node.is_some() {
@@ -726,10 +919,24 @@ pub fn expr_parse(expression: &str) -> anyhow::Result<BoxedExpression> {
     exp_parser::Parser::parse(expression)
 }
 
+/// Parses an expression into its `ast::Expr` tree, to find the paths and operators it uses.
+pub fn expr_parse_ast(expression: &str) -> anyhow::Result<ast::Expr> {
+    Ok(exp_parser::Parser::parse_expr(expression)?)
+}
+
+pub fn parse_sql(sql: &str) -> Result<SqlStatement, String> {
+    sql::parse(sql)
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::{Flql, parse, expr_parse};
+    use crate::{Flql, parse, expr_parse, parse_sql, transfer};
+    use crate::transfer::TransferFormat;
     use crate::gjson::gjson::get;
+    use crate::search::SearchTerm;
+    use crate::exp_parser::Value;
+    use crate::duration::parse_duration;
+    use std::time::Duration;
 
     #[test]
     fn test() {
@@ -768,10 +975,18 @@ mod tests {
                     Flql::Length(_) => {}
                     Flql::Flush(_) => {}
                     Flql::Ttl(_,_,_) => {}
+                    Flql::TtlAt(_,_,_) => {}
+                    Flql::TtlPointer(_,_,_) => {}
+                    Flql::TtlGet(_,_) => {}
+                    Flql::TtlClear(_,_) => {}
                     Flql::Put(_, _) => {}
                     Flql::PutWhen(_, _, _) => {}
                     Flql::PutPointer(_, _, _) => {}
                     Flql::SearchTyping(_,_) => {}
+                    Flql::Copy(_,_,_,_) => {}
+                    Flql::Move(_,_,_,_) => {}
+                    Flql::Export(_,_,_,_) => {}
+                    Flql::Import(_,_,_) => {}
                     Flql::Get(_,_,_) => {}
                     Flql::GetWhen(_,_,_,_)=>{}
                     Flql::GetPointer(_, _) => {}
@@ -789,6 +1004,270 @@ mod tests {
         }
     }
 
+    #[test]
+    fn search_typing() {
+        let parsed = parse("search.typing('\"red shoes\" -kids sneakers OR boots').from('products').fields(['title','body']).fuzzy(2).prefix(true).limit(20).highlight(false);").unwrap();
+        let Flql::SearchTyping(spec, collection) = parsed else {
+            panic!("expected search.typing, found {parsed:?}");
+        };
+        assert_eq!(collection, "'products'");
+        assert_eq!(spec.query, "\"red shoes\" -kids sneakers OR boots");
+        assert_eq!(spec.terms, vec![
+            SearchTerm::Phrase("red shoes".to_string()),
+            SearchTerm::Exclude(Box::new(SearchTerm::Word("kids".to_string()))),
+            SearchTerm::Or(vec![
+                SearchTerm::Word("sneakers".to_string()),
+                SearchTerm::Word("boots".to_string()),
+            ]),
+        ]);
+        assert_eq!(spec.fields, vec!["title", "body"]);
+        assert_eq!(spec.fuzzy, Some(2));
+        assert_eq!(spec.prefix, Some(true));
+        assert_eq!(spec.limit, Some(20));
+        assert_eq!(spec.highlight, Some(false));
+
+        let parsed = parse("search.typing('shoes').from('products');").unwrap();
+        let Flql::SearchTyping(spec, _) = parsed else {
+            panic!("expected search.typing, found {parsed:?}");
+        };
+        assert_eq!(spec.fields, Vec::<String>::new());
+        assert_eq!(spec.fuzzy, None);
+
+        assert!(parse("search.typing('\"red shoes').from('products');").is_err());
+        assert!(parse("search.typing('shoes OR').from('products');").is_err());
+        assert!(parse("search.typing('shoes').from('products').limit(99999999999999999999999);").is_err());
+        let parsed = parse("search.typing('shoes').from('products').fuzzy(10);").unwrap();
+        let Flql::SearchTyping(spec, _) = parsed else {
+            panic!("expected search.typing, found {parsed:?}");
+        };
+        assert_eq!(spec.fuzzy, Some(10));
+        assert_eq!(
+            parse("search.typing('shoes').from('products').fuzzy(256);"),
+            Err("invalid fuzzy: number too large to fit in target type".to_string())
+        );
+    }
+
+    #[test]
+    fn ttl() {
+        assert_eq!(
+            parse("ttl(60).if('').into('c');").unwrap(),
+            Flql::Ttl(Duration::from_secs(60), "''".to_string(), "'c'".to_string())
+        );
+        assert_eq!(
+            parse("ttl('1h30m').if('.a == 1').into('c');").unwrap(),
+            Flql::Ttl(Duration::from_secs(5400), "'.a == 1'".to_string(), "'c'".to_string())
+        );
+        assert_eq!(
+            parse("ttl.pointer('p', '7d').into('c');").unwrap(),
+            Flql::TtlPointer("'p'".to_string(), Duration::from_secs(7 * 24 * 60 * 60), "'c'".to_string())
+        );
+        assert_eq!(
+            parse("ttl.get('p').from('c');").unwrap(),
+            Flql::TtlGet("'p'".to_string(), "'c'".to_string())
+        );
+        assert_eq!(
+            parse("ttl.clear('p').from('c');").unwrap(),
+            Flql::TtlClear("'p'".to_string(), "'c'".to_string())
+        );
+        let parsed = parse("ttl.at('2023-06-01T00:00:00Z').if('').into('c');").unwrap();
+        let Flql::TtlAt(at, _, _) = parsed else {
+            panic!("expected ttl.at, found {parsed:?}");
+        };
+        assert_eq!(at.timestamp(), 1_685_577_600);
+
+        assert!(parse("ttl('15 parsecs').if('').into('c');").is_err());
+        assert!(parse("ttl.at('not a date').if('').into('c');").is_err());
+        assert_eq!(parse_duration("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("250ms"), Ok(Duration::from_millis(250)));
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("h").is_err());
+    }
+
+    #[test]
+    fn sql() {
+        let statement = parse_sql("SELECT name, address.city FROM users WHERE age >= 18 AND (city = 'KL' OR city IN ('Penang', 'Ipoh')) ORDER BY name DESC LIMIT 10 OFFSET 20").unwrap();
+        assert_eq!(statement.projection, Some("{name,address.city}".to_string()));
+        assert_eq!(statement.flql, Flql::GetWhen(
+            "'((.age >= 18) && ((.city == \"KL\") || (.city IN [\"Penang\", \"Ipoh\"])))'".to_string(),
+            "'users'".to_string(),
+            Some("'name','DESC'".to_string()),
+            Some("20,10".to_string()),
+        ));
+
+        let statement = parse_sql("select * from users").unwrap();
+        assert_eq!(statement.projection, None);
+        assert_eq!(statement.flql, Flql::Get("'users'".to_string(), None, None));
+
+        let statement = parse_sql("SELECT * FROM users WHERE name LIKE 'Jo%' AND NOT deleted = true AND age NOT BETWEEN 1 AND 9 AND email IS NOT NULL").unwrap();
+        let Flql::GetWhen(condition, _, _, _) = statement.flql else {
+            panic!("expected get.when, found {:?}", statement.flql);
+        };
+        assert_eq!(
+            condition,
+            "'((((.name LIKE \"Jo*\") && ((.deleted IS NOT NULL) && !(.deleted == true))) && ((.age IS NOT NULL) && !((.age >= 1) && (.age <= 9)))) && (.email IS NOT NULL))'"
+        );
+        let expr = expr_parse(&condition[1..condition.len() - 1]).unwrap();
+        assert_eq!(expr.calculate(br#"{"name":"Joe","deleted":false,"age":30,"email":"j@x.io"}"#), Ok(Value::Bool(true)));
+        assert_eq!(expr.calculate(br#"{"name":"Joe","deleted":false,"age":3,"email":"j@x.io"}"#), Ok(Value::Bool(false)));
+        assert_eq!(expr.calculate(br#"{"name":"Joe","deleted":false,"age":30}"#), Ok(Value::Bool(false)));
+        assert_eq!(expr.calculate(br#"{"name":"Joe","deleted":false,"age":30,"email":null}"#), Ok(Value::Bool(false)));
+        assert_eq!(expr.calculate(br#"{"name":"Joe","age":30,"email":"j@x.io"}"#), Ok(Value::Bool(false)));
+        assert_eq!(expr.calculate(br#"{"name":"Joe","deleted":false,"email":"j@x.io"}"#), Ok(Value::Bool(false)));
+
+        // A condition on a missing or NULL column is unknown, so it matches neither way.
+        let matches = |sql: &str, document: &[u8]| {
+            let Flql::GetWhen(condition, _, _, _) = parse_sql(sql).unwrap().flql else {
+                panic!("expected get.when for {sql}");
+            };
+            expr_parse(&condition[1..condition.len() - 1]).unwrap().calculate(document)
+        };
+        let missing = br#"{"name":"Jo"}"#;
+        assert_eq!(matches("SELECT * FROM users WHERE email IS NULL", missing), Ok(Value::Bool(true)));
+        assert_eq!(matches("SELECT * FROM users WHERE NOT email IS NOT NULL", missing), Ok(Value::Bool(true)));
+        assert_eq!(matches("SELECT * FROM users WHERE email IS NOT NULL", missing), Ok(Value::Bool(false)));
+        assert_eq!(matches("SELECT * FROM users WHERE email <> 'a@x.io'", missing), Ok(Value::Bool(false)));
+        assert_eq!(matches("SELECT * FROM users WHERE email NOT IN ('a@x.io')", missing), Ok(Value::Bool(false)));
+        assert_eq!(matches("SELECT * FROM users WHERE email NOT LIKE 'a%'", missing), Ok(Value::Bool(false)));
+        assert_eq!(matches("SELECT * FROM users WHERE age NOT BETWEEN 1 AND 9", missing), Ok(Value::Bool(false)));
+        assert_eq!(matches("SELECT * FROM users WHERE NOT (age = 3 OR name = 'Al')", missing), Ok(Value::Bool(false)));
+        assert_eq!(matches("SELECT * FROM users WHERE NOT (age = 3 AND name = 'Al')", missing), Ok(Value::Bool(true)));
+        assert_eq!(matches("SELECT * FROM users WHERE email <> 'a@x.io'", br#"{"email":"b@x.io"}"#), Ok(Value::Bool(true)));
+        assert_eq!(matches("SELECT * FROM users WHERE NOT email <> 'a@x.io'", br#"{"email":"a@x.io"}"#), Ok(Value::Bool(true)));
+
+        assert_eq!(
+            parse_sql("SELECT COUNT(*) FROM users;").unwrap().flql,
+            Flql::Length("'users'".to_string())
+        );
+        assert_eq!(
+            parse_sql("INSERT INTO users VALUES ({\"name\": \"Jo\", \"age\": 3})").unwrap().flql,
+            Flql::Put("{\"name\": \"Jo\", \"age\": 3}".to_string(), "'users'".to_string())
+        );
+        assert_eq!(
+            parse_sql("DELETE FROM users WHERE name = 'O''Neil'").unwrap().flql,
+            Flql::DeleteWhen("'(.name == \"O''Neil\")'".to_string(), "'users'".to_string())
+        );
+        assert_eq!(
+            parse_sql("DELETE FROM users").unwrap().flql,
+            Flql::Delete("'users'".to_string())
+        );
+
+        assert_eq!(parse_sql("UPDATE users SET a = 1"), Err("unsupported SQL: UPDATE".to_string()));
+        assert_eq!(parse_sql("SELECT * FROM a JOIN b"), Err("unsupported SQL: JOIN".to_string()));
+        assert!(parse_sql("SELECT COUNT(*) FROM users WHERE a = 1").is_err());
+        assert!(parse_sql("SELECT * FROM users ORDER BY a, b").is_err());
+        let like = |pattern: &str, name: &str| {
+            matches(&format!("SELECT * FROM users WHERE name LIKE '{pattern}'"), format!("{{\"name\":\"{name}\"}}").as_bytes())
+        };
+        assert_eq!(like("J_n", "Jon"), Ok(Value::Bool(true)));
+        assert_eq!(like("J_n", "Joan"), Ok(Value::Bool(false)));
+        assert_eq!(like("a%b%", "a-b-c"), Ok(Value::Bool(true)));
+        assert_eq!(like("%n", "Jon"), Ok(Value::Bool(true)));
+        assert_eq!(like("%o%", "Jon"), Ok(Value::Bool(true)));
+        assert_eq!(like("Jon", "Jonny"), Ok(Value::Bool(false)));
+        assert_eq!(like("a*b?", "a*b?"), Ok(Value::Bool(true)));
+        assert_eq!(like("a*b?", "axbc"), Ok(Value::Bool(false)));
+    }
+
+    #[test]
+    fn copy_move() {
+        assert_eq!(
+            parse("copy.when('.year < 2020').from('orders').project('{id,total}').into('archive');").unwrap(),
+            Flql::Copy(
+                Some("'.year < 2020'".to_string()),
+                "'orders'".to_string(),
+                Some("'{id,total}'".to_string()),
+                "'archive'".to_string(),
+            )
+        );
+        assert_eq!(
+            parse("move.when('.year < 2020').from('orders').into('archive');").unwrap(),
+            Flql::Move(Some("'.year < 2020'".to_string()), "'orders'".to_string(), None, "'archive'".to_string())
+        );
+        assert_eq!(
+            parse("copy.from('orders').into('backup');").unwrap(),
+            Flql::Copy(None, "'orders'".to_string(), None, "'backup'".to_string())
+        );
+        assert!(parse("move.when('.a').into('archive');").is_err());
+    }
+
+    #[test]
+    fn transfer() {
+        assert_eq!(
+            parse("export('users').when('.age > 18').to('adults.csv').format('csv', ['name','age']);").unwrap(),
+            Flql::Export(
+                "'users'".to_string(),
+                Some("'.age > 18'".to_string()),
+                "'adults.csv'".to_string(),
+                TransferFormat::Csv(vec!["name".to_string(), "age".to_string()]),
+            )
+        );
+        assert_eq!(
+            parse("export('users').to('users.jsonl');").unwrap(),
+            Flql::Export("'users'".to_string(), None, "'users.jsonl'".to_string(), TransferFormat::JsonLines)
+        );
+        assert_eq!(
+            parse("import('users.csv').into('users');").unwrap(),
+            Flql::Import("'users.csv'".to_string(), TransferFormat::Csv(vec![]), "'users'".to_string())
+        );
+        assert_eq!(
+            parse("import('dump').format('jsonl').into('users');").unwrap(),
+            Flql::Import("'dump'".to_string(), TransferFormat::JsonLines, "'users'".to_string())
+        );
+        assert!(parse("export('users').to('users.xml').format('xml');").is_err());
+        assert!(parse("import('users.csv').format('csv', ['a']).into('users');").is_err());
+
+        let records = [
+            r#"{"name":"Jo, Jr.","age":30,"tags":["a"]}"#,
+            "{\n  \"name\": \"Al\",\n  \"age\": 12\n}",
+            r#"{"name":"Mo","age":41}"#,
+        ];
+        let adults = expr_parse(".age > 18").unwrap();
+
+        let mut jsonl = vec![];
+        let exported = transfer::export(records, Some(adults.as_ref()), &TransferFormat::JsonLines, &mut jsonl).unwrap();
+        assert_eq!(exported, 2);
+        let mut imported = vec![];
+        let count = transfer::import(jsonl.as_slice(), &TransferFormat::JsonLines, |record| {
+            imported.push(record.to_string());
+            Ok(())
+        }).unwrap();
+        assert_eq!(count, 2);
+        assert_eq!(imported, vec![records[0], records[2]]);
+
+        let mut csv = vec![];
+        transfer::export(records, None, &TransferFormat::Csv(vec![]), &mut csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv.clone()).unwrap(),
+            "name,age,tags\n\"Jo, Jr.\",30,\"[\"\"a\"\"]\"\nAl,12,\nMo,41,\n"
+        );
+        let mut imported = vec![];
+        transfer::import(csv.as_slice(), &TransferFormat::Csv(vec![]), |record| {
+            imported.push(record.to_string());
+            Ok(())
+        }).unwrap();
+        assert_eq!(imported[1], r#"{"name":"Al","age":12,"tags":null}"#);
+
+        let records = [
+            r#"{"a.b":"42","tags#":"true","x*y":"","{k":"null","$v":42,"a\\b":true,"@n":null}"#,
+            r#"{"a.b":"4 2","tags#":"t","x*y":"x","{k":"","$v":"-1.5e3","a\\b":false,"@n":"a,\"b\""}"#,
+        ];
+        let mut csv = vec![];
+        transfer::export(records, None, &TransferFormat::Csv(vec![]), &mut csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv.clone()).unwrap(),
+            "a.b,tags#,x*y,{k,$v,a\\b,@n\n\"42\",\"true\",\"\",null,42,true,\n4 2,t,x,\"\",\"-1.5e3\",false,\"a,\"\"b\"\"\"\n"
+        );
+        let mut imported = vec![];
+        transfer::import(csv.as_slice(), &TransferFormat::Csv(vec![]), |record| {
+            imported.push(record.to_string());
+            Ok(())
+        }).unwrap();
+        assert_eq!(imported, records);
+
+        let invalid = "{\"a\":1}\n{\"a\":}\n";
+        assert!(transfer::import(invalid.as_bytes(), &TransferFormat::JsonLines, |_| Ok(())).is_err());
+    }
+
     #[test]
     fn parser() {
         let src = r#"{"string":"TEST","date":"2023-01-01 12:00:01", "object":{ "prop": true }, "array":[1,3], "array_map":[{"a":1},{"a":2}] }"#.as_bytes();
@@ -0,0 +1,192 @@
+use std::cmp::Ordering;
+#[cfg(feature = "decimal")]
+use rust_decimal::prelude::ToPrimitive;
+#[cfg(feature = "decimal")]
+use rust_decimal::{Decimal, MathematicalOps};
+use crate::exp_parser::{Error, Result, Value};
+
+/// Arithmetic operators of the numeric `Value`s.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Op {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    /// Division truncated toward zero, `DIV`.
+    IntDiv,
+    /// Remainder of the truncated division, with the sign of the dividend.
+    Rem,
+    Pow,
+}
+
+impl Op {
+    fn symbol(self) -> &'static str {
+        match self {
+            Op::Add => "+",
+            Op::Sub => "-",
+            Op::Mul => "*",
+            Op::Div => "/",
+            Op::IntDiv => "DIV",
+            Op::Rem => "%",
+            Op::Pow => "**",
+        }
+    }
+}
+
+impl std::fmt::Display for Op {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.symbol())
+    }
+}
+
+/// Whether `value` is an `Int`, `Decimal` or `Number`.
+pub(crate) fn is_number(value: &Value) -> bool {
+    match value {
+        Value::Int(_) | Value::Number(_) => true,
+        #[cfg(feature = "decimal")]
+        Value::Decimal(_) => true,
+        _ => false,
+    }
+}
+
+#[allow(clippy::cast_precision_loss)]
+pub(crate) fn to_f64(value: &Value) -> Option<f64> {
+    match value {
+        Value::Int(i) => Some(*i as f64),
+        Value::Number(n) => Some(*n),
+        #[cfg(feature = "decimal")]
+        Value::Decimal(d) => d.to_f64(),
+        _ => None,
+    }
+}
+
+/// Reads a JSON number, exactly as an `Int` when it is a whole number that fits.
+pub(crate) fn from_json(raw: &str, fallback: impl FnOnce() -> f64) -> Value {
+    match raw.parse::<i64>() {
+        Ok(i) => Value::Int(i),
+        Err(_) => Value::Number(fallback()),
+    }
+}
+
+/// Applies `op` to two numbers, `None` if either is not one.
+///
+/// Both sides are promoted to the wider of `Int`, `Decimal` and `Number` first. `Int` results
+/// that overflow, `Int` divisions with a remainder and `Int` powers with a negative exponent
+/// become a `Number`. Dividing by zero, including a negative power of zero, is an error, whatever
+/// the kind of the numbers, and so is
+/// a `Decimal` result that overflows.
+#[allow(clippy::cast_precision_loss)]
+pub(crate) fn arithmetic(op: Op, left: &Value, right: &Value) -> Option<Result<Value>> {
+    let by_zero = match op {
+        Op::Div | Op::IntDiv | Op::Rem => to_f64(right)? == 0.0 && is_number(left),
+        // a negative power is a division by the positive one
+        Op::Pow => to_f64(left)? == 0.0 && to_f64(right)? < 0.0,
+        _ => false,
+    };
+    if by_zero {
+        return Some(Err(Error::DivisionByZero(format!("{left} {op} {right}"))));
+    }
+    Some(Ok(match (left, right) {
+        (Value::Int(a), Value::Int(b)) => {
+            let exact = match op {
+                Op::Add => a.checked_add(*b),
+                Op::Sub => a.checked_sub(*b),
+                Op::Mul => a.checked_mul(*b),
+                Op::Div => a.checked_rem(*b).filter(|r| *r == 0).and_then(|_| a.checked_div(*b)),
+                Op::IntDiv => a.checked_div(*b),
+                // only `i64::MIN % -1` overflows, and its remainder is 0
+                Op::Rem => Some(a.wrapping_rem(*b)),
+                Op::Pow => u32::try_from(*b).ok().and_then(|b| a.checked_pow(b)),
+            };
+            match exact {
+                Some(i) => Value::Int(i),
+                None => Value::Number(float(op, *a as f64, *b as f64)),
+            }
+        }
+        #[cfg(feature = "decimal")]
+        (Value::Int(_) | Value::Decimal(_), Value::Int(_) | Value::Decimal(_)) => {
+            let (a, b) = (to_decimal(left)?, to_decimal(right)?);
+            let result = match op {
+                Op::Add => a.checked_add(b),
+                Op::Sub => a.checked_sub(b),
+                Op::Mul => a.checked_mul(b),
+                Op::Div => a.checked_div(b),
+                Op::IntDiv => a.checked_div(b).map(|d| d.trunc()),
+                Op::Rem => a.checked_rem(b),
+                Op::Pow => match right {
+                    Value::Int(b) => a.checked_powi(*b),
+                    _ => a.checked_powd(b),
+                },
+            };
+            match result {
+                Some(d) => Value::Decimal(d),
+                None => return Some(Err(Error::Overflow(format!("{left} {op} {right}")))),
+            }
+        }
+        _ => Value::Number(float(op, to_f64(left)?, to_f64(right)?)),
+    }))
+}
+
+fn float(op: Op, a: f64, b: f64) -> f64 {
+    match op {
+        Op::Add => a + b,
+        Op::Sub => a - b,
+        Op::Mul => a * b,
+        Op::Div => a / b,
+        Op::IntDiv => (a / b).trunc(),
+        Op::Rem => a % b,
+        Op::Pow => a.powf(b),
+    }
+}
+
+/// Negates a number, `None` if it is not one. An `Int` that overflows becomes a `Number`.
+#[allow(clippy::cast_precision_loss)]
+pub(crate) fn negate(value: &Value) -> Option<Value> {
+    match value {
+        Value::Int(i) => Some(i.checked_neg().map_or(Value::Number(-(*i as f64)), Value::Int)),
+        Value::Number(n) => Some(Value::Number(-n)),
+        #[cfg(feature = "decimal")]
+        Value::Decimal(d) => Some(Value::Decimal(-d)),
+        _ => None,
+    }
+}
+
+#[cfg(feature = "decimal")]
+fn to_decimal(value: &Value) -> Option<Decimal> {
+    match value {
+        Value::Int(i) => Some(Decimal::from(*i)),
+        Value::Decimal(d) => Some(*d),
+        _ => None,
+    }
+}
+
+/// Orders two numbers by their exact value, `None` if either is not one or is NaN.
+pub(crate) fn compare(left: &Value, right: &Value) -> Option<Ordering> {
+    match (left, right) {
+        (Value::Int(a), Value::Int(b)) => Some(a.cmp(b)),
+        (Value::Int(a), Value::Number(b)) => compare_int_float(*a, *b),
+        (Value::Number(a), Value::Int(b)) => compare_int_float(*b, *a).map(Ordering::reverse),
+        #[cfg(feature = "decimal")]
+        (Value::Int(_) | Value::Decimal(_), Value::Int(_) | Value::Decimal(_)) => {
+            Some(to_decimal(left)?.cmp(&to_decimal(right)?))
+        }
+        _ => to_f64(left)?.partial_cmp(&to_f64(right)?),
+    }
+}
+
+#[allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+fn compare_int_float(a: i64, b: f64) -> Option<Ordering> {
+    if b.is_nan() {
+        None
+    } else if b >= i64::MAX as f64 {
+        // i64::MAX rounds up to 2^63 which no i64 reaches
+        Some(Ordering::Less)
+    } else if b < i64::MIN as f64 {
+        Some(Ordering::Greater)
+    } else {
+        match a.cmp(&(b.trunc() as i64)) {
+            Ordering::Equal => 0.0.partial_cmp(&b.fract()),
+            ordering => Some(ordering),
+        }
+    }
+}
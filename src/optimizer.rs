@@ -0,0 +1,283 @@
+use std::fmt::{Display, Formatter};
+use crate::ast::{fold_children, BinaryOp, Expr, Fold, IterateOp, UnaryOp};
+use crate::exp_parser::{Parser, Value};
+use crate::functions;
+
+/// An `Expr` after `optimize`, with what was found out about its predicates.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Optimized {
+    pub expr: Expr,
+    pub warnings: Vec<Warning>,
+}
+
+/// A predicate whose result does not depend on the document, as it was written.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Warning {
+    AlwaysTrue(Expr),
+    AlwaysFalse(Expr),
+}
+
+impl Display for Warning {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Warning::AlwaysTrue(expr) => write!(f, "{expr} is always true"),
+            Warning::AlwaysFalse(expr) => write!(f, "{expr} is always false"),
+        }
+    }
+}
+
+/// Simplifies an `Expr` without changing its result for any document.
+///
+/// - Operations on literals only are calculated, eg. `60 * 60` becomes `3600`. Those that fail,
+///   such as `1 / 0`, are kept so that they fail when the expression is calculated.
+/// - `true && x`, `false || x` and `!!x` become `x`, `false && x` becomes `false` and
+///   `true || x` becomes `true`, when `x` is a predicate that always results in a boolean.
+/// - Repeated predicates of `&&` and `||` are kept once, eg. `.a > 1 && .a > 1` becomes `.a > 1`.
+///
+/// `Parser::compile` looks up `IN` and `NOT IN` an array of literals in a hash set.
+///
+/// Predicates that are always true or always false, such as `1 == 2 && .a > 1`, are reported
+/// as `Warning`s, only the outermost when they nest.
+///
+/// ```
+/// use flql::exp_parser::Parser;
+/// use flql::optimizer::{optimize, Warning};
+///
+/// let optimized = optimize(Parser::parse_expr(".age > 6 * 3 && true").unwrap());
+/// assert_eq!(optimized.expr, Parser::parse_expr(".age > 18").unwrap());
+///
+/// let optimized = optimize(Parser::parse_expr(".age > 18 || 2 > 1").unwrap());
+/// assert_eq!(optimized.warnings.len(), 1);
+/// assert_eq!(optimized.warnings[0].to_string(), "((.age > 18) || (2 > 1)) is always true");
+/// ```
+#[must_use]
+pub fn optimize(expr: Expr) -> Optimized {
+    let mut optimizer = Optimizer { warnings: Vec::new() };
+    let expr = optimizer.fold_expr(expr);
+    Optimized {
+        expr,
+        warnings: optimizer.warnings,
+    }
+}
+
+struct Optimizer {
+    warnings: Vec<Warning>,
+}
+
+impl Fold for Optimizer {
+    fn fold_expr(&mut self, expr: Expr) -> Expr {
+        let original = (is_predicate(&expr) && !matches!(expr, Expr::Literal(_))).then(|| expr.clone());
+        let nested_warnings = self.warnings.len();
+
+        let expr = simplify(fold_children(self, expr));
+
+        if let (Some(original), Expr::Literal(Value::Bool(b))) = (original, &expr) {
+            // the warning of this predicate covers those of its operands
+            self.warnings.truncate(nested_warnings);
+            self.warnings.push(if *b {
+                Warning::AlwaysTrue(original)
+            } else {
+                Warning::AlwaysFalse(original)
+            });
+        }
+        expr
+    }
+}
+
+/// Simplifies `expr`, whose children are already simplified.
+fn simplify(expr: Expr) -> Expr {
+    if let Some(value) = constant(&expr) {
+        return Expr::Literal(value);
+    }
+    match expr {
+        Expr::Unary {
+            op: UnaryOp::Not,
+            expr,
+        } => match *expr {
+            Expr::Unary {
+                op: UnaryOp::Not,
+                expr,
+            } if is_predicate(&expr) => *expr,
+            expr => Expr::Unary {
+                op: UnaryOp::Not,
+                expr: Box::new(expr),
+            },
+        },
+        Expr::Binary {
+            op: op @ (BinaryOp::And | BinaryOp::Or),
+            left,
+            right,
+        } => {
+            let mut operands = Vec::new();
+            flatten(op, *left, &mut operands);
+            flatten(op, *right, &mut operands);
+            logical(op, operands)
+        }
+        expr => expr,
+    }
+}
+
+/// Collects the operands of a chain of `op`, eg. `a`, `b` and `c` of `(a && b) && c`.
+fn flatten(op: BinaryOp, expr: Expr, operands: &mut Vec<Expr>) {
+    match expr {
+        Expr::Binary { op: chained, left, right } if chained == op => {
+            flatten(op, *left, operands);
+            flatten(op, *right, operands);
+        }
+        expr => operands.push(expr),
+    }
+}
+
+/// Rebuilds a chain of `&&` or `||` from its operands, without the ones that do not change its
+/// result and the repeated ones.
+fn logical(op: BinaryOp, operands: Vec<Expr>) -> Expr {
+    // `true` for `&&` and `false` for `||`
+    let identity = op == BinaryOp::And;
+    if !operands.iter().all(is_predicate) {
+        return chain(op, operands);
+    }
+    let mut kept: Vec<Expr> = Vec::new();
+    for operand in operands {
+        match operand {
+            Expr::Literal(Value::Bool(b)) if b == identity => {}
+            Expr::Literal(Value::Bool(b)) => return Expr::Literal(Value::Bool(b)),
+            operand if kept.contains(&operand) => {}
+            operand => kept.push(operand),
+        }
+    }
+    if kept.is_empty() {
+        Expr::Literal(Value::Bool(identity))
+    } else {
+        chain(op, kept)
+    }
+}
+
+fn chain(op: BinaryOp, operands: Vec<Expr>) -> Expr {
+    operands
+        .into_iter()
+        .reduce(|left, right| Expr::Binary {
+            op,
+            left: Box::new(left),
+            right: Box::new(right),
+        })
+        .expect("a chain has operands")
+}
+
+/// The value of `expr` when it only operates on literals, `None` if it does not or fails.
+fn constant(expr: &Expr) -> Option<Value> {
+    let foldable = match expr {
+        Expr::Literal(_)
+        | Expr::Path(_)
+        | Expr::Variable(_)
+        | Expr::Exists(_)
+        | Expr::Missing(_) => false,
+        // `now()` changes and host functions are unknown
+        Expr::Call { name, .. } => name != "now" && functions::lookup(name).is_some(),
+        _ => true,
+    };
+    if !foldable || !expr.children().into_iter().all(|child| matches!(child, Expr::Literal(_))) {
+        return None;
+    }
+    Parser::compile(expr.clone()).ok()?.calculate(&[]).ok()
+}
+
+/// Whether `expr` always results in a boolean, or fails.
+fn is_predicate(expr: &Expr) -> bool {
+    match expr {
+        Expr::Literal(value) => matches!(value, Value::Bool(_)),
+        Expr::Binary { op, .. } => op.is_comparison() || matches!(op, BinaryOp::And | BinaryOp::Or),
+        Expr::Unary { op, .. } => matches!(op, UnaryOp::Not | UnaryOp::IsNull | UnaryOp::IsNotNull),
+        Expr::Between { .. } | Expr::Matches { .. } | Expr::Exists(_) | Expr::Missing(_) => true,
+        Expr::Iterate { op, .. } => matches!(op, IterateOp::Any | IterateOp::All),
+        Expr::Path(_)
+        | Expr::Variable(_)
+        | Expr::Array(_)
+        | Expr::Object(_)
+        | Expr::Coerce { .. }
+        | Expr::Call { .. }
+        | Expr::Case { .. } => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{optimize, Warning};
+    use crate::ast::Expr;
+    use crate::exp_parser::{Parser, Value};
+
+    fn optimized(expression: &str) -> String {
+        optimize(Parser::parse_expr(expression).unwrap()).expr.to_string()
+    }
+
+    fn warnings(expression: &str) -> Vec<String> {
+        let optimized = optimize(Parser::parse_expr(expression).unwrap());
+        optimized.warnings.iter().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn folds_constants() {
+        assert_eq!(optimized(".a > 60 * 60 + -1"), "(.a > 3599)");
+        assert_eq!(optimized(".d < 7d + 12h"), "(.d < 1w12h)");
+        assert_eq!(optimized("len('abc') ** 2"), "9");
+        assert_eq!(optimized(".a IN [1, (1 + 1), upper('x')]"), "(.a IN [1, 2, \"X\"])");
+        assert_eq!(optimized("COERCE (1 + 2) _string_"), "\"3\"");
+        assert_eq!(optimized(".a + 1 / (1 - 1)"), "(.a + (1 / 0))");
+        assert_eq!(optimized("now() > .at"), "(now() > .at)");
+        assert_eq!(optimized("lookup(1 + 1) && $flag"), "(lookup(2) && $flag)");
+    }
+
+    #[test]
+    fn simplifies() {
+        assert_eq!(optimized("true && .a > 1"), "(.a > 1)");
+        assert_eq!(optimized(".a > 1 && true && .b == 2"), "((.a > 1) && (.b == 2))");
+        assert_eq!(optimized("false || .a > 1"), "(.a > 1)");
+        assert_eq!(optimized("!!(.a > 1)"), "(.a > 1)");
+        assert_eq!(optimized("!!!EXISTS .a"), "(!EXISTS .a)");
+        assert_eq!(optimized(".a > 1 && .b && .a > 1"), "(((.a > 1) && .b) && (.a > 1))");
+        assert_eq!(optimized(".a > 1 && .b < 2 && .a > 1 && .b < 2"), "((.a > 1) && (.b < 2))");
+        assert_eq!(optimized(".a > 1 || .a > 1"), "(.a > 1)");
+
+        // `.a` may be `Missing` or not a boolean, so these keep their result
+        assert_eq!(optimized("true && .a"), "(true && .a)");
+        assert_eq!(optimized("!!.a"), "(!(!.a))");
+    }
+
+    #[test]
+    fn warns() {
+        assert_eq!(warnings(".a > 1 && 1 == 2"), ["((.a > 1) && (1 == 2)) is always false"]);
+        assert_eq!(warnings("1 == 1"), ["(1 == 1) is always true"]);
+        assert_eq!(warnings("!(1 > 2) || .a"), ["(!(1 > 2)) is always true"]);
+        assert_eq!(
+            warnings("(1 == 2 || .a > 1) && 'a' IN ['a']"),
+            ["(1 == 2) is always false", "(\"a\" IN [\"a\"]) is always true"]
+        );
+        assert!(warnings(".a > 1 && true").is_empty());
+        assert!(warnings("true").is_empty());
+
+        let optimized = optimize(Parser::parse_expr(".a > 1 || 2 > 1").unwrap());
+        assert_eq!(optimized.expr, Expr::Literal(Value::Bool(true)));
+        assert!(matches!(optimized.warnings[..], [Warning::AlwaysTrue(_)]));
+    }
+
+    #[test]
+    fn in_hash_set() {
+        let eval = |expression: &str| {
+            let expr = optimize(Parser::parse_expr(expression).unwrap()).expr;
+            Parser::compile(expr)
+                .unwrap()
+                .calculate(br#"{"a":2,"s":"b","n":null,"id":9007199254740993}"#)
+                .unwrap()
+        };
+
+        assert_eq!(eval(".a IN [1, 2.0, 'x']"), Value::Bool(true));
+        assert_eq!(eval(".a IN [1, 2.5]"), Value::Bool(false));
+        assert_eq!(eval(".a NOT IN [1, 3]"), Value::Bool(true));
+        assert_eq!(eval(".s IN ['a', 'b']"), Value::Bool(true));
+        assert_eq!(eval(".n IN [NULL]"), Value::Bool(true));
+        assert_eq!(eval(".missing IN [NULL, 1]"), Value::Bool(false));
+        assert_eq!(eval(".missing NOT IN [NULL, 1]"), Value::Bool(true));
+        assert_eq!(eval(".id IN [9007199254740992]"), Value::Bool(false));
+        assert_eq!(eval(".id IN [9007199254740993]"), Value::Bool(true));
+        assert_eq!(eval(".a * 1.5 IN [3]"), Value::Bool(true));
+    }
+}
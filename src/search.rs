@@ -0,0 +1,120 @@
+/// Options of a `search.typing(...)` statement.
+///
+/// `search.typing('"red shoes" -kids OR adults').from('products').fields(['title','body']).fuzzy(2).prefix(true).limit(20).highlight(true);`
+///
+/// Unlike the other `Flql` arguments, the values held here are unquoted.
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct SearchSpec {
+    /// The query text as typed by the user.
+    pub query: String,
+    /// The parsed query. Every term must match unless it is excluded.
+    pub terms: Vec<SearchTerm>,
+    /// Fields to search in. Empty means every field.
+    pub fields: Vec<String>,
+    /// Maximum edit distance allowed when matching a word.
+    pub fuzzy: Option<u8>,
+    /// Whether the last word may match as a prefix.
+    pub prefix: Option<bool>,
+    /// Maximum number of hits to return.
+    pub limit: Option<u64>,
+    /// Whether matches should be highlighted in the hits.
+    pub highlight: Option<bool>,
+}
+
+/// A single term of a search query.
+#[derive(PartialEq, Debug, Clone)]
+pub enum SearchTerm {
+    /// A bare word, `shoes`.
+    Word(String),
+    /// A quoted phrase, `"red shoes"`.
+    Phrase(String),
+    /// A term that must not match, `-kids`.
+    Exclude(Box<SearchTerm>),
+    /// Alternatives of which at least one must match, `kids OR adults`.
+    Or(Vec<SearchTerm>),
+}
+
+impl SearchSpec {
+    /// Creates a `SearchSpec` for the query, leaving every option unset.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the query is not valid, see `parse_query`.
+    pub fn new(query: &str) -> Result<Self, String> {
+        Ok(SearchSpec {
+            query: query.to_string(),
+            terms: parse_query(query)?,
+            ..SearchSpec::default()
+        })
+    }
+}
+
+/// Parses the search mini-syntax: words, `"quoted phrases"`, `-excluded` terms and `OR`.
+///
+/// # Errors
+///
+/// Will return `Err` on an unterminated phrase, a dangling `-` or a dangling `OR`.
+pub fn parse_query(query: &str) -> Result<Vec<SearchTerm>, String> {
+    let mut terms: Vec<SearchTerm> = vec![];
+    let mut pending_or = false;
+    let mut rest = query.trim_start();
+
+    while !rest.is_empty() {
+        let (term, remaining) = next_term(rest)?;
+        rest = remaining.trim_start();
+
+        if term == SearchTerm::Word("OR".to_string()) {
+            if pending_or || terms.is_empty() {
+                return Err(format!("dangling OR in search query `{query}`"));
+            }
+            pending_or = true;
+            continue;
+        }
+        if pending_or {
+            pending_or = false;
+            match terms.pop() {
+                Some(SearchTerm::Or(mut alternatives)) => {
+                    alternatives.push(term);
+                    terms.push(SearchTerm::Or(alternatives));
+                }
+                Some(previous) => terms.push(SearchTerm::Or(vec![previous, term])),
+                None => unreachable!(),
+            }
+        } else {
+            terms.push(term);
+        }
+    }
+    if pending_or {
+        return Err(format!("dangling OR in search query `{query}`"));
+    }
+    Ok(terms)
+}
+
+fn next_term(data: &str) -> Result<(SearchTerm, &str), String> {
+    if let Some(rest) = data.strip_prefix('-') {
+        if rest.is_empty() || rest.starts_with(char::is_whitespace) {
+            return Err(format!("nothing to exclude after `-` in `{data}`"));
+        }
+        let (term, rest) = next_term(rest)?;
+        return Ok((SearchTerm::Exclude(Box::new(term)), rest));
+    }
+    if let Some(rest) = data.strip_prefix('"') {
+        return match rest.find('"') {
+            Some(end) => Ok((SearchTerm::Phrase(rest[..end].to_string()), &rest[end + 1..])),
+            None => Err(format!("unterminated phrase in search query `{data}`")),
+        };
+    }
+    let end = data
+        .find(|c: char| c.is_whitespace() || c == '"')
+        .unwrap_or(data.len());
+    Ok((SearchTerm::Word(data[..end].to_string()), &data[end..]))
+}
+
+/// Strips the quotes of a `quots` argument and unescapes doubled single quotes.
+pub(crate) fn unquote(quoted: &str) -> String {
+    quoted
+        .strip_prefix('\'')
+        .and_then(|s| s.strip_suffix('\''))
+        .unwrap_or(quoted)
+        .replace("''", "'")
+}
@@ -0,0 +1,326 @@
+use pest::Parser;
+use pest::iterators::Pair;
+use crate::Flql;
+use crate::gjson::util::escape_path;
+
+#[derive(Parser)]
+#[grammar = "./sql.pest"]
+struct SqlParser;
+
+/// A SQL statement compiled to FLQL.
+#[derive(PartialEq, Debug, Clone)]
+pub struct SqlStatement {
+    /// The equivalent FLQL statement.
+    pub flql: Flql,
+    /// The selected columns as a gjson multipath, eg. `{name,address.city}`. `None` for `SELECT *`.
+    pub projection: Option<String>,
+}
+
+const UNSUPPORTED: [&str; 12] = [
+    "UPDATE", "JOIN", "GROUP", "HAVING", "UNION", "DISTINCT", "CREATE", "DROP", "ALTER", "CASE",
+    "EXISTS", "RETURNING",
+];
+
+/// Compiles a practical subset of SQL to FLQL.
+///
+/// `SELECT cols FROM c WHERE ... ORDER BY ... LIMIT ... OFFSET ...` <br>
+/// `SELECT COUNT(*) FROM c` <br>
+/// `INSERT INTO c VALUES ({...})` <br>
+/// `DELETE FROM c WHERE ...` <br>
+///
+/// # Errors
+///
+/// Will return `Err` if the statement is not valid or uses unsupported SQL.
+pub fn parse(sql: &str) -> Result<SqlStatement, String> {
+    let pair = match SqlParser::parse(Rule::sql, sql) {
+        Ok(mut pairs) => pairs.next().unwrap(),
+        Err(e) => {
+            return Err(match unsupported_keyword(sql) {
+                Some(keyword) => format!("unsupported SQL: {keyword}"),
+                None => format!("{e}"),
+            });
+        }
+    };
+
+    match pair.as_rule() {
+        Rule::select_count => {
+            let mut inner = pair.into_inner();
+            let collection = collection(inner.next().unwrap());
+            if inner.next().is_some() {
+                return Err("unsupported SQL: SELECT COUNT(*) with WHERE".to_string());
+            }
+            Ok(SqlStatement { flql: Flql::Length(collection), projection: None })
+        }
+        Rule::select => select(pair),
+        Rule::insert => {
+            let mut inner = pair.into_inner();
+            let collection = collection(inner.next().unwrap());
+            let document = inner.next().unwrap().as_str().to_string();
+            Ok(SqlStatement { flql: Flql::Put(document, collection), projection: None })
+        }
+        Rule::delete => {
+            let mut inner = pair.into_inner();
+            let collection = collection(inner.next().unwrap());
+            let flql = match inner.next() {
+                Some(clause) => Flql::DeleteWhen(where_clause(clause)?, collection),
+                None => Flql::Delete(collection),
+            };
+            Ok(SqlStatement { flql, projection: None })
+        }
+        _ => Err("failed to parse".to_owned()),
+    }
+}
+
+fn select(pair: Pair<Rule>) -> Result<SqlStatement, String> {
+    let mut inner = pair.into_inner();
+    let projection = projection(inner.next().unwrap())?;
+    let collection = collection(inner.next().unwrap());
+
+    let mut condition = None;
+    let mut sort = None;
+    let mut limit = None;
+    let mut offset = None;
+    for clause in inner {
+        match clause.as_rule() {
+            Rule::where_clause => condition = Some(where_clause(clause)?),
+            Rule::order_by => sort = Some(order_by(clause)?),
+            Rule::limit => limit = Some(clause.into_inner().next().unwrap().as_str().to_string()),
+            Rule::offset => offset = Some(clause.into_inner().next().unwrap().as_str().to_string()),
+            _ => {}
+        }
+    }
+    let page = match (offset, limit) {
+        (None, None) => None,
+        (offset, limit) => Some(format!(
+            "{},{}",
+            offset.unwrap_or_else(|| "0".to_string()),
+            limit.unwrap_or_default()
+        )),
+    };
+
+    let flql = match condition {
+        Some(condition) => Flql::GetWhen(condition, collection, sort, page),
+        None => Flql::Get(collection, sort, page),
+    };
+    Ok(SqlStatement { flql, projection })
+}
+
+fn projection(pair: Pair<Rule>) -> Result<Option<String>, String> {
+    let mut columns = vec![];
+    for column in pair.into_inner() {
+        if column.as_rule() == Rule::star {
+            return Ok(None);
+        }
+        columns.push(path(column)?);
+    }
+    Ok(Some(format!("{{{}}}", columns.join(","))))
+}
+
+fn order_by(pair: Pair<Rule>) -> Result<String, String> {
+    let mut orderings = pair.into_inner();
+    let ordering = orderings.next().unwrap();
+    if orderings.next().is_some() {
+        return Err("unsupported SQL: ORDER BY on more than one column".to_string());
+    }
+    let mut inner = ordering.into_inner();
+    let column = path(inner.next().unwrap())?;
+    let direction = match inner.next().and_then(|d| d.into_inner().next()) {
+        Some(d) if d.as_rule() == Rule::desc => "DESC",
+        _ => "ASC",
+    };
+    Ok(format!("{},'{direction}'", quote(&column)))
+}
+
+fn where_clause(pair: Pair<Rule>) -> Result<String, String> {
+    let condition = or_expr(pair.into_inner().next().unwrap(), false)?;
+    crate::expr_parse(&condition).map_err(|e| format!("invalid WHERE clause `{condition}`: {e}"))?;
+    Ok(quote(&condition))
+}
+
+// A condition on a missing or NULL column is unknown in SQL, so neither it nor its negation holds.
+// FLQL conditions are false on a missing field, so each function compiles a condition when
+// `negated` is false and its negation, with the columns it reads guarded by `IS NOT NULL`, when
+// `negated` is true. NOT flips `negated`, and by De Morgan a negated OR is an AND of negations.
+
+fn or_expr(pair: Pair<Rule>, negated: bool) -> Result<String, String> {
+    join(pair, if negated { "&&" } else { "||" }, negated, and_expr)
+}
+
+fn and_expr(pair: Pair<Rule>, negated: bool) -> Result<String, String> {
+    join(pair, if negated { "||" } else { "&&" }, negated, not_expr)
+}
+
+fn join(
+    pair: Pair<Rule>,
+    op: &str,
+    negated: bool,
+    operand: fn(Pair<Rule>, bool) -> Result<String, String>,
+) -> Result<String, String> {
+    let mut inner = pair
+        .into_inner()
+        .filter(|p| !matches!(p.as_rule(), Rule::and_op | Rule::or_op));
+    let mut expression = operand(inner.next().unwrap(), negated)?;
+    for next in inner {
+        expression = format!("({expression} {op} {})", operand(next, negated)?);
+    }
+    Ok(expression)
+}
+
+fn not_expr(pair: Pair<Rule>, negated: bool) -> Result<String, String> {
+    let mut inner = pair.into_inner();
+    let first = inner.next().unwrap();
+    match first.as_rule() {
+        Rule::not_op => not_expr(inner.next().unwrap(), !negated),
+        _ => predicate(first, negated),
+    }
+}
+
+fn predicate(pair: Pair<Rule>, negated: bool) -> Result<String, String> {
+    let rule = pair.as_rule();
+    if rule == Rule::or_expr {
+        return or_expr(pair, negated);
+    }
+    let mut inner = pair.into_inner().peekable();
+    let (not, columns, expression) = match rule {
+        Rule::comparison => {
+            let left = inner.next().unwrap();
+            let op = inner.next().unwrap().as_str();
+            let right = inner.next().unwrap();
+            let columns = [&left, &right]
+                .into_iter()
+                .filter(|p| p.as_rule() == Rule::column)
+                .map(|p| path(p.clone()))
+                .collect::<Result<Vec<_>, _>>()?;
+            let (left, right) = (operand(left)?, operand(right)?);
+            match op {
+                "=" => (false, columns, format!("({left} == {right})")),
+                "<>" | "!=" => (true, columns, format!("({left} == {right})")),
+                op => (false, columns, format!("({left} {op} {right})")),
+            }
+        }
+        Rule::between => {
+            let column = path(inner.next().unwrap())?;
+            let not = inner.next_if(|p| p.as_rule() == Rule::not_op).is_some();
+            let low = operand(inner.next().unwrap())?;
+            let _and = inner.next();
+            let high = operand(inner.next().unwrap())?;
+            let expression = format!("((.{column} >= {low}) && (.{column} <= {high}))");
+            (not, vec![column], expression)
+        }
+        Rule::in_list => {
+            let column = path(inner.next().unwrap())?;
+            let not = inner.next_if(|p| p.as_rule() == Rule::not_op).is_some();
+            let values = inner.map(operand).collect::<Result<Vec<_>, _>>()?;
+            let expression = format!("(.{column} IN [{}])", values.join(", "));
+            (not, vec![column], expression)
+        }
+        Rule::like => {
+            let column = path(inner.next().unwrap())?;
+            let not = inner.next_if(|p| p.as_rule() == Rule::not_op).is_some();
+            let expression = like(&column, inner.next().unwrap().as_str())?;
+            (not, vec![column], expression)
+        }
+        Rule::is_null => {
+            let column = path(inner.next().unwrap())?;
+            let not = inner.next_if(|p| p.as_rule() == Rule::not_op).is_some();
+            // IS NULL is never unknown, and is true of a missing column too.
+            return Ok(if not == negated {
+                format!("(.{column} IS NULL || .{column} IS MISSING)")
+            } else {
+                format!("(.{column} IS NOT NULL)")
+            });
+        }
+        _ => return Err(format!("unsupported SQL: {}", inner.map(|p| p.as_str()).collect::<String>())),
+    };
+    if not == negated {
+        return Ok(expression);
+    }
+    let mut guarded = columns
+        .iter()
+        .map(|column| format!("(.{column} IS NOT NULL)"))
+        .collect::<Vec<_>>();
+    guarded.push(format!("!{expression}"));
+    Ok(match guarded.len() {
+        1 => guarded.pop().unwrap(),
+        _ => format!("({})", guarded.join(" && ")),
+    })
+}
+
+/// `LIKE` with the `*` and `?` wildcards of FLQL for `%` and `_`, escaping a literal `*`, `?` or `\`.
+fn like(column: &str, pattern: &str) -> Result<String, String> {
+    let mut wildcards = String::with_capacity(pattern.len());
+    for c in sql_string(pattern).chars() {
+        match c {
+            '%' => wildcards.push('*'),
+            '_' => wildcards.push('?'),
+            '*' | '?' | '\\' => {
+                wildcards.push('\\');
+                wildcards.push(c);
+            }
+            c => wildcards.push(c),
+        }
+    }
+    Ok(format!("(.{column} LIKE {})", string_literal(&wildcards)?))
+}
+
+fn operand(pair: Pair<Rule>) -> Result<String, String> {
+    match pair.as_rule() {
+        Rule::column => Ok(format!(".{}", path(pair)?)),
+        Rule::string => string_literal(&sql_string(pair.as_str())),
+        Rule::number => Ok(pair.as_str().to_string()),
+        Rule::boolean => Ok(pair.as_str().to_lowercase()),
+        Rule::null => Ok("NULL".to_string()),
+        _ => Err(format!("unsupported SQL: {}", pair.as_str())),
+    }
+}
+
+/// Turns a column into a gjson path, escaping the characters gjson treats specially.
+fn path(pair: Pair<Rule>) -> Result<String, String> {
+    let mut components = vec![];
+    for ident in pair.into_inner() {
+        let ident = ident.as_str();
+        let name = match ident.chars().next() {
+            Some('"' | '`') => &ident[1..ident.len() - 1],
+            _ => ident,
+        };
+        if name.contains(|c: char| c.is_whitespace() || matches!(c, '(' | ')' | '[' | ']' | ',')) {
+            return Err(format!("unsupported SQL: column name {ident}"));
+        }
+        components.push(escape_path(name));
+    }
+    Ok(components.join("."))
+}
+
+fn collection(pair: Pair<Rule>) -> String {
+    let ident = pair.into_inner().next().unwrap().as_str();
+    match ident.chars().next() {
+        Some('"' | '`') => quote(&ident[1..ident.len() - 1]),
+        _ => quote(ident),
+    }
+}
+
+/// Unescapes a single quoted SQL string.
+fn sql_string(quoted: &str) -> String {
+    quoted[1..quoted.len() - 1].replace("''", "'")
+}
+
+/// Writes a string literal of the expression language.
+fn string_literal(s: &str) -> Result<String, String> {
+    if !s.contains('"') {
+        Ok(format!("\"{s}\""))
+    } else if !s.contains('\'') {
+        Ok(format!("'{s}'"))
+    } else {
+        Err(format!("unsupported SQL: string containing both quote characters {s}"))
+    }
+}
+
+/// Quotes an argument the way FLQL does.
+fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "''"))
+}
+
+fn unsupported_keyword(sql: &str) -> Option<&'static str> {
+    sql.split(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+        .find_map(|word| UNSUPPORTED.iter().find(|k| k.eq_ignore_ascii_case(word)).copied())
+}
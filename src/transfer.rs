@@ -0,0 +1,262 @@
+use std::io::{BufRead, Write};
+use anyhow::anyhow;
+use crate::exp_parser::{Expression, Value};
+use crate::gjson::gjson::{for_each_line, get, parse, Kind};
+use crate::gjson::pretty::ugly;
+use crate::gjson::util::escape_path;
+use crate::gjson::valid::valid;
+
+/// Number of bytes of JSON Lines buffered before the records are handed over.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// File format of `import` and `export` statements.
+#[derive(PartialEq, Debug, Clone)]
+pub enum TransferFormat {
+    /// One JSON document per line.
+    JsonLines,
+    /// Comma separated values with a header row. When exporting, the columns are
+    /// gjson paths. Empty takes the keys of the first exported record, so keys only
+    /// later records have are left out.
+    ///
+    /// A missing or null value is an empty field, and an empty field is imported as null.
+    /// Strings that would be imported as something else, eg. `""`, `"42"` or `"true"`, are
+    /// exported quoted, and a quoted field is always imported as a string. Objects and
+    /// arrays are exported as their JSON and imported as strings.
+    Csv(Vec<String>),
+}
+
+impl TransferFormat {
+    /// Picks the format from the file extension, JSON Lines unless it is `.csv`.
+    pub fn from_path(path: &str) -> Self {
+        if path.to_lowercase().ends_with(".csv") {
+            TransferFormat::Csv(vec![])
+        } else {
+            TransferFormat::JsonLines
+        }
+    }
+}
+
+/// Reads records from `reader` and hands each one to `sink` as a JSON document.
+///
+/// JSON Lines are buffered in chunks of whole lines and walked with the gjson `..` support,
+/// so the file is never loaded into memory at once.
+///
+/// # Errors
+///
+/// Will return `Err` if reading fails, a record is not valid or `sink` fails.
+pub fn import<R, F>(mut reader: R, format: &TransferFormat, mut sink: F) -> anyhow::Result<usize>
+    where
+        R: BufRead,
+        F: FnMut(&str) -> anyhow::Result<()>,
+{
+    match format {
+        TransferFormat::JsonLines => {
+            let mut count = 0;
+            let mut chunk = String::new();
+            loop {
+                let read = reader.read_line(&mut chunk)?;
+                if read > 0 && chunk.len() < CHUNK_SIZE {
+                    continue;
+                }
+                let mut result = Ok(());
+                for_each_line(&chunk, |record| {
+                    let json = record.json();
+                    result = if valid(json) {
+                        count += 1;
+                        sink(json)
+                    } else {
+                        Err(anyhow!("invalid JSON in record {}: {json}", count + 1))
+                    };
+                    result.is_ok()
+                });
+                result?;
+                chunk.clear();
+                if read == 0 {
+                    return Ok(count);
+                }
+            }
+        }
+        TransferFormat::Csv(_) => {
+            let Some(header) = read_csv_record(&mut reader)? else {
+                return Ok(0);
+            };
+            let mut count = 0;
+            while let Some(fields) = read_csv_record(&mut reader)? {
+                if fields.len() != header.len() {
+                    return Err(anyhow!(
+                        "CSV record {} has {} fields, expected {}",
+                        count + 1,
+                        fields.len(),
+                        header.len()
+                    ));
+                }
+                let mut json = String::from("{");
+                for (i, ((column, _), (field, quoted))) in header.iter().zip(&fields).enumerate() {
+                    if i > 0 {
+                        json.push(',');
+                    }
+                    json.push_str(&serde_json::to_string(column)?);
+                    json.push(':');
+                    json.push_str(&csv_field_to_json(field, *quoted)?);
+                }
+                json.push('}');
+                count += 1;
+                sink(&json)?;
+            }
+            Ok(count)
+        }
+    }
+}
+
+/// Writes the `records` matching `condition` to `writer`, returning the number written.
+///
+/// # Errors
+///
+/// Will return `Err` if writing fails or `condition` cannot be evaluated or is not a boolean.
+pub fn export<I, R, W>(
+    records: I,
+    condition: Option<&dyn Expression>,
+    format: &TransferFormat,
+    mut writer: W,
+) -> anyhow::Result<usize>
+    where
+        I: IntoIterator<Item=R>,
+        R: AsRef<str>,
+        W: Write,
+{
+    let mut columns = match format {
+        TransferFormat::Csv(columns) if !columns.is_empty() => Some(columns.clone()),
+        _ => None,
+    };
+    if let Some(columns) = &columns {
+        write_csv_record(&mut writer, columns.iter().map(|c| (c.as_str(), false)))?;
+    }
+
+    let mut count = 0;
+    for record in records {
+        let record = record.as_ref();
+        if let Some(condition) = condition {
+            match condition.calculate(record.as_bytes())? {
+                Value::Bool(true) => {}
+                Value::Bool(false) => continue,
+                v => return Err(anyhow!("export condition must be a boolean, found {v}")),
+            }
+        }
+        match format {
+            TransferFormat::JsonLines => {
+                if record.contains(['\n', '\r']) {
+                    writeln!(writer, "{}", ugly(record))?;
+                } else {
+                    writeln!(writer, "{}", record.trim())?;
+                }
+            }
+            TransferFormat::Csv(_) => {
+                if columns.is_none() {
+                    let mut keys = vec![];
+                    parse(record).each(|k, _| {
+                        keys.push(k.str().to_string());
+                        true
+                    });
+                    write_csv_record(&mut writer, keys.iter().map(|k| (k.as_str(), false)))?;
+                    columns = Some(keys.iter().map(|k| escape_path(k)).collect());
+                }
+                let fields: Vec<(String, bool)> = columns
+                    .iter()
+                    .flatten()
+                    .map(|column| {
+                        let value = get(record, column);
+                        match value.kind() {
+                            Kind::Null => (String::new(), false),
+                            Kind::String => (value.str().to_string(), !reads_as_string(value.str())),
+                            _ => (value.json().to_string(), false),
+                        }
+                    })
+                    .collect();
+                write_csv_record(&mut writer, fields.iter().map(|(f, q)| (f.as_str(), *q)))?;
+            }
+        }
+        count += 1;
+    }
+    writer.flush()?;
+    Ok(count)
+}
+
+/// The JSON of a CSV field, a string when it was `quoted`.
+fn csv_field_to_json(field: &str, quoted: bool) -> anyhow::Result<String> {
+    Ok(match field {
+        _ if quoted || reads_as_string(field) => serde_json::to_string(field)?,
+        "" => "null".to_string(),
+        _ => field.to_string(),
+    })
+}
+
+/// Whether an unquoted CSV field is imported as a string, rather than null, a boolean or a number.
+fn reads_as_string(field: &str) -> bool {
+    !matches!(field, "" | "true" | "false") && field.parse::<serde_json::Number>().is_err()
+}
+
+/// Writes the fields of a record, quoting those that are `quoted` or need it.
+fn write_csv_record<'a, W, I>(writer: &mut W, fields: I) -> std::io::Result<()>
+    where
+        W: Write,
+        I: IntoIterator<Item=(&'a str, bool)>,
+{
+    for (i, (field, quoted)) in fields.into_iter().enumerate() {
+        if i > 0 {
+            writer.write_all(b",")?;
+        }
+        if quoted || field.contains([',', '"', '\n', '\r']) {
+            write!(writer, "\"{}\"", field.replace('"', "\"\""))?;
+        } else {
+            writer.write_all(field.as_bytes())?;
+        }
+    }
+    writer.write_all(b"\n")
+}
+
+/// Reads one CSV record, which spans several lines when a quoted field contains line breaks.
+/// Each field comes with whether it was quoted.
+fn read_csv_record<R: BufRead>(reader: &mut R) -> std::io::Result<Option<Vec<(String, bool)>>> {
+    let mut line = String::new();
+    loop {
+        if reader.read_line(&mut line)? == 0 {
+            break;
+        }
+        // an odd number of quotes means a quoted field continues on the next line
+        if line.matches('"').count() % 2 == 1 {
+            continue;
+        }
+        // skip blank lines
+        if line.trim_end_matches(['\n', '\r']).is_empty() {
+            line.clear();
+            continue;
+        }
+        break;
+    }
+    let line = line.trim_end_matches(['\n', '\r']);
+    if line.is_empty() {
+        return Ok(None);
+    }
+
+    let mut fields = vec![];
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut was_quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            '"' => {
+                quoted = !quoted;
+                was_quoted = true;
+            }
+            ',' if !quoted => fields.push((std::mem::take(&mut field), std::mem::take(&mut was_quoted))),
+            c => field.push(c),
+        }
+    }
+    fields.push((field, was_quoted));
+    Ok(Some(fields))
+}
@@ -0,0 +1,803 @@
+use std::mem;
+use regex::Regex;
+use crate::ast::{BinaryOp, Expr, IterateOp, UnaryOp};
+use crate::exp_parser::{
+    between, binary, call, check_arity, compile_regex, holds, host_call, is_constant, iterate,
+    matches, object, short_circuits, unary, Coerce, EvalContext, Expression, LiteralSet,
+    ParseOptions, Result, Value,
+};
+use crate::functions::{self, Builtin};
+use crate::number;
+use crate::document::{is_keys, Document};
+use crate::gjson::gjson::{self, RawMembers};
+use crate::gjson::util::tostr;
+
+/// An expression compiled into instructions for a stack machine, which `Parser::parse` returns.
+///
+/// Unlike the tree of `Parser::compile`, where every node calculates its operands:
+///
+/// - the selector paths are read from the document in a single pass, only as far into it as the
+///   instructions need, and each path only once however often the expression uses it;
+/// - `&&` and `||` jump over their right side when their left side gives the result;
+/// - the instructions run in one loop over a stack of values sized when compiled, which like the
+///   values of the paths is not allocated for most expressions.
+///
+/// ```
+/// use flql::exp_parser::{Expression, ParseOptions, Parser, Value};
+/// use flql::vm::Program;
+///
+/// let expr = Parser::parse_expr(".age >= 18 && .name.first STARTS_WITH 'A'").unwrap();
+/// let program = Program::compile(expr, ParseOptions::default()).unwrap();
+/// assert_eq!(
+///     program.calculate(br#"{"name":{"first":"Ann"},"age":20}"#),
+///     Ok(Value::Bool(true))
+/// );
+/// ```
+#[derive(Debug)]
+pub struct Program {
+    code: Vec<Instr>,
+    paths: Paths,
+    /// The most values on the stack at once.
+    stack_size: usize,
+    /// Whether the program only applies operations of one operand in turn to the value of its
+    /// only path, eg. `.age > 30`, so that it runs without a stack or slots.
+    chain: bool,
+    missing_as_null: bool,
+}
+
+/// An instruction of a `Program`, each pushes one value after popping its operands.
+#[derive(Debug)]
+enum Instr {
+    Const(Value),
+    /// The value of the path of a slot.
+    Load(usize),
+    /// Like `Load`, moving the value out of the slot when nothing else uses it.
+    Take(usize),
+    Exists(usize),
+    Missing(usize),
+    Variable(String),
+    Now,
+    /// Pops that many values into an array.
+    Array(usize),
+    /// Pops the values of the keys into an object.
+    Object(Box<[String]>),
+    Unary(UnaryOp),
+    Binary(BinaryOp),
+    /// Like `Binary` with a constant right side, which is not pushed.
+    BinaryConst(BinaryOp, Value),
+    /// Replaces the left side of `&&` or `||` with the result and jumps to the instruction after
+    /// the operator when it short-circuits, keeps it for the right side otherwise.
+    ShortCircuit(BinaryOp, usize),
+    InSet(Box<LiteralSet>),
+    Between,
+    Matches(Box<Regex>),
+    Coerce(Box<Coerce>),
+    /// Calls a function with that many arguments.
+    Call(Box<Builtin>, usize),
+    HostCall(String, usize),
+    /// Runs the program of the body for each element of the array, with its own paths read from
+    /// the element.
+    Iterate(IterateOp, Box<Program>),
+    /// Jumps to the instruction, without pushing a value.
+    Jump(usize),
+    /// Pops the condition of a `CASE` and jumps to the instruction unless it is `true`, without
+    /// pushing a value.
+    JumpUnless(usize),
+}
+
+impl Instr {
+    /// Whether the instruction pops a single value and pushes the result of an operation on it.
+    fn is_operation(&self) -> bool {
+        matches!(
+            self,
+            Instr::Unary(_)
+                | Instr::BinaryConst(..)
+                | Instr::InSet(_)
+                | Instr::Matches(_)
+                | Instr::Coerce(_)
+        )
+    }
+
+    /// The result of an operation on the value, see `is_operation`.
+    fn apply(&self, value: Value) -> Result<Value> {
+        match self {
+            Instr::Unary(op) => unary(*op, value),
+            Instr::BinaryConst(op, right) => binary(*op, value, right.clone()),
+            Instr::InSet(set) => Ok(set.find(&value)),
+            Instr::Matches(regex) => matches(value, regex),
+            Instr::Coerce(to) => to.apply(value),
+            _ => unreachable!("not an operation: {self:?}"),
+        }
+    }
+}
+
+impl Program {
+    /// compiles an `Expr`, eg. one returned by `Parser::parse_expr`, with the supplied
+    /// `ParseOptions`.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the `Expr` is invalid, such as an unknown time zone or a built-in
+    /// function called with the wrong number of arguments.
+    pub fn compile(expr: Expr, options: ParseOptions) -> anyhow::Result<Program> {
+        let mut compiler = Compiler {
+            options,
+            ..Compiler::default()
+        };
+        compiler.expr(expr)?;
+
+        let Compiler {
+            mut code,
+            paths,
+            uses,
+            stack_size,
+            ..
+        } = compiler;
+        for instr in &mut code {
+            if let Instr::Load(slot) = *instr {
+                if uses[slot] == 1 {
+                    *instr = Instr::Take(slot);
+                }
+            }
+        }
+        let chain = matches!(code.first(), Some(Instr::Take(0)))
+            && code[1..].iter().all(Instr::is_operation);
+        Ok(Program {
+            code,
+            paths: Paths::new(paths),
+            stack_size,
+            chain,
+            missing_as_null: options.missing_as_null,
+        })
+    }
+
+    fn path_value(&self, slot: Slot) -> Value {
+        match slot {
+            Slot::Found(value) => value,
+            _ if self.missing_as_null => gjson::Value::default().into(),
+            _ => Value::Missing,
+        }
+    }
+
+    fn run(
+        &self,
+        document: &dyn Document,
+        ctx: &EvalContext,
+        slots: &mut [Slot],
+        values: &mut [Value],
+    ) -> Result<Value> {
+        let mut stack = Stack { values, len: 0 };
+        let mut paths = Reader::new(&self.paths, document, slots);
+        let mut next = 0;
+
+        while let Some(instr) = self.code.get(next) {
+            next += 1;
+            let value = match instr {
+                Instr::Const(value) => value.clone(),
+                Instr::Load(slot) => self.path_value(paths.get(*slot).clone()),
+                Instr::Take(slot) => self.path_value(mem::replace(paths.get(*slot), Slot::Missing)),
+                Instr::Exists(slot) => Value::Bool(matches!(paths.get(*slot), Slot::Found(_))),
+                Instr::Missing(slot) => Value::Bool(matches!(paths.get(*slot), Slot::Missing)),
+                Instr::Variable(name) => ctx.variable(name)?,
+                Instr::Now => Value::DateTime(ctx.now().fixed_offset()),
+                Instr::Array(count) => Value::Array(stack.pop_many(*count)),
+                Instr::Object(keys) => object(keys.iter(), stack.pop_many(keys.len())),
+                Instr::Unary(_)
+                | Instr::BinaryConst(..)
+                | Instr::InSet(_)
+                | Instr::Matches(_)
+                | Instr::Coerce(_) => instr.apply(stack.pop())?,
+                Instr::Binary(op) => {
+                    let right = stack.pop();
+                    binary(*op, stack.pop(), right)?
+                }
+                Instr::ShortCircuit(op, end) => {
+                    let left = stack.pop();
+                    if short_circuits(*op, &left) {
+                        next = *end;
+                        Value::Bool(*op == BinaryOp::Or)
+                    } else {
+                        left
+                    }
+                }
+                Instr::Between => {
+                    let high = stack.pop();
+                    let low = stack.pop();
+                    between(stack.pop(), low, high)?
+                }
+                Instr::Call(builtin, count) => {
+                    let value = call(builtin, stack.top(*count))?;
+                    stack.truncate(stack.len - count);
+                    value
+                }
+                Instr::HostCall(name, count) => {
+                    let value = host_call(name, stack.top(*count), ctx)?;
+                    stack.truncate(stack.len - count);
+                    value
+                }
+                Instr::Iterate(op, body) => {
+                    iterate(*op, stack.pop(), |element| body.calculate_document(element, ctx))?
+                }
+                Instr::Jump(to) => {
+                    next = *to;
+                    continue;
+                }
+                Instr::JumpUnless(to) => {
+                    if !holds(stack.pop())? {
+                        next = *to;
+                    }
+                    continue;
+                }
+            };
+            stack.push(value);
+        }
+        Ok(stack.pop())
+    }
+}
+
+impl Expression for Program {
+    fn calculate_document(&self, document: &dyn Document, ctx: &EvalContext) -> Result<Value> {
+        if self.chain {
+            let path = document.get(&self.paths.paths[0]);
+            let value = self.path_value(path.map_or(Slot::Missing, Slot::Found));
+            return self.code[1..]
+                .iter()
+                .try_fold(value, |value, instr| instr.apply(value));
+        }
+        let mut inline_slots = [const { Slot::Unread }; INLINE];
+        let mut inline_values = [const { Value::Null }; INLINE];
+        let (mut more_slots, mut more_values) = (Vec::new(), Vec::new());
+        self.run(
+            document,
+            ctx,
+            buffer(&mut inline_slots, &mut more_slots, self.paths.paths.len(), Slot::Unread),
+            buffer(&mut inline_values, &mut more_values, self.stack_size, Value::Null),
+        )
+    }
+}
+
+/// The number of values most expressions need at once, kept on the stack of the thread instead
+/// of allocated for each document.
+const INLINE: usize = 8;
+
+/// The first `len` values of `inline`, or of `more` filled with `fill` when they do not fit.
+fn buffer<'a, T: Clone>(
+    inline: &'a mut [T; INLINE],
+    more: &'a mut Vec<T>,
+    len: usize,
+    fill: T,
+) -> &'a mut [T] {
+    if len <= INLINE {
+        &mut inline[..len]
+    } else {
+        more.resize(len, fill);
+        more
+    }
+}
+
+/// The values of the instructions, with room for as many as the program needs at once.
+struct Stack<'a> {
+    values: &'a mut [Value],
+    len: usize,
+}
+
+impl Stack<'_> {
+    fn push(&mut self, value: Value) {
+        self.values[self.len] = value;
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Value {
+        self.len -= 1;
+        mem::replace(&mut self.values[self.len], Value::Null)
+    }
+
+    fn pop_many(&mut self, count: usize) -> Vec<Value> {
+        self.len -= count;
+        self.values[self.len..self.len + count]
+            .iter_mut()
+            .map(|value| mem::replace(value, Value::Null))
+            .collect()
+    }
+
+    /// The last `count` values, which the caller drops from the stack once it is done with them.
+    fn top(&self, count: usize) -> &[Value] {
+        &self.values[self.len - count..self.len]
+    }
+
+    fn truncate(&mut self, len: usize) {
+        for value in &mut self.values[len..self.len] {
+            *value = Value::Null;
+        }
+        self.len = len;
+    }
+}
+
+#[derive(Default)]
+struct Compiler {
+    options: ParseOptions,
+    code: Vec<Instr>,
+    /// The path of each slot.
+    paths: Vec<String>,
+    /// The number of instructions using each slot.
+    uses: Vec<usize>,
+    depth: usize,
+    stack_size: usize,
+}
+
+impl Compiler {
+    fn emit(&mut self, instr: Instr, pops: usize) {
+        self.depth = self.depth - pops + 1;
+        self.stack_size = self.stack_size.max(self.depth);
+        self.code.push(instr);
+    }
+
+    /// Emits a jump, which pops `pops` values and pushes none.
+    fn jump(&mut self, instr: Instr, pops: usize) {
+        self.depth -= pops;
+        self.code.push(instr);
+    }
+
+    fn slot(&mut self, path: String) -> usize {
+        let slot = match self.paths.iter().position(|p| *p == path) {
+            Some(slot) => slot,
+            None => {
+                self.paths.push(path);
+                self.uses.push(0);
+                self.paths.len() - 1
+            }
+        };
+        self.uses[slot] += 1;
+        slot
+    }
+
+    fn expr(&mut self, expr: Expr) -> anyhow::Result<()> {
+        match expr {
+            Expr::Literal(value) => self.emit(Instr::Const(value), 0),
+            Expr::Path(path) => {
+                let slot = self.slot(path);
+                self.emit(Instr::Load(slot), 0);
+            }
+            Expr::Variable(name) => self.emit(Instr::Variable(name), 0),
+            Expr::Array(items) => {
+                let count = items.len();
+                for item in items {
+                    self.expr(item)?;
+                }
+                self.emit(Instr::Array(count), count);
+            }
+            Expr::Object(members) => {
+                let count = members.len();
+                let mut keys = Vec::with_capacity(count);
+                for (key, value) in members {
+                    keys.push(key);
+                    self.expr(value)?;
+                }
+                self.emit(Instr::Object(keys.into()), count);
+            }
+            Expr::Unary { op, expr } => {
+                self.expr(*expr)?;
+                self.emit(Instr::Unary(op), 1);
+            }
+            Expr::Binary {
+                op: op @ (BinaryOp::In | BinaryOp::NotIn),
+                left,
+                right,
+            } if LiteralSet::new(op, &right).is_some() => {
+                self.expr(*left)?;
+                let set = LiteralSet::new(op, &right).unwrap_or_default();
+                self.emit(Instr::InSet(Box::new(set)), 1);
+            }
+            Expr::Binary {
+                op: op @ (BinaryOp::And | BinaryOp::Or),
+                left,
+                right,
+            } => {
+                self.expr(*left)?;
+                let jump = self.code.len();
+                self.emit(Instr::ShortCircuit(op, 0), 1);
+                self.expr(*right)?;
+                self.emit(Instr::Binary(op), 2);
+                self.code[jump] = Instr::ShortCircuit(op, self.code.len());
+            }
+            Expr::Binary { op, left, right } => {
+                self.expr(*left)?;
+                match *right {
+                    Expr::Literal(value) => self.emit(Instr::BinaryConst(op, value), 1),
+                    right => {
+                        self.expr(right)?;
+                        self.emit(Instr::Binary(op), 2);
+                    }
+                }
+            }
+            Expr::Between { expr, low, high } => {
+                self.expr(*expr)?;
+                self.expr(*low)?;
+                self.expr(*high)?;
+                self.emit(Instr::Between, 3);
+            }
+            // the regular expression is compiled once here instead of for every document
+            Expr::Matches { expr, pattern } => {
+                self.expr(*expr)?;
+                self.emit(Instr::Matches(Box::new(compile_regex(&pattern)?)), 1);
+            }
+            Expr::Exists(path) => {
+                let slot = self.slot(path);
+                self.emit(Instr::Exists(slot), 0);
+            }
+            Expr::Missing(path) => {
+                let slot = self.slot(path);
+                self.emit(Instr::Missing(slot), 0);
+            }
+            Expr::Coerce { expr, to } => {
+                let to = Coerce::new(to)?;
+                // a constant datetime is parsed once here
+                if matches!(to, Coerce::DateTime { .. }) && is_constant(&expr) {
+                    let value = Program::compile(*expr, self.options)?.calculate(&[])?;
+                    self.emit(Instr::Const(to.apply(value)?), 0);
+                } else {
+                    self.expr(*expr)?;
+                    self.emit(Instr::Coerce(Box::new(to)), 1);
+                }
+            }
+            Expr::Case { branches, otherwise } => {
+                // each branch starts from the depth before the `CASE` and ends one value deeper
+                let depth = self.depth;
+                let mut ends = Vec::new();
+                for (condition, value) in branches {
+                    self.expr(condition)?;
+                    let skip = self.code.len();
+                    self.jump(Instr::JumpUnless(0), 1);
+                    self.expr(value)?;
+                    ends.push(self.code.len());
+                    self.jump(Instr::Jump(0), 0);
+                    self.depth = depth;
+                    self.code[skip] = Instr::JumpUnless(self.code.len());
+                }
+                match otherwise {
+                    Some(otherwise) => self.expr(*otherwise)?,
+                    None => self.emit(Instr::Const(Value::Null), 0),
+                }
+                for end in ends {
+                    self.code[end] = Instr::Jump(self.code.len());
+                }
+            }
+            Expr::Iterate { op, array, body } => {
+                self.expr(*array)?;
+                let body = Program::compile(*body, self.options)?;
+                self.emit(Instr::Iterate(op, Box::new(body)), 1);
+            }
+            Expr::Call { name, args } => {
+                let count = args.len();
+                if name == "now" && count == 0 {
+                    self.emit(Instr::Now, 0);
+                    return Ok(());
+                }
+                check_arity(&name, count)?;
+                for arg in args {
+                    self.expr(arg)?;
+                }
+                match functions::lookup(&name) {
+                    Some(builtin) => self.emit(Instr::Call(Box::new(builtin), count), count),
+                    // resolved from the `EvalContext` when calculated
+                    None => self.emit(Instr::HostCall(name, count), count),
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The selector paths of a `Program`.
+#[derive(Debug, Default)]
+struct Paths {
+    /// The paths of keys and array indexes only, such as `name.first` or `tags.0`, as a tree of
+    /// their keys.
+    keys: Node,
+    /// The path of each slot.
+    paths: Vec<String>,
+    /// Whether gjson looks up the path of a slot itself, for queries, wildcards or modifiers such
+    /// as `friends.#(age>40)`, or for the only path of a program, which it finds without going
+    /// over the members before it.
+    queries: Vec<bool>,
+}
+
+#[derive(Debug, Default)]
+struct Node {
+    slot: Option<usize>,
+    children: Vec<(String, Node)>,
+}
+
+/// The value of a path in a document.
+#[derive(Debug, Clone)]
+enum Slot {
+    Unread,
+    Missing,
+    Found(Value),
+}
+
+impl Paths {
+    fn new(paths: Vec<String>) -> Paths {
+        let mut tree = Paths::default();
+        for (slot, path) in paths.iter().enumerate() {
+            tree.queries.push(paths.len() == 1 || !is_keys(path));
+            if tree.queries[slot] {
+                continue;
+            }
+            let mut node = &mut tree.keys;
+            for key in path.split('.') {
+                let child = match node.children.iter().position(|(k, _)| k == key) {
+                    Some(child) => child,
+                    None => {
+                        node.children.push((key.to_string(), Node::default()));
+                        node.children.len() - 1
+                    }
+                };
+                node = &mut node.children[child].1;
+            }
+            node.slot = Some(slot);
+        }
+        tree.paths = paths;
+        tree
+    }
+}
+
+/// Reads the paths of a `Program` from a document, from raw JSON in a single pass over it, only
+/// as far as the values asked for so far.
+struct Reader<'a> {
+    paths: &'a Paths,
+    document: &'a dyn Document,
+    json: Option<&'a [u8]>,
+    /// The members of raw JSON that are not read yet, `None` once it is read to the end or when it
+    /// is not an object.
+    members: Option<RawMembers<'a>>,
+    slots: &'a mut [Slot],
+}
+
+impl<'a> Reader<'a> {
+    fn new(paths: &'a Paths, document: &'a dyn Document, slots: &'a mut [Slot]) -> Reader<'a> {
+        let json = document.json();
+        let members = json.and_then(RawMembers::new);
+        if let (Some(json), None) = (json, &members) {
+            // eg. the indexes of an array
+            paths.keys.read(json, slots);
+        }
+        Reader {
+            paths,
+            document,
+            json,
+            members,
+            slots,
+        }
+    }
+
+    fn get(&mut self, slot: usize) -> &mut Slot {
+        let looked_up = self.paths.queries[slot] || self.json.is_none();
+        if let (Slot::Unread, true) = (&self.slots[slot], looked_up) {
+            self.slots[slot] = match self.document.get(&self.paths.paths[slot]) {
+                Some(value) => Slot::Found(value),
+                None => Slot::Missing,
+            };
+        }
+        while let Slot::Unread = self.slots[slot] {
+            match self.members.as_mut().and_then(Iterator::next) {
+                Some((key, value)) => {
+                    if let Some((_, node)) =
+                        self.paths.keys.children.iter().find(|(k, _)| *k == key)
+                    {
+                        node.read(value, self.slots);
+                        // the first of duplicate keys is the one gjson finds
+                        node.settle(self.slots);
+                    }
+                }
+                None => {
+                    self.members = None;
+                    self.slots[slot] = Slot::Missing;
+                }
+            }
+        }
+        &mut self.slots[slot]
+    }
+}
+
+impl Node {
+    /// Fills the unread slots of this node and those below it from the raw json of its value.
+    fn read(&self, json: &[u8], slots: &mut [Slot]) {
+        if let Some(slot) = self
+            .slot
+            .filter(|slot| matches!(slots[*slot], Slot::Unread))
+        {
+            slots[slot] = Slot::Found(value(json));
+        }
+        if self.children.is_empty() {
+            return;
+        }
+        if let Some(members) = RawMembers::new(json) {
+            for (key, value) in members {
+                if let Some((_, node)) = self.children.iter().find(|(k, _)| *k == key) {
+                    node.read(value, slots);
+                }
+            }
+        } else {
+            for (index, node) in &self.children {
+                let value = gjson::get(tostr(json), index);
+                if value.exists() {
+                    node.read(value.json().as_bytes(), slots);
+                }
+            }
+        }
+    }
+
+    /// Marks the slots of this node and those below it that are still unread as missing.
+    fn settle(&self, slots: &mut [Slot]) {
+        if let Some(slot) = self
+            .slot
+            .filter(|slot| matches!(slots[*slot], Slot::Unread))
+        {
+            slots[slot] = Slot::Missing;
+        }
+        for (_, node) in &self.children {
+            node.settle(slots);
+        }
+    }
+}
+
+/// The value of the raw json of a member, without parsing again the scalars that read as they
+/// are written.
+fn value(json: &[u8]) -> Value {
+    match json.first() {
+        Some(b'"') if !json.contains(&b'\\') => {
+            Value::String(tostr(&json[1..json.len() - 1]).to_string())
+        }
+        Some(b'0'..=b'9' | b'-') => {
+            number::from_json(tostr(json), || gjson::parse(tostr(json)).f64())
+        }
+        Some(b't') => Value::Bool(true),
+        Some(b'f') => Value::Bool(false),
+        Some(b'n') => Value::Null,
+        _ => gjson::parse(tostr(json)).into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Program;
+    use crate::exp_parser::{EvalContext, Expression, ParseOptions, Parser, Value};
+    use crate::functions::FunctionRegistry;
+
+    const DOCS: [&[u8]; 5] = [
+        br#"{"a":1,"b":2.5,"name":{"first":"Tom","last":"O\"Neil"},"tags":["x","y"],"ok":true,"at":"2023-01-01"}"#,
+        br#"{"a":"1","b":null,"name":{"first":"ann"},"tags":[],"ok":false,"friends":[{"age":44},{"age":12}]}"#,
+        br#"{"name":"flat","tags":["y"],"a":-3,"a":4}"#,
+        br#"[1,2,3]"#,
+        b"",
+    ];
+
+    const EXPRESSIONS: [&str; 31] = [
+        ".a",
+        ".a + 1 > .b",
+        ".a > 0 && .ok",
+        ".ok || .a > 0",
+        ".a == 1 && .a != 2 && EXISTS .a",
+        ".name.first STARTS_WITH 'T' || .name.last ENDS_WITH 'l'",
+        ".name.first ILIKE 'a*'",
+        ".tags.0 == 'x' && .tags.1 == 'y'",
+        ".tags CONTAINS 'y'",
+        ".tags.5 IS MISSING",
+        ".name IS NOT MISSING && .name.first IS NOT NULL",
+        ".a IN [1, 4, 'x'] || .a NOT IN [.b]",
+        ".b BETWEEN 2 3",
+        ".name.first MATCHES '^[A-Z]'",
+        "COERCE .a _number_ * 2",
+        "COERCE .at _datetime_ > COERCE '2022-01-01' _datetime_",
+        "len(.name.first) + size(.tags)",
+        "coalesce(.b, .a, 0)",
+        ".friends.#(age>40).age",
+        ".friends.#",
+        "!(.ok) || -.a < 0",
+        "0 && .a",
+        "[.a, .b, .name.first]",
+        "is_even(.a) || $limit > .a",
+        "ANY(.friends, .age > 40) || ALL(.tags, . != 'z')",
+        ".friends FILTER (.age < 20) MAP (.age * 2)",
+        "ALL(., . > 0)",
+        "CASE WHEN .a > 2 THEN 'many' WHEN .ok THEN .name.first ELSE coalesce(.b, 0) + 1 END",
+        "{'a': .a * 2, 'first': .name.first, 'none': .none, 'ages': .friends MAP ({'age': .age})}",
+        "[.a + 1, {'tags': .tags}, {}] CONTAINS {}",
+        ".ok ? .a > 0 ? 'positive' : 'other' : CASE WHEN .tags CONTAINS 'y' THEN .tags END",
+    ];
+
+    #[test]
+    fn same_as_tree() {
+        let mut functions = FunctionRegistry::new();
+        functions.register("is_even", |args| match args {
+            [Value::Int(n)] => Ok(Value::Bool(n % 2 == 0)),
+            _ => Ok(Value::Bool(false)),
+        });
+        let ctx = EvalContext::new()
+            .with_functions(&functions)
+            .with_variable("limit", Value::Int(3));
+
+        for missing_as_null in [false, true] {
+            let options = ParseOptions { missing_as_null };
+            for expression in EXPRESSIONS {
+                let expr = Parser::parse_expr(expression).unwrap();
+                let tree = Parser::compile_with_options(expr.clone(), options).unwrap();
+                let program = Program::compile(expr, options).unwrap();
+                for doc in DOCS {
+                    let expected = tree.calculate_with(doc, &ctx);
+                    let context = format!("{expression} on {}", String::from_utf8_lossy(doc));
+                    assert_eq!(program.calculate_with(doc, &ctx), expected, "{context}");
+
+                    // serde_json keeps the last of duplicate keys where gjson finds the first
+                    if doc == DOCS[2] {
+                        continue;
+                    }
+                    let Ok(json) = serde_json::from_slice::<serde_json::Value>(doc) else {
+                        continue;
+                    };
+                    let value = Value::from(&json);
+                    assert_eq!(program.calculate_document(&json, &ctx), expected, "{context}");
+                    assert_eq!(tree.calculate_document(&json, &ctx), expected, "{context}");
+                    assert_eq!(program.calculate_document(&value, &ctx), expected, "{context}");
+                    assert_eq!(tree.calculate_document(&value, &ctx), expected, "{context}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn short_circuits() {
+        let eval = |expression: &str| {
+            let program = Program::compile(
+                Parser::parse_expr(expression).unwrap(),
+                ParseOptions::default(),
+            )
+            .unwrap();
+            program.calculate(br#"{"a":1,"s":"x"}"#)
+        };
+
+        assert_eq!(eval(".a > 1 && .s > 1"), Ok(Value::Bool(false)));
+        assert_eq!(eval(".missing && .s > 1"), Ok(Value::Bool(false)));
+        assert_eq!(eval(".a == 1 || unknown(.a)"), Ok(Value::Bool(true)));
+        assert!(eval(".a == 1 && .s > 1").is_err());
+        assert!(eval(".a > 1 || .s > 1").is_err());
+    }
+
+    #[test]
+    fn reads_paths_once() {
+        let program = Program::compile(
+            Parser::parse_expr(".a.b > 1 && .a.b < 5 && .a.c == 'x' && .d IS MISSING").unwrap(),
+            ParseOptions::default(),
+        )
+        .unwrap();
+
+        assert_eq!(program.paths.queries, [false, false, false]);
+        assert_eq!(program.paths.keys.children.len(), 2);
+        assert_eq!(program.stack_size, 2);
+        assert_eq!(
+            program.calculate(br#"{"a":{"c":"x","b":2}}"#),
+            Ok(Value::Bool(true))
+        );
+    }
+
+    #[test]
+    fn runs_chains_without_a_stack() {
+        let compile = |expression: &str| {
+            Program::compile(Parser::parse_expr(expression).unwrap(), ParseOptions::default())
+                .unwrap()
+        };
+
+        for expression in [".a", ".a > 1", "!(.a IN [1, 2])", ".s MATCHES '^x' == true"] {
+            assert!(compile(expression).chain, "{expression}");
+        }
+        for expression in [".a > .b", ".a > 1 && .a < 5", "1 > .a", "len(.s) > 0"] {
+            assert!(!compile(expression).chain, "{expression}");
+        }
+        assert_eq!(
+            compile(".s MATCHES '^x' == true").calculate(br#"{"a":1,"s":"x"}"#),
+            Ok(Value::Bool(true))
+        );
+        assert_eq!(compile(".b > 1").calculate(br#"{"a":1}"#), Ok(Value::Bool(false)));
+    }
+}